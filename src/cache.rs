@@ -0,0 +1,187 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+use crate::utils::copy_dir_all;
+use crate::BuildKey;
+
+/// Subdirectories of a build directory that are not cached, as they are made again for every build from the builds
+/// of the dependencies.
+const UNCACHED_SUBDIRS: &[&str] = &["deps"];
+
+/// The entry of a build in the artifact cache, which is keyed by build hash alone, as it already covers the key.
+fn entry_dir(cache_dir: &Path, build_key: &BuildKey) -> PathBuf {
+    cache_dir.join(build_key.build_hash.as_ref())
+}
+
+/// The entry of a build in the artifact cache, if it was published there.
+pub(crate) fn lookup(cache_dir: &Path, build_key: &BuildKey) -> Option<PathBuf> {
+    let entry = entry_dir(cache_dir, build_key);
+    entry.join("build").is_dir().then_some(entry)
+}
+
+/// Copy the subdirectories of a build directory, other than the uncached ones, with hardlinks when possible.
+fn copy_build_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst).with_context(|| format!("Failed to create directory: {}", dst.display()))?;
+    for entry in fs::read_dir(src).with_context(|| format!("Failed to read directory: {}", src.display()))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || UNCACHED_SUBDIRS.contains(&entry.file_name().to_string_lossy().as_ref()) {
+            continue;
+        }
+        copy_dir_all(&entry.path(), &dst.join(entry.file_name())).with_context(|| {
+            format!(
+                "Failed to copy {} to {}",
+                entry.path().display(),
+                dst.join(entry.file_name()).display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Put the cached build of a build key in place in the workspace, returning whether it was cached. Like a local
+/// build, it is assembled in a temporary directory and renamed into place.
+pub(crate) fn fetch(cache_dir: &Path, build_key: &BuildKey, workspace: &Path) -> Result<bool> {
+    let Some(entry) = lookup(cache_dir, build_key) else {
+        return Ok(false);
+    };
+
+    let builds_dir = workspace.join("builds");
+    let build_dir = builds_dir.join(format!("{}.tmp", build_key.build_dir_name()));
+    let build_dir_final = builds_dir.join(build_key.build_dir_name());
+    let _ = fs::remove_dir_all(&build_dir);
+    copy_build_dir(&entry, &build_dir)?;
+    fs::rename(&build_dir, &build_dir_final).with_context(|| {
+        format!(
+            "Failed to rename build directory from {} to {}",
+            build_dir.display(),
+            build_dir_final.display()
+        )
+    })?;
+
+    info!("Fetched {} from artifact cache {}", build_key, entry.display());
+    Ok(true)
+}
+
+/// Publish a finished build in the workspace to the artifact cache. The entry is assembled in a temporary directory
+/// and renamed into place, so that other workspaces never see it half-copied. An existing entry is kept, unless
+/// `replace` is set for a forced rebuild.
+pub(crate) fn publish(cache_dir: &Path, build_key: &BuildKey, workspace: &Path, replace: bool) -> Result<()> {
+    let entry = entry_dir(cache_dir, build_key);
+    if entry.exists() && !replace {
+        debug!("{} is already in artifact cache", build_key);
+        return Ok(());
+    }
+
+    fs::create_dir_all(cache_dir)
+        .with_context(|| format!("Failed to create artifact cache directory: {}", cache_dir.display()))?;
+    let staging = tempfile::Builder::new()
+        .prefix(".tmp-")
+        .tempdir_in(cache_dir)
+        .with_context(|| format!("Failed to create temporary directory in {}", cache_dir.display()))?;
+    // Temporary directories are only accessible to their owner, while the cache may be shared
+    fs::set_permissions(staging.path(), fs::Permissions::from_mode(0o755))?;
+    copy_build_dir(
+        &workspace.join("builds").join(build_key.build_dir_name()),
+        staging.path(),
+    )?;
+
+    if replace && entry.exists() {
+        fs::remove_dir_all(&entry)
+            .with_context(|| format!("Failed to remove artifact cache entry: {}", entry.display()))?;
+    }
+    match fs::rename(staging.path(), &entry) {
+        Ok(()) => info!("Published {} to artifact cache {}", build_key, entry.display()),
+        // Another workspace published the same build hash in the meantime
+        Err(_) if entry.exists() => debug!("{} was published to artifact cache concurrently", build_key),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to publish to artifact cache: {}", entry.display()))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch, lookup, publish};
+    use crate::spec_file::load_test_tree;
+    use crate::{calculate_build_hash, BuildEnvironment, BuildHash, BuildKey, SourceHash, SourceKey};
+    use std::collections::HashMap;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_publish_and_fetch() {
+        let cache = TempDir::new().unwrap();
+        let first = TempDir::new().unwrap();
+        let second = TempDir::new().unwrap();
+        let build_key = BuildKey::new(
+            SourceKey::from("hello".to_string()),
+            BuildHash::from("0123456789abcdef".to_string()),
+        );
+
+        let build_dir = first.path().join("builds").join(build_key.build_dir_name());
+        for subdir in ["build", "srpm", "deps"] {
+            fs::create_dir_all(build_dir.join(subdir)).unwrap();
+        }
+        fs::write(build_dir.join("build/hello-1-1.x86_64.rpm"), "rpm").unwrap();
+
+        assert!(!fetch(cache.path(), &build_key, second.path()).unwrap());
+        publish(cache.path(), &build_key, first.path(), false).unwrap();
+        let entry = lookup(cache.path(), &build_key).unwrap();
+        assert_eq!(entry, cache.path().join("0123456789abcdef"));
+        assert!(!entry.join("deps").exists());
+
+        // Kept as is unless replaced
+        fs::remove_file(build_dir.join("build/hello-1-1.x86_64.rpm")).unwrap();
+        publish(cache.path(), &build_key, first.path(), false).unwrap();
+        assert!(entry.join("build/hello-1-1.x86_64.rpm").exists());
+        publish(cache.path(), &build_key, first.path(), true).unwrap();
+        assert!(!entry.join("build/hello-1-1.x86_64.rpm").exists());
+
+        fs::write(build_dir.join("build/hello-1-1.x86_64.rpm"), "rpm").unwrap();
+        publish(cache.path(), &build_key, first.path(), true).unwrap();
+        assert!(fetch(cache.path(), &build_key, second.path()).unwrap());
+        let fetched = second.path().join("builds").join(build_key.build_dir_name());
+        assert_eq!(
+            fs::read_to_string(fetched.join("build/hello-1-1.x86_64.rpm")).unwrap(),
+            "rpm"
+        );
+        assert!(fetched.join("srpm").is_dir());
+        assert!(!second
+            .path()
+            .join("builds")
+            .join(format!("{}.tmp", build_key.build_dir_name()))
+            .exists());
+    }
+
+    #[test]
+    fn test_host_os_entries() {
+        let cache = TempDir::new().unwrap();
+        let spec_tree = load_test_tree("hello: {}\n");
+        let key = SourceKey::from("hello".to_string());
+        let build_key = |host_os: &str| {
+            let environment = BuildEnvironment { host_os: Some(host_os.to_string()), ..Default::default() };
+            let build_hash = calculate_build_hash(
+                &key,
+                &spec_tree.sources[&key],
+                &SourceHash::from("0123456789abcdef".to_string()),
+                &HashMap::new(),
+                None,
+                &environment,
+            )
+            .unwrap();
+            BuildKey::new(key.clone(), build_hash)
+        };
+
+        // Developers on different hosts without a target OS do not get each other's builds
+        let workspace = TempDir::new().unwrap();
+        let fedora41 = build_key("fedora41");
+        fs::create_dir_all(workspace.path().join("builds").join(fedora41.build_dir_name()).join("build")).unwrap();
+        publish(cache.path(), &fedora41, workspace.path(), false).unwrap();
+        assert!(lookup(cache.path(), &fedora41).is_some());
+        assert!(lookup(cache.path(), &build_key("fedora40")).is_none());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::cycles::{find_cycles, suggestion};
+use crate::spec_file::SourceOrigin;
+use crate::{Dependency, SourceKey, SpecTree};
+
+/// A problem found in a spec tree.
+#[derive(Debug)]
+pub struct Problem {
+    pub origin: Option<SourceOrigin>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.origin {
+            Some(origin) => write!(f, "{}: {}", origin, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+fn sorted_keys(spec_tree: &SpecTree) -> Vec<&SourceKey> {
+    let mut keys: Vec<&SourceKey> = spec_tree.sources.keys().collect();
+    keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    keys
+}
+
+/// Run all graph checks on a spec tree.
+///
+/// `roots` are the root sources as given by the user. When empty, every source counts as a root and no
+/// source is reported as unreachable.
+pub fn check_spec_tree(spec_tree: &SpecTree, roots: &[String]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let origin = |key: &SourceKey| spec_tree.origins.get(key).cloned();
+    let keys = sorted_keys(spec_tree);
+
+    for key in &keys {
+        let source = &spec_tree.sources[*key];
+
+        if key.as_ref().starts_with('~') {
+            problems.push(Problem {
+                origin: origin(key),
+                message: format!(
+                    "source key '{}' starts with '~', which is reserved for dependencies",
+                    key
+                ),
+            });
+        }
+
+        // Targets can also come from the command line, so they can only be checked against `targets:`
+        if !spec_tree.targets.is_empty() {
+            for (field, targets) in [
+                ("only_targets", &source.only_targets),
+                ("skip_targets", &source.skip_targets),
+            ] {
+                for target in targets.iter().filter(|target| !spec_tree.targets.contains(target)) {
+                    problems.push(Problem {
+                        origin: origin(key),
+                        message: format!(
+                            "'{}' lists '{}' in {}, which is not one of the targets",
+                            key, target, field
+                        ),
+                    });
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for dep_str in &source.dependencies {
+            let dependency = Dependency::parse(dep_str.as_ref());
+            let dep_key = SourceKey::from(dependency.key().to_string());
+
+            if !seen.insert(dep_key.clone()) {
+                problems.push(Problem {
+                    origin: origin(key),
+                    message: format!("'{}' lists dependency '{}' more than once", key, dep_key),
+                });
+            }
+            if &&dep_key == key {
+                problems.push(Problem { origin: origin(key), message: format!("'{}' depends on itself", key) });
+            } else if !spec_tree.sources.contains_key(&dep_key) {
+                problems.push(Problem {
+                    origin: origin(key),
+                    message: format!("'{}' depends on '{}', which is not defined", key, dep_key),
+                });
+            }
+        }
+    }
+
+    let mut root_keys = Vec::new();
+    for root in roots {
+        if root.starts_with('~') {
+            problems.push(Problem {
+                origin: None,
+                message: format!(
+                    "root source '{}' has a '~' prefix, which only applies to dependencies",
+                    root
+                ),
+            });
+        }
+        let root_key = SourceKey::from(Dependency::parse(root).key().to_string());
+        if !spec_tree.sources.contains_key(&root_key) {
+            problems.push(Problem {
+                origin: None,
+                message: format!("root source '{}' is not defined", root_key),
+            });
+        } else {
+            root_keys.push(root_key);
+        }
+    }
+
+    // Graph traversal assumes that all dependencies exist
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    let all_keys: Vec<SourceKey> = keys.iter().map(|key| (*key).clone()).collect();
+    let cycles = find_cycles(&all_keys, spec_tree);
+    for cycle in &cycles {
+        problems.push(Problem {
+            origin: origin(cycle.start()),
+            message: format!("circular dependency: {} ({})", cycle, suggestion(cycle)),
+        });
+    }
+    if !cycles.is_empty() {
+        return problems;
+    }
+
+    if !roots.is_empty() {
+        let reachable = reachable_from(spec_tree, &root_keys);
+        for key in &keys {
+            if !reachable.contains(*key) {
+                problems.push(Problem {
+                    origin: origin(key),
+                    message: format!("'{}' is not reachable from any root source", key),
+                });
+            }
+        }
+    }
+
+    problems
+}
+
+fn reachable_from(spec_tree: &SpecTree, roots: &[SourceKey]) -> HashSet<SourceKey> {
+    let mut reachable = HashSet::new();
+    let mut queue: VecDeque<SourceKey> = roots.iter().cloned().collect();
+
+    while let Some(key) = queue.pop_front() {
+        if !reachable.insert(key.clone()) {
+            continue;
+        }
+        if let Some(source) = spec_tree.sources.get(&key) {
+            for dep_str in &source.dependencies {
+                queue.push_back(SourceKey::from(Dependency::parse(dep_str.as_ref()).key().to_string()));
+            }
+        }
+    }
+
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_spec_tree;
+    use crate::spec_file::load_spec_tree;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn check(yaml: &str, roots: &[&str]) -> Vec<String> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tree.yaml");
+        fs::write(&path, yaml).unwrap();
+        let tree = load_spec_tree(&path, &[]).unwrap();
+        let roots: Vec<String> = roots.iter().map(|s| s.to_string()).collect();
+        check_spec_tree(&tree, &roots).iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_clean_tree() {
+        let yaml = "defaults: {type: {source: git, path: x}}\na: {}\nb: {dependencies: [\"~a\"]}\n";
+        assert!(check(yaml, &[]).is_empty());
+        assert!(check(yaml, &["b"]).is_empty());
+    }
+
+    #[test]
+    fn test_dependency_problems() {
+        let yaml = "defaults: {type: {source: git, path: x}}\na: {}\nb: {dependencies: [a, c, \"~a\"]}\n";
+        let problems = check(yaml, &[]);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].ends_with("tree.yaml:3: 'b' depends on 'c', which is not defined"));
+        assert!(problems[1].ends_with("tree.yaml:3: 'b' lists dependency 'a' more than once"));
+    }
+
+    #[test]
+    fn test_root_problems() {
+        let yaml = "defaults: {type: {source: git, path: x}}\na: {}\nb: {}\n";
+        let problems = check(yaml, &["~a", "c"]);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+
+        let problems = check(yaml, &["a"]);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].ends_with("tree.yaml:3: 'b' is not reachable from any root source"));
+    }
+
+    #[test]
+    fn test_unknown_target() {
+        let yaml = "targets: [epel9]\ndefaults: {type: {source: git, path: x}}\na: {only_targets: [epel9]}\nb: {skip_targets: [epel8]}\n";
+        let problems = check(yaml, &[]);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(
+            problems[0].ends_with("tree.yaml:4: 'b' lists 'epel8' in skip_targets, which is not one of the targets")
+        );
+    }
+
+    #[test]
+    fn test_cycle() {
+        let yaml = "defaults: {type: {source: git, path: x}}\na: {dependencies: [b]}\nb: {dependencies: [a]}\n";
+        let problems = check(yaml, &[]);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(
+            problems[0].contains("tree.yaml:2: circular dependency: a -> b -> a"),
+            "{}",
+            problems[0]
+        );
+    }
+}
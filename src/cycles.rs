@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{Dependency, Error, SourceKey, SpecTree};
+
+/// An edge of a dependency cycle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleEdge {
+    pub from: SourceKey,
+    pub to: SourceKey,
+    pub direct_only: bool,
+}
+
+/// A dependency cycle, as the list of edges leading from its first source back to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cycle {
+    pub edges: Vec<CycleEdge>,
+}
+
+impl Cycle {
+    pub fn start(&self) -> &SourceKey {
+        &self.edges[0].from
+    }
+
+    /// The edge that closes the cycle, which is the one suggested for breaking it.
+    pub fn closing_edge(&self) -> &CycleEdge {
+        self.edges.last().unwrap()
+    }
+}
+
+impl std::fmt::Display for Cycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.start())?;
+        for edge in &self.edges {
+            write!(f, " -> {}{}", if edge.direct_only { "~" } else { "" }, edge.to)?;
+        }
+        Ok(())
+    }
+}
+
+/// Dependencies of a source that exist in the tree, in declaration order and without duplicates.
+fn dependency_edges(spec_tree: &SpecTree, key: &SourceKey) -> Vec<(SourceKey, bool)> {
+    let mut edges: Vec<(SourceKey, bool)> = Vec::new();
+    let Some(source) = spec_tree.sources.get(key) else {
+        return edges;
+    };
+
+    for dep_str in &source.dependencies {
+        let dependency = Dependency::parse(dep_str.as_ref());
+        let dep_key = SourceKey::from(dependency.key().to_string());
+        if !spec_tree.sources.contains_key(&dep_key) || edges.iter().any(|(existing, _)| *existing == dep_key) {
+            continue;
+        }
+        edges.push((dep_key, dependency.is_direct_only()));
+    }
+
+    edges
+}
+
+/// Tarjan's algorithm for finding the strongly connected components of the dependency graph.
+struct Components<'a> {
+    spec_tree: &'a SpecTree,
+    index: HashMap<SourceKey, usize>,
+    lowlink: HashMap<SourceKey, usize>,
+    stack: Vec<SourceKey>,
+    on_stack: HashSet<SourceKey>,
+    components: Vec<Vec<SourceKey>>,
+}
+
+impl Components<'_> {
+    fn visit(&mut self, key: &SourceKey) {
+        let index = self.index.len();
+        self.index.insert(key.clone(), index);
+        self.lowlink.insert(key.clone(), index);
+        self.stack.push(key.clone());
+        self.on_stack.insert(key.clone());
+
+        for (dep_key, _) in dependency_edges(self.spec_tree, key) {
+            if !self.index.contains_key(&dep_key) {
+                self.visit(&dep_key);
+                let low = self.lowlink[key].min(self.lowlink[&dep_key]);
+                self.lowlink.insert(key.clone(), low);
+            } else if self.on_stack.contains(&dep_key) {
+                let low = self.lowlink[key].min(self.index[&dep_key]);
+                self.lowlink.insert(key.clone(), low);
+            }
+        }
+
+        if self.lowlink[key] == self.index[key] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack.remove(&member);
+                let done = member == *key;
+                component.push(member);
+                if done {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+/// Number of cycles after which the search stops, as a tree where most sources depend on each other has a huge number.
+pub const MAX_CYCLES: usize = 20;
+
+/// Johnson's algorithm for finding the elementary cycles of a strongly connected component.
+struct Circuits<'a> {
+    spec_tree: &'a SpecTree,
+    /// Sources that the cycles of the current start may go through
+    allowed: HashSet<&'a SourceKey>,
+    blocked: HashSet<SourceKey>,
+    blocked_by: HashMap<SourceKey, HashSet<SourceKey>>,
+    path: Vec<CycleEdge>,
+    cycles: Vec<Cycle>,
+}
+
+impl Circuits<'_> {
+    /// Look for the cycles through `start` that continue from `key`, returning whether any was found.
+    fn visit(&mut self, start: &SourceKey, key: &SourceKey) -> bool {
+        let mut found = false;
+        self.blocked.insert(key.clone());
+
+        let edges: Vec<(SourceKey, bool)> = dependency_edges(self.spec_tree, key)
+            .into_iter()
+            .filter(|(dep_key, _)| self.allowed.contains(dep_key))
+            .collect();
+        for (dep_key, direct_only) in &edges {
+            if self.cycles.len() >= MAX_CYCLES {
+                break;
+            }
+            self.path
+                .push(CycleEdge { from: key.clone(), to: dep_key.clone(), direct_only: *direct_only });
+            if dep_key == start {
+                self.cycles.push(Cycle { edges: self.path.clone() });
+                found = true;
+            } else if !self.blocked.contains(dep_key) && self.visit(start, dep_key) {
+                found = true;
+            }
+            self.path.pop();
+        }
+
+        if found {
+            self.unblock(key);
+        } else {
+            for (dep_key, _) in edges {
+                self.blocked_by.entry(dep_key).or_default().insert(key.clone());
+            }
+        }
+        found
+    }
+
+    fn unblock(&mut self, key: &SourceKey) {
+        self.blocked.remove(key);
+        for blocker in self.blocked_by.remove(key).unwrap_or_default() {
+            if self.blocked.contains(&blocker) {
+                self.unblock(&blocker);
+            }
+        }
+    }
+}
+
+/// Find the dependency cycles reachable from the given sources.
+///
+/// Every elementary cycle is returned, up to [`MAX_CYCLES`] of them, starting from the lowest key of the cycle and
+/// with the shortest cycles of each source first. Dependencies that are not defined are ignored.
+pub fn find_cycles(sources: &[SourceKey], spec_tree: &SpecTree) -> Vec<Cycle> {
+    let mut components = Components {
+        spec_tree,
+        index: HashMap::new(),
+        lowlink: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        components: Vec::new(),
+    };
+
+    let mut sorted_sources: Vec<&SourceKey> = sources.iter().collect();
+    sorted_sources.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    for key in sorted_sources {
+        if spec_tree.sources.contains_key(key) && !components.index.contains_key(key) {
+            components.visit(key);
+        }
+    }
+
+    let mut circuits = Circuits {
+        spec_tree,
+        allowed: HashSet::new(),
+        blocked: HashSet::new(),
+        blocked_by: HashMap::new(),
+        path: Vec::new(),
+        cycles: Vec::new(),
+    };
+    for component in &components.components {
+        let mut members: Vec<&SourceKey> = component.iter().collect();
+        members.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        // The cycles through each source only go through the sources after it, as the others were done before
+        for (i, start) in members.iter().enumerate() {
+            circuits.allowed = members[i..].iter().copied().collect();
+            circuits.blocked.clear();
+            circuits.blocked_by.clear();
+            circuits.visit(start, start);
+        }
+    }
+
+    let mut cycles = circuits.cycles;
+    cycles.sort_by(|a, b| {
+        a.start()
+            .as_ref()
+            .cmp(b.start().as_ref())
+            .then(a.edges.len().cmp(&b.edges.len()))
+    });
+    cycles
+}
+
+/// Describe how a cycle can be broken.
+pub fn suggestion(cycle: &Cycle) -> String {
+    let edge = cycle.closing_edge();
+    let mut suggestion = format!(
+        "to break it, add a bootstrap stage to '{}' that does not depend on '{}', and depend on '{}@<stage>' instead",
+        edge.from, edge.to, edge.from
+    );
+    if cycle.edges.iter().any(|edge| edge.direct_only) {
+        suggestion.push_str("; note that '~' dependencies are still built first, so they do not break cycles");
+    }
+    suggestion
+}
+
+/// Fail with a description of every dependency cycle reachable from the given sources.
+pub fn ensure_acyclic(sources: &[SourceKey], spec_tree: &SpecTree) -> Result<(), Error> {
+    let cycles = find_cycles(sources, spec_tree);
+    if cycles.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = if cycles.len() >= MAX_CYCLES {
+        format!(
+            "Circular dependency detected (at least {} cycles, only the first ones are listed):",
+            cycles.len()
+        )
+    } else {
+        format!("Circular dependency detected ({} cycles):", cycles.len())
+    };
+    for cycle in &cycles {
+        message.push_str(&format!("\n  {}\n    {}", cycle, suggestion(cycle)));
+    }
+    Err(Error::Cycle(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ensure_acyclic, find_cycles, MAX_CYCLES};
+    use crate::spec_file::load_test_tree;
+    use crate::{SourceKey, SpecTree};
+
+    fn all_keys(tree: &SpecTree) -> Vec<SourceKey> {
+        tree.sources.keys().cloned().collect()
+    }
+
+    #[test]
+    fn test_no_cycles() {
+        let tree = load_test_tree("a: {dependencies: [b, c]}\nb: {dependencies: [c]}\nc: {}\n");
+        assert!(find_cycles(&all_keys(&tree), &tree).is_empty());
+        assert!(ensure_acyclic(&all_keys(&tree), &tree).is_ok());
+    }
+
+    #[test]
+    fn test_cycle_paths() {
+        let tree = load_test_tree(
+            "a: {dependencies: [b]}\nb: {dependencies: [c]}\nc: {dependencies: [\"~a\"]}\n\
+             d: {dependencies: [e, d]}\ne: {dependencies: [d]}\nf: {dependencies: [f]}\n",
+        );
+        let cycles: Vec<String> = find_cycles(&all_keys(&tree), &tree).iter().map(|c| c.to_string()).collect();
+        assert_eq!(cycles, vec!["a -> b -> c -> ~a", "d -> d", "d -> e -> d", "f -> f"]);
+
+        let message = ensure_acyclic(&all_keys(&tree), &tree).unwrap_err().to_string();
+        assert!(message.contains("(4 cycles)"), "{}", message);
+        assert!(
+            message.contains("bootstrap stage to 'c' that does not depend on 'a', and depend on 'c@<stage>'"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_only_reachable_cycles() {
+        let tree = load_test_tree("a: {dependencies: [b]}\nb: {}\nc: {dependencies: [d]}\nd: {dependencies: [c]}\n");
+        assert!(find_cycles(&[SourceKey::from("a".to_string())], &tree).is_empty());
+        assert_eq!(find_cycles(&all_keys(&tree), &tree).len(), 1);
+    }
+
+    #[test]
+    fn test_cycles_of_one_component() {
+        // One group of sources that depend on each other, with three cycles through it
+        let tree = load_test_tree(
+            "a: {dependencies: [b, c]}\nb: {dependencies: [a, c]}\nc: {dependencies: [a]}\nd: {dependencies: [a]}\n",
+        );
+        let cycles: Vec<String> = find_cycles(&all_keys(&tree), &tree).iter().map(|c| c.to_string()).collect();
+        assert_eq!(cycles, vec!["a -> b -> a", "a -> c -> a", "a -> b -> c -> a"]);
+    }
+
+    #[test]
+    fn test_max_cycles() {
+        // Every pair of sources depends on each other, which makes far more cycles than are looked for
+        let names: Vec<String> = (0..8).map(|i| format!("s{}", i)).collect();
+        let yaml: String = names
+            .iter()
+            .map(|name| format!("{}: {{dependencies: [{}]}}\n", name, names.join(", ")))
+            .collect();
+        let tree = load_test_tree(&yaml);
+        assert_eq!(find_cycles(&all_keys(&tree), &tree).len(), MAX_CYCLES);
+
+        let message = ensure_acyclic(&all_keys(&tree), &tree).unwrap_err().to_string();
+        assert!(
+            message.contains("(at least 20 cycles, only the first ones are listed)"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{find_all_dependency_pairs, SourceKey, SpecTree};
+
+/// The sources to rebuild after a change to some sources.
+#[derive(Debug, PartialEq)]
+pub struct Dependents {
+    /// The changed sources along with every source that transitively depends on them
+    pub selected: HashSet<SourceKey>,
+    /// The selected sources that no other selected source depends on, to build from
+    pub roots: Vec<SourceKey>,
+}
+
+/// Find the sources reachable from `roots` that transitively depend on any of the `changed` sources.
+///
+/// The dependency graph of the roots is inverted, so that the changed sources lead to their dependents. Changed
+/// sources that the roots do not reach are ignored.
+pub fn find_dependents(changed: &[SourceKey], roots: &[SourceKey], spec_tree: &SpecTree) -> Result<Dependents> {
+    let dependency_pairs = find_all_dependency_pairs(roots, spec_tree)?;
+
+    let mut reachable: HashSet<&SourceKey> = roots.iter().collect();
+    let mut dependents_map: HashMap<&SourceKey, Vec<&SourceKey>> = HashMap::new();
+    for (dependent, dependency) in &dependency_pairs {
+        reachable.insert(dependent);
+        reachable.insert(dependency);
+        dependents_map.entry(dependency).or_default().push(dependent);
+    }
+
+    let mut selected = HashSet::new();
+    let mut queue: VecDeque<&SourceKey> = changed.iter().filter(|key| reachable.contains(key)).collect();
+    while let Some(key) = queue.pop_front() {
+        if !selected.insert(key.clone()) {
+            continue;
+        }
+        queue.extend(dependents_map.get(key).into_iter().flatten().copied());
+    }
+
+    let mut roots: Vec<SourceKey> = selected
+        .iter()
+        .filter(|key| {
+            !dependency_pairs
+                .iter()
+                .any(|(dependent, dependency)| dependency == *key && selected.contains(dependent))
+        })
+        .cloned()
+        .collect();
+    roots.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+
+    Ok(Dependents { selected, roots })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_dependents;
+    use crate::plan::{BuildStatus, PlanReport};
+    use crate::spec_file::load_test_tree;
+    use crate::utils::test_commit;
+    use crate::{prepare_build_plans, BuildKey, BuildOptions, BuilderBackend, SourceKey};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn keys(names: &[&str]) -> Vec<SourceKey> {
+        names.iter().map(|name| SourceKey::from(name.to_string())).collect()
+    }
+
+    fn sorted(selected: &std::collections::HashSet<SourceKey>) -> Vec<String> {
+        let mut names: Vec<String> = selected.iter().map(|key| key.to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_find_dependents() {
+        let tree = load_test_tree(
+            "app: {dependencies: [lib, tool]}\nlib: {dependencies: [zlib]}\ntool: {}\n\
+             plugin: {dependencies: [\"~lib\"]}\nother: {dependencies: [zlib]}\nzlib: {}\n",
+        );
+
+        let dependents = find_dependents(&keys(&["zlib"]), &keys(&["app", "plugin"]), &tree).unwrap();
+        assert_eq!(sorted(&dependents.selected), vec!["app", "lib", "plugin", "zlib"]);
+        assert_eq!(dependents.roots, keys(&["app", "plugin"]));
+
+        let dependents = find_dependents(&keys(&["lib"]), &keys(&["app"]), &tree).unwrap();
+        assert_eq!(sorted(&dependents.selected), vec!["app", "lib"]);
+        assert_eq!(dependents.roots, keys(&["app"]));
+
+        // Sources that the roots do not reach are left alone
+        let dependents = find_dependents(&keys(&["zlib"]), &keys(&["other", "tool"]), &tree).unwrap();
+        assert_eq!(sorted(&dependents.selected), vec!["other", "zlib"]);
+        let dependents = find_dependents(&keys(&["zlib"]), &keys(&["tool"]), &tree).unwrap();
+        assert!(dependents.selected.is_empty());
+    }
+
+    #[test]
+    fn test_force_rebuild_dependents() {
+        let dir = TempDir::new().unwrap();
+        for name in ["app", "lib", "tool", "zlib"] {
+            test_commit(&dir.path().join(name), name);
+        }
+        let spec_file = dir.path().join("tree.yaml");
+        fs::write(
+            &spec_file,
+            format!(
+                "defaults: {{type: {{source: git, path: \"{}/${{NAME}}\"}}}}\n\
+                 app: {{dependencies: [lib, tool]}}\nlib: {{dependencies: [zlib]}}\ntool: {{}}\nzlib: {{}}\n",
+                dir.path().display()
+            ),
+        )
+        .unwrap();
+
+        let mut options = BuildOptions::new(&spec_file, dir.path().join("workspace"));
+        options.backend = BuilderBackend::Null;
+        options.root_sources = keys(&["app"]);
+        for plan in prepare_build_plans(&options, false).unwrap() {
+            for (key, hash) in &plan.build_hashes {
+                let build_key = BuildKey::new(key.clone(), hash.clone());
+                fs::create_dir_all(options.workspace.join("builds").join(build_key.build_dir_name()).join("build"))
+                    .unwrap();
+            }
+        }
+
+        let statuses = |options: &BuildOptions| -> Vec<(String, BuildStatus)> {
+            let plans = prepare_build_plans(options, false).unwrap();
+            PlanReport::new(options, &plans)
+                .unwrap()
+                .sources
+                .iter()
+                .map(|entry| (entry.key.to_string(), entry.status))
+                .collect()
+        };
+
+        // Without --force, the dependents that are already built are kept
+        options.rebuild_dependents_of = keys(&["lib"]);
+        assert!(statuses(&options).iter().all(|(_, status)| *status == BuildStatus::Cached));
+
+        // With it, only the selected sources are rebuilt, and not their dependencies or the other sources
+        options.force = true;
+        let plans = prepare_build_plans(&options, false).unwrap();
+        assert_eq!(sorted(&plans[0].forced), vec!["app", "lib"]);
+        assert_eq!(
+            statuses(&options),
+            vec![
+                ("app".to_string(), BuildStatus::Stale),
+                ("lib".to_string(), BuildStatus::Stale),
+                ("tool".to_string(), BuildStatus::Cached),
+                ("zlib".to_string(), BuildStatus::Cached),
+            ]
+        );
+
+        // --force alone does not select anything to rebuild
+        options.rebuild_dependents_of.clear();
+        assert!(prepare_build_plans(&options, false).unwrap()[0].forced.is_empty());
+    }
+}
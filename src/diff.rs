@@ -0,0 +1,475 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+use crate::lock::LockFile;
+use crate::shell::{Shell, ShellEscaped};
+use crate::{
+    compute_all_build_hashes, get_base_os, get_source_hashes, load_spec_tree, resolve_targets, setup_workspace,
+    spec_tree_for_target, BuildEnvironment, BuildHash, Dependency, Error, Source, SourceHashes, SourceKey, SpecTree,
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum DiffFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added => f.pad("added"),
+            Change::Removed => f.pad("removed"),
+            Change::Changed => f.pad("changed"),
+        }
+    }
+}
+
+/// Why the build hash of a source changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Cause {
+    /// The source hash changed, e.g. the source now points to another path or revision
+    Sources,
+    /// `params`, `with`, `without`, `defines`, `repos`, `target_os`, `release_suffix` or `backend` changed
+    Settings,
+    /// The `hash_version` of the spec file changed
+    HashVersion,
+    /// Dependencies were added or removed
+    Dependencies,
+    /// The build hashes of these dependencies changed
+    Upstream { dependencies: Vec<SourceKey> },
+}
+
+impl std::fmt::Display for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cause::Sources => write!(f, "own sources"),
+            Cause::Settings => write!(f, "build settings"),
+            Cause::HashVersion => write!(f, "hash version"),
+            Cause::Dependencies => write!(f, "dependency list"),
+            Cause::Upstream { dependencies } => write!(
+                f,
+                "upstream {}",
+                dependencies.iter().map(|key| key.as_ref()).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffEntry {
+    pub key: SourceKey,
+    /// Target OS of the builds, when comparing for explicit targets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub change: Change,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_build_hash: Option<BuildHash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_build_hash: Option<BuildHash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<Cause>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+    /// Whether any source has a build hash that the old tree did not have
+    pub needs_build: bool,
+    pub sources: Vec<DiffEntry>,
+}
+
+/// Everything that goes into the build hash of a source besides its key, sources and dependencies.
+fn settings_differ(old: &Source, new: &Source) -> bool {
+    old.params != new.params
+        || old.with != new.with
+        || old.without != new.without
+        || old.defines != new.defines
+        || old.repos != new.repos
+        || old.target_os != new.target_os
+        || old.release_suffix != new.release_suffix
+        || old.backend != new.backend
+}
+
+fn dependency_keys(source: &Source) -> HashSet<SourceKey> {
+    source
+        .dependencies
+        .iter()
+        .map(|dep| SourceKey::from(Dependency::parse(dep.as_ref()).key().to_string()))
+        .collect()
+}
+
+fn sorted_keys<'a>(keys: impl Iterator<Item = &'a SourceKey>) -> Vec<SourceKey> {
+    let mut keys: Vec<SourceKey> = keys.cloned().collect();
+    keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    keys
+}
+
+/// The build hashes of every source of a spec tree, as built for a target.
+struct TargetHashes {
+    spec_tree: SpecTree,
+    build_hashes: HashMap<SourceKey, BuildHash>,
+}
+
+impl TargetHashes {
+    fn new(spec_tree: &SpecTree, source_hashes: &SourceHashes, target: Option<&str>) -> Result<Self> {
+        let spec_tree = spec_tree_for_target(spec_tree, target);
+        let keys = sorted_keys(spec_tree.sources.keys());
+        // Both trees are compared as built with the default backend, so only their own settings make a difference
+        let environment = BuildEnvironment {
+            hash_version: spec_tree.hash_version,
+            host_os: get_base_os().ok(),
+            ..Default::default()
+        };
+        let build_hashes = compute_all_build_hashes(&keys, &spec_tree, source_hashes, target, &environment)?;
+        Ok(Self { spec_tree, build_hashes })
+    }
+}
+
+impl DiffReport {
+    /// Compare the build hashes of two spec trees for the same targets, using the source hashes of each.
+    pub(crate) fn new(
+        old_tree: &SpecTree, old_hashes: &SourceHashes, new_tree: &SpecTree, new_hashes: &SourceHashes,
+        targets: &[Option<String>],
+    ) -> Result<Self> {
+        let mut sources = Vec::new();
+        for target in targets {
+            let old = TargetHashes::new(old_tree, old_hashes, target.as_deref())?;
+            let new = TargetHashes::new(new_tree, new_hashes, target.as_deref())?;
+            Self::add_target_entries(target, &old, old_hashes, &new, new_hashes, &mut sources);
+        }
+
+        let needs_build = sources.iter().any(|entry| entry.change != Change::Removed);
+        let targets = targets.iter().flatten().cloned().collect();
+        Ok(Self { targets, needs_build, sources })
+    }
+
+    fn add_target_entries(
+        target: &Option<String>, old: &TargetHashes, old_hashes: &SourceHashes, new: &TargetHashes,
+        new_hashes: &SourceHashes, sources: &mut Vec<DiffEntry>,
+    ) {
+        let keys = sorted_keys(old.spec_tree.sources.keys().chain(new.spec_tree.sources.keys()));
+        let mut keys = keys.into_iter().peekable();
+
+        while let Some(key) = keys.next() {
+            // Keys from both trees were merged, so a key in both is listed twice in a row
+            if keys.peek() == Some(&key) {
+                keys.next();
+            }
+
+            let old_hash = old.build_hashes.get(&key).cloned();
+            let new_hash = new.build_hashes.get(&key).cloned();
+            let (change, causes) = match (&old_hash, &new_hash) {
+                (None, Some(_)) => (Change::Added, Vec::new()),
+                (Some(_), None) => (Change::Removed, Vec::new()),
+                (Some(old_hash), Some(new_hash)) if old_hash != new_hash => {
+                    (Change::Changed, Self::causes(&key, old, old_hashes, new, new_hashes))
+                }
+                _ => continue,
+            };
+
+            sources.push(DiffEntry {
+                key,
+                target: target.clone(),
+                change,
+                old_build_hash: old_hash,
+                new_build_hash: new_hash,
+                causes,
+            });
+        }
+    }
+
+    fn causes(
+        key: &SourceKey, old: &TargetHashes, old_hashes: &SourceHashes, new: &TargetHashes, new_hashes: &SourceHashes,
+    ) -> Vec<Cause> {
+        let old_source = &old.spec_tree.sources[key];
+        let new_source = &new.spec_tree.sources[key];
+        let mut causes = Vec::new();
+
+        if old_hashes.hashes.get(key) != new_hashes.hashes.get(key) {
+            causes.push(Cause::Sources);
+        }
+        if settings_differ(old_source, new_source) {
+            causes.push(Cause::Settings);
+        }
+        if old.spec_tree.hash_version != new.spec_tree.hash_version {
+            causes.push(Cause::HashVersion);
+        }
+
+        let old_dependencies = dependency_keys(old_source);
+        let new_dependencies = dependency_keys(new_source);
+        if old_dependencies != new_dependencies {
+            causes.push(Cause::Dependencies);
+        }
+
+        let upstream: Vec<SourceKey> = sorted_keys(old_dependencies.intersection(&new_dependencies))
+            .into_iter()
+            .filter(|dep| old.build_hashes.get(dep) != new.build_hashes.get(dep))
+            .collect();
+        if !upstream.is_empty() {
+            causes.push(Cause::Upstream { dependencies: upstream });
+        }
+
+        causes
+    }
+
+    pub fn render(&self, format: DiffFormat) -> Result<String, Error> {
+        match format {
+            DiffFormat::Json => Ok(serde_json::to_string_pretty(self)? + "\n"),
+            DiffFormat::Text => Ok(self.render_text()),
+        }
+    }
+
+    fn render_text(&self) -> String {
+        let mut out = String::new();
+        let mut current_target = None;
+
+        for entry in &self.sources {
+            if entry.target.is_some() && entry.target != current_target {
+                let _ = writeln!(
+                    out,
+                    "{}{}:",
+                    if current_target.is_some() { "\n" } else { "" },
+                    entry.target.as_deref().unwrap_or_default()
+                );
+                current_target = entry.target.clone();
+            }
+
+            let _ = write!(out, "{:<8} {}", entry.change, entry.key);
+            if !entry.causes.is_empty() {
+                let causes: Vec<String> = entry.causes.iter().map(|cause| cause.to_string()).collect();
+                let _ = write!(out, ": {}", causes.join("; "));
+            }
+            out.push('\n');
+        }
+
+        if self.sources.is_empty() {
+            out.push_str("No build hashes changed\n");
+            return out;
+        }
+
+        let count = |change: Change| self.sources.iter().filter(|entry| entry.change == change).count();
+        let _ = writeln!(
+            out,
+            "\n{} sources affected: {} added, {} removed, {} changed",
+            self.sources.len(),
+            count(Change::Added),
+            count(Change::Removed),
+            count(Change::Changed)
+        );
+
+        out
+    }
+}
+
+/// Compare two spec files by the sources whose build hashes differ, hashing the sources of both against the same
+/// workspace. Without `target_os`, the trees are compared for the targets of either.
+pub fn diff_spec_files(
+    old_spec_file: &Path, new_spec_file: &Path, workspace: &Path, target_os: &[String], vars: &[(String, String)],
+) -> Result<DiffReport, Error> {
+    diff_spec_files_inner(old_spec_file, new_spec_file, workspace, target_os, vars)
+        .map_err(|err| Error::classify(err, Error::Plan))
+}
+
+fn diff_spec_files_inner(
+    old_spec_file: &Path, new_spec_file: &Path, workspace: &Path, target_os: &[String], vars: &[(String, String)],
+) -> Result<DiffReport> {
+    setup_workspace(workspace)?;
+    let mut old_tree = load_spec_tree(old_spec_file, vars)?;
+    let mut new_tree = load_spec_tree(new_spec_file, vars)?;
+
+    // Each tree is hashed at the commits locked for it, as it would be built
+    LockFile::load(&LockFile::path_for(old_spec_file))?.pin(&mut old_tree);
+    LockFile::load(&LockFile::path_for(new_spec_file))?.pin(&mut new_tree);
+
+    // Both trees are compared for the targets of either, so that a target added or removed shows up as well
+    let mut targets = resolve_targets(target_os, &new_tree);
+    for target in resolve_targets(target_os, &old_tree) {
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+    if targets.len() > 1 {
+        targets.retain(|target| target.is_some());
+    }
+
+    let new_keys = sorted_keys(new_tree.sources.keys());
+    let new_hashes = get_source_hashes(workspace, &new_tree, &new_keys)?;
+
+    // Sources of the same origin in both trees share a source hash, so only the others are fetched again
+    let mut old_hashes = SourceHashes::default();
+    let mut old_keys: Vec<SourceKey> = Vec::new();
+    for (key, source) in &old_tree.sources {
+        match (new_tree.sources.get(key), new_hashes.hashes.get(key)) {
+            (Some(new_source), Some(hash)) if new_source.typ == source.typ => {
+                old_hashes.hashes.insert(key.clone(), hash.clone());
+            }
+            _ => old_keys.push(key.clone()),
+        }
+    }
+    old_keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    old_hashes
+        .hashes
+        .extend(get_source_hashes(workspace, &old_tree, &old_keys)?.hashes);
+
+    DiffReport::new(&old_tree, &old_hashes, &new_tree, &new_hashes, &targets)
+}
+
+/// Extract the repository that holds a spec file as of a git revision, so that the files it includes are there as
+/// well. Returns the temporary directory, which is removed when dropped, and the path of the spec file in it.
+pub fn checkout_spec_file(spec_file: &Path, revision: &str) -> Result<(TempDir, PathBuf), Error> {
+    checkout_spec_file_inner(spec_file, revision).map_err(|err| Error::classify(err, Error::Git))
+}
+
+fn checkout_spec_file_inner(spec_file: &Path, revision: &str) -> Result<(TempDir, PathBuf)> {
+    let spec_file =
+        fs::canonicalize(spec_file).with_context(|| format!("Failed to read spec file: {}", spec_file.display()))?;
+    let spec_dir = spec_file.parent().unwrap_or_else(|| Path::new("/"));
+
+    let shell = Shell::new(spec_dir);
+    let toplevel = shell
+        .run_with_output_sync("git rev-parse --show-toplevel")
+        .with_context(|| format!("{} is not in a git repository", spec_file.display()))?;
+    let relative_path = spec_file
+        .strip_prefix(fs::canonicalize(&toplevel)?)
+        .with_context(|| format!("{} is outside of {}", spec_file.display(), toplevel))?
+        .to_path_buf();
+
+    let checkout = TempDir::new().context("Failed to create a temporary directory")?;
+    Shell::new(Path::new(&toplevel))
+        .run_sync(&format!(
+            "set -o pipefail; git archive --format=tar {} | tar -x -C {}",
+            revision.shell_escaped(),
+            checkout.path().to_string_lossy().shell_escaped()
+        ))
+        .with_context(|| format!("Failed to check out revision '{}'", revision))?;
+
+    let old_spec_file = checkout.path().join(relative_path);
+    if !old_spec_file.is_file() {
+        anyhow::bail!("{} does not exist in revision '{}'", spec_file.display(), revision);
+    }
+    Ok((checkout, old_spec_file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cause, Change, DiffReport};
+    use crate::spec_file::load_test_tree;
+    use crate::{SourceHash, SourceHashes, SourceKey, SpecTree};
+    use std::collections::HashMap;
+
+    fn hashes(tree: &SpecTree, changed: &[&str]) -> SourceHashes {
+        let hashes: HashMap<SourceKey, SourceHash> = tree
+            .sources
+            .keys()
+            .map(|key| {
+                let suffix = if changed.contains(&key.as_ref()) { "-new" } else { "" };
+                (key.clone(), SourceHash::new(format!("{}{}", key, suffix)))
+            })
+            .collect();
+        SourceHashes { hashes }
+    }
+
+    fn key(name: &str) -> SourceKey {
+        SourceKey::from(name.to_string())
+    }
+
+    #[test]
+    fn test_diff_causes() {
+        let old = load_test_tree(
+            "app: {dependencies: [lib, tool]}\nlib: {dependencies: [zlib]}\ntool: {}\nzlib: {}\nold: {}\n",
+        );
+        let new = load_test_tree(
+            "app: {dependencies: [lib, tool]}\nlib: {dependencies: [zlib, extra]}\ntool: {with: [docs]}\n\
+             zlib: {}\nextra: {}\n",
+        );
+        let report = DiffReport::new(&old, &hashes(&old, &[]), &new, &hashes(&new, &["zlib"]), &[None]).unwrap();
+
+        let summary: Vec<(String, Change, Vec<Cause>)> = report
+            .sources
+            .iter()
+            .map(|entry| (entry.key.to_string(), entry.change, entry.causes.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "app".to_string(),
+                    Change::Changed,
+                    vec![Cause::Upstream { dependencies: vec![key("lib"), key("tool")] }]
+                ),
+                ("extra".to_string(), Change::Added, vec![]),
+                (
+                    "lib".to_string(),
+                    Change::Changed,
+                    vec![Cause::Dependencies, Cause::Upstream { dependencies: vec![key("zlib")] }]
+                ),
+                ("old".to_string(), Change::Removed, vec![]),
+                ("tool".to_string(), Change::Changed, vec![Cause::Settings]),
+                ("zlib".to_string(), Change::Changed, vec![Cause::Sources]),
+            ]
+        );
+        assert!(report.needs_build);
+    }
+
+    #[test]
+    fn test_diff_targets() {
+        let old = load_test_tree("app: {}\nlib: {}\n");
+        let new = load_test_tree("app: {}\nlib: {skip_targets: [epel9]}\n");
+        let targets = [Some("epel9".to_string()), Some("epel10".to_string())];
+        let report = DiffReport::new(&old, &hashes(&old, &[]), &new, &hashes(&new, &[]), &targets).unwrap();
+
+        assert_eq!(report.sources.len(), 1);
+        assert_eq!(report.sources[0].key, key("lib"));
+        assert_eq!(report.sources[0].target.as_deref(), Some("epel9"));
+        assert_eq!(report.sources[0].change, Change::Removed);
+        assert!(!report.needs_build);
+        assert_eq!(
+            report.render_text(),
+            "epel9:\nremoved  lib\n\n1 sources affected: 0 added, 1 removed, 0 changed\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_hash_version() {
+        let old = load_test_tree("app: {}\nlib: {}\n");
+        let new = load_test_tree("hash_version: 2\napp: {}\nlib: {backend: copr}\n");
+        let report = DiffReport::new(&old, &hashes(&old, &[]), &new, &hashes(&new, &[]), &[None]).unwrap();
+
+        let causes: Vec<(String, Vec<Cause>)> = report
+            .sources
+            .iter()
+            .map(|entry| (entry.key.to_string(), entry.causes.clone()))
+            .collect();
+        assert_eq!(
+            causes,
+            vec![
+                ("app".to_string(), vec![Cause::HashVersion]),
+                ("lib".to_string(), vec![Cause::Settings, Cause::HashVersion]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let tree = load_test_tree("app: {dependencies: [lib]}\nlib: {}\n");
+        let report = DiffReport::new(&tree, &hashes(&tree, &[]), &tree, &hashes(&tree, &[]), &[None]).unwrap();
+        assert!(report.sources.is_empty());
+        assert_eq!(report.render_text(), "No build hashes changed\n");
+    }
+}
@@ -1,6 +1,7 @@
 use crate::shell::Shell;
 use anyhow::Result;
 use std::{path::Path, process::Output};
+use tracing::{info, warn};
 
 pub fn get_builder_dockerfile_for_os(os: &str) -> Result<String> {
     match os {
@@ -92,11 +93,55 @@ pub async fn ensure_image(
 
     let build_command = format!("docker build {args} --no-cache -t {} -", image_name);
 
-    let output = shell.run_with_stdin_get_output(&build_command, &dockerfile_content).await?;
+    let output = shell.run_with_stdin_get_output(&build_command, dockerfile_content).await?;
 
     if !output.status.success() {
         return Ok(Err(output));
     }
 
-    return Ok(Ok(image_name));
+    Ok(Ok(image_name))
+}
+
+/// Remove the tagged builder images, keeping the `latest` ones.
+pub async fn clean_images() -> Result<()> {
+    info!("Cleaning Docker images (removing non-latest tagged images)...");
+
+    let shell = Shell::new(Path::new("."));
+
+    // Get all spectree.ops images
+    let images_output = shell
+        .run_with_output("docker images spectree.ops/\\* --format '{{.Repository}}:{{.Tag}}'")
+        .await?;
+
+    let mut removed_count = 0;
+
+    for line in images_output.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Skip 'latest' tagged images
+        if line.ends_with(":latest") {
+            info!("Keeping latest image: {}", line);
+            continue;
+        }
+
+        // Remove non-latest images
+        info!("Removing image: {}", line);
+        let remove_result = shell.run_with_output(&format!("docker rmi {}", line)).await;
+
+        match remove_result {
+            Ok(_) => {
+                info!("✅ Removed: {}", line);
+                removed_count += 1;
+            }
+            Err(e) => {
+                warn!("Failed to remove {}: {}", line, e);
+            }
+        }
+    }
+
+    info!("✅ Cleanup complete. Removed {} Docker images", removed_count);
+    Ok(())
 }
@@ -0,0 +1,61 @@
+use crate::SourceKey;
+
+/// An error with its chain of causes, as kept by the variants of [`Error`] that wrap the details of a failure.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Errors returned by the public functions of the library.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A spec file could not be read, or does not describe a valid spec tree
+    #[error(transparent)]
+    SpecTree(BoxError),
+    /// A source is not defined in the spec tree
+    #[error("Source '{0}' not found in spec tree")]
+    SourceNotFound(SourceKey),
+    /// A source was given where two different sources are expected
+    #[error("'{0}' was given as both ends of a dependency path")]
+    SameSource(SourceKey),
+    /// The dependencies of the given sources form cycles, described in the message
+    #[error("{0}")]
+    Cycle(String),
+    /// The sources of a source could not be fetched or hashed
+    #[error("Failed to process sources for source {key}")]
+    Source { key: SourceKey, source: BoxError },
+    /// A source hash was not given for a source whose build hash is computed
+    #[error("Source hash not found for source: {0}")]
+    MissingSourceHash(SourceKey),
+    /// A value given as a string, e.g. on the command line, could not be parsed
+    #[error("{0}")]
+    InvalidValue(String),
+    /// The lock file could not be read, written or updated
+    #[error(transparent)]
+    Lock(BoxError),
+    /// A git command failed
+    #[error(transparent)]
+    Git(BoxError),
+    /// The build could not be planned, e.g. because of invalid options or sources that were not built yet
+    #[error(transparent)]
+    Plan(BoxError),
+    /// Building, or managing the build environment, failed
+    #[error(transparent)]
+    Build(BoxError),
+    /// Builds could not be pushed to or pulled from the remote cache
+    #[error(transparent)]
+    RemoteCache(BoxError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// Recover an [`Error`] raised in one of the internal functions, which report errors with `anyhow`, or else wrap
+    /// the error with `wrap`.
+    pub(crate) fn classify(err: anyhow::Error, wrap: fn(BoxError) -> Error) -> Error {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => wrap(err.into()),
+        }
+    }
+}
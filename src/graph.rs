@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::plan::{BuildStatus, PlanEntry, PlanReport};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    Mermaid,
+}
+
+struct Edge {
+    /// Indices of the entries in the report
+    from: usize,
+    to: usize,
+    direct_only: bool,
+}
+
+/// Collect the dependency edges of the report, dropping duplicate entries in `dependencies:`.
+fn collect_edges(report: &PlanReport) -> Vec<Edge> {
+    let index: HashMap<(Option<&str>, &str), usize> = report
+        .sources
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| ((entry.target.as_deref(), entry.key.as_ref()), i))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut edges = Vec::new();
+
+    for (from, entry) in report.sources.iter().enumerate() {
+        for dep in &entry.dependencies {
+            let Some(&to) = index.get(&(entry.target.as_deref(), dep.key.as_ref())) else {
+                continue;
+            };
+            if seen.insert((from, to)) {
+                edges.push(Edge { from, to, direct_only: dep.direct_only });
+            }
+        }
+    }
+
+    edges
+}
+
+/// Entries of one target, along with their indices in the report.
+type TargetGroup<'a> = (Option<&'a str>, Vec<(usize, &'a PlanEntry)>);
+
+/// Group the entries of the report by target.
+fn entries_by_target(report: &PlanReport) -> Vec<TargetGroup<'_>> {
+    let mut groups: Vec<TargetGroup<'_>> = Vec::new();
+    for (index, entry) in report.sources.iter().enumerate() {
+        let target = entry.target.as_deref();
+        match groups.iter_mut().find(|(group_target, _)| *group_target == target) {
+            Some((_, entries)) => entries.push((index, entry)),
+            None => groups.push((target, vec![(index, entry)])),
+        }
+    }
+    groups
+}
+
+fn status_color(status: BuildStatus) -> &'static str {
+    match status {
+        BuildStatus::Cached => "#a6e3a1",
+        BuildStatus::Stale => "#f9e2af",
+        BuildStatus::Failed => "#f38ba8",
+        BuildStatus::Excluded => "#bac2de",
+    }
+}
+
+fn status_class(status: BuildStatus) -> &'static str {
+    match status {
+        BuildStatus::Cached => "cached",
+        BuildStatus::Stale => "stale",
+        BuildStatus::Failed => "failed",
+        BuildStatus::Excluded => "excluded",
+    }
+}
+
+fn short_hash(hash: &str) -> &str {
+    &hash[..hash.len().min(12)]
+}
+
+pub fn render(report: &PlanReport, format: GraphFormat) -> String {
+    match format {
+        GraphFormat::Dot => render_dot(report),
+        GraphFormat::Mermaid => render_mermaid(report),
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", dot_escape(s))
+}
+
+/// DOT node ID of an entry; the same source appears once per target.
+fn dot_id(entry: &PlanEntry) -> String {
+    match &entry.target {
+        Some(target) => dot_quote(&format!("{}/{}", target, entry.key)),
+        None => dot_quote(entry.key.as_ref()),
+    }
+}
+
+fn render_dot(report: &PlanReport) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "digraph spectree {{");
+    let _ = writeln!(out, "    rankdir=LR;");
+    let _ = writeln!(out, "    node [shape=box, style=\"rounded,filled\"];");
+    let _ = writeln!(out);
+
+    for (target, entries) in entries_by_target(report) {
+        let indent = if target.is_some() { "        " } else { "    " };
+        if let Some(target) = target {
+            let _ = writeln!(out, "    subgraph {} {{", dot_quote(&format!("cluster_{}", target)));
+            let _ = writeln!(out, "        label={};", dot_quote(target));
+        }
+
+        for (_, entry) in entries {
+            let root = report.roots.contains(&entry.key);
+            let _ = writeln!(
+                out,
+                "{}{} [label=\"{}\\n{}\", fillcolor={}, tooltip={}{}];",
+                indent,
+                dot_id(entry),
+                dot_escape(entry.key.as_ref()),
+                short_hash(&entry.build_hash),
+                dot_quote(status_color(entry.status)),
+                dot_quote(&format!("{} ({})", entry.build_key, status_class(entry.status))),
+                if root { ", penwidth=2" } else { "" }
+            );
+        }
+
+        if target.is_some() {
+            let _ = writeln!(out, "    }}");
+        }
+    }
+
+    let _ = writeln!(out);
+
+    for edge in collect_edges(report) {
+        let _ = writeln!(
+            out,
+            "    {} -> {}{};",
+            dot_id(&report.sources[edge.from]),
+            dot_id(&report.sources[edge.to]),
+            if edge.direct_only { " [style=dashed, label=\"~\"]" } else { "" }
+        );
+    }
+
+    let _ = writeln!(out, "}}");
+    out
+}
+
+fn mermaid_label(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+fn render_mermaid(report: &PlanReport) -> String {
+    let mut out = String::new();
+
+    // Source keys may contain characters that Mermaid does not accept in node IDs
+    let id = |index: usize| format!("n{}", index);
+
+    let _ = writeln!(out, "graph LR");
+
+    for (group, (target, entries)) in entries_by_target(report).into_iter().enumerate() {
+        let indent = if target.is_some() { "        " } else { "    " };
+        if let Some(target) = target {
+            let _ = writeln!(out, "    subgraph t{}[\"{}\"]", group, mermaid_label(target));
+        }
+
+        for (index, entry) in entries {
+            let label = format!("{}<br/>{}", entry.key, short_hash(&entry.build_hash));
+            let _ = if report.roots.contains(&entry.key) {
+                writeln!(out, "{}{}[[\"{}\"]]", indent, id(index), mermaid_label(&label))
+            } else {
+                writeln!(out, "{}{}[\"{}\"]", indent, id(index), mermaid_label(&label))
+            };
+        }
+
+        if target.is_some() {
+            let _ = writeln!(out, "    end");
+        }
+    }
+
+    for edge in collect_edges(report) {
+        if edge.direct_only {
+            let _ = writeln!(out, "    {} -.->|~| {}", id(edge.from), id(edge.to));
+        } else {
+            let _ = writeln!(out, "    {} --> {}", id(edge.from), id(edge.to));
+        }
+    }
+
+    for status in [
+        BuildStatus::Cached,
+        BuildStatus::Stale,
+        BuildStatus::Failed,
+        BuildStatus::Excluded,
+    ] {
+        let members: Vec<String> = report
+            .sources
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.status == status)
+            .map(|(index, _)| id(index))
+            .collect();
+
+        let _ = writeln!(
+            out,
+            "    classDef {} fill:{}",
+            status_class(status),
+            status_color(status)
+        );
+        if !members.is_empty() {
+            let _ = writeln!(out, "    class {} {}", members.join(","), status_class(status));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, GraphFormat};
+    use crate::plan::{test_entry as entry, BuildStatus, PlanReport};
+    use crate::SourceKey;
+
+    /// `app` depends on `lib`, whose last build failed, and on `tool`, which depends on `hello` directly only.
+    fn report() -> PlanReport {
+        PlanReport {
+            roots: vec![SourceKey::from("app".to_string())],
+            targets: Vec::new(),
+            needs_build: true,
+            sources: vec![
+                entry("hello", BuildStatus::Cached, &[]),
+                entry("lib", BuildStatus::Failed, &["hello", "hello"]),
+                entry("tool", BuildStatus::Stale, &["~hello"]),
+                entry("app", BuildStatus::Stale, &["lib", "tool"]),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_render_dot() {
+        assert_eq!(
+            render(&report(), GraphFormat::Dot),
+            r##"digraph spectree {
+    rankdir=LR;
+    node [shape=box, style="rounded,filled"];
+
+    "hello" [label="hello\n0123456789ab", fillcolor="#a6e3a1", tooltip="hello-0123456789abcdef (cached)"];
+    "lib" [label="lib\n0123456789ab", fillcolor="#f38ba8", tooltip="lib-0123456789abcdef (failed)"];
+    "tool" [label="tool\n0123456789ab", fillcolor="#f9e2af", tooltip="tool-0123456789abcdef (stale)"];
+    "app" [label="app\n0123456789ab", fillcolor="#f9e2af", tooltip="app-0123456789abcdef (stale)", penwidth=2];
+
+    "lib" -> "hello";
+    "tool" -> "hello" [style=dashed, label="~"];
+    "app" -> "lib";
+    "app" -> "tool";
+}
+"##
+        );
+    }
+
+    #[test]
+    fn test_render_mermaid() {
+        assert_eq!(
+            render(&report(), GraphFormat::Mermaid),
+            r##"graph LR
+    n0["hello<br/>0123456789ab"]
+    n1["lib<br/>0123456789ab"]
+    n2["tool<br/>0123456789ab"]
+    n3[["app<br/>0123456789ab"]]
+    n1 --> n0
+    n2 -.->|~| n0
+    n3 --> n1
+    n3 --> n2
+    classDef cached fill:#a6e3a1
+    class n0 cached
+    classDef stale fill:#f9e2af
+    class n2,n3 stale
+    classDef failed fill:#f38ba8
+    class n1 failed
+    classDef excluded fill:#bac2de
+"##
+        );
+    }
+}
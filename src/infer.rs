@@ -0,0 +1,279 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tracing::{debug, info, warn};
+
+use crate::shell::{Shell, ShellEscaped};
+use crate::{resolve_dependencies, Dependency, Source, SourceKey, SourceType, SpecTree};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum InferDeps {
+    /// Only report dependencies that are missing from or extra in `dependencies:`
+    Report,
+    /// Add the missing dependencies to the tree and report the extra ones
+    Add,
+}
+
+/// Capabilities of a source, as declared in its spec file.
+#[derive(Debug, Default)]
+pub struct SpecCapabilities {
+    pub build_requires: Vec<String>,
+    pub provides: HashSet<String>,
+}
+
+/// Extract the capability name from an `rpmspec -q` output line such as `pkgconfig(foo) >= 1.0`.
+///
+/// Rich dependencies such as `(foo or bar)` cannot be matched against a single source and are skipped.
+pub fn parse_capability(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('(') {
+        return None;
+    }
+    line.split_whitespace().next()
+}
+
+fn find_spec_file(dir: &Path) -> Result<PathBuf> {
+    for candidate in [dir.to_path_buf(), dir.join("SPECS")] {
+        let Ok(entries) = std::fs::read_dir(&candidate) else {
+            continue;
+        };
+        let spec_files: Vec<_> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.is_file() && path.extension()? == "spec" {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        match spec_files.len() {
+            0 => continue,
+            1 => return Ok(spec_files[0].clone()),
+            _ => anyhow::bail!("Multiple spec files found in {}: {:?}", candidate.display(), spec_files),
+        }
+    }
+
+    anyhow::bail!("No spec file found in {}", dir.display())
+}
+
+/// The spec file of a git source, in its working tree, which is fetched if it is not there yet.
+pub(crate) fn git_spec_file(
+    key: &SourceKey, source: &Source, workspace: &Path, subpath: Option<&str>,
+) -> Result<PathBuf> {
+    let mut working_path = source.get_working_path(key, workspace, false)?;
+    if !working_path.exists() {
+        working_path = source.get_working_path(key, workspace, true)?;
+    }
+    if let Some(subpath) = subpath {
+        working_path = working_path.join(subpath);
+    }
+    find_spec_file(&working_path)
+}
+
+/// Query a spec file with `rpmspec -q`, evaluated with the build parameters of the source.
+pub(crate) fn rpmspec_query(spec_file: &Path, source: &Source, what: &str) -> Result<String> {
+    let spec_dir = spec_file.parent().unwrap_or_else(|| Path::new("."));
+    let shell = Shell::new(spec_dir);
+
+    // RHEL Git packaging keeps the sources next to the SPECS directory
+    let sources_dir = match spec_dir.parent() {
+        Some(parent) if spec_dir.ends_with("SPECS") && parent.join("SOURCES").exists() => parent.join("SOURCES"),
+        _ => spec_dir.to_path_buf(),
+    };
+    let params = source.build_params()?.to_command_args(" ");
+
+    shell
+        .run_with_output_sync(&format!(
+            "rpmspec -q {} --define \"_sourcedir {}\"{} {}",
+            what,
+            sources_dir.shell_escaped(),
+            params,
+            spec_file.shell_escaped()
+        ))
+        .with_context(|| format!("Failed to query {} of {}", what, spec_file.display()))
+}
+
+fn query_spec(spec_file: &Path, source: &Source) -> Result<SpecCapabilities> {
+    let query = |what: &str| rpmspec_query(spec_file, source, what);
+
+    let build_requires = query("--buildrequires")?
+        .lines()
+        .filter_map(parse_capability)
+        .map(String::from)
+        .collect();
+    let provides = query("--provides")?
+        .lines()
+        .filter_map(parse_capability)
+        .map(String::from)
+        .collect();
+
+    Ok(SpecCapabilities { build_requires, provides })
+}
+
+fn get_capabilities(key: &SourceKey, source: &Source, workspace: &Path) -> Result<SpecCapabilities> {
+    match &source.typ {
+        SourceType::Git { subpath, .. } => {
+            query_spec(&git_spec_file(key, source, workspace, subpath.as_deref())?, source)
+        }
+        SourceType::Srpm { .. } => {
+            let srpm_path = source.get_repo_path(key, workspace, false)?;
+            let temp_dir = TempDir::new().context("Failed to create temporary directory")?;
+            let shell = Shell::new(temp_dir.path());
+            shell
+                .run_sync(&format!(
+                    "rpm2cpio {} | cpio -i --quiet '*.spec'",
+                    srpm_path.shell_escaped()
+                ))
+                .with_context(|| format!("Failed to extract spec file from {}", srpm_path.display()))?;
+            query_spec(&find_spec_file(temp_dir.path())?, source)
+        }
+    }
+}
+
+/// Match the BuildRequires of every source against the Provides of the other sources.
+///
+/// Returns the inferred dependencies of each source, in sorted order.
+pub fn infer_edges(capabilities: &HashMap<SourceKey, SpecCapabilities>) -> HashMap<SourceKey, Vec<SourceKey>> {
+    let mut providers: HashMap<&str, Vec<&SourceKey>> = HashMap::new();
+    for (key, caps) in capabilities {
+        for provide in &caps.provides {
+            providers.entry(provide.as_str()).or_default().push(key);
+        }
+    }
+
+    let mut edges = HashMap::new();
+    for (key, caps) in capabilities {
+        let mut deps: Vec<SourceKey> = Vec::new();
+        for build_require in &caps.build_requires {
+            let Some(candidates) = providers.get(build_require.as_str()) else {
+                continue;
+            };
+            let candidates: Vec<_> = candidates.iter().filter(|candidate| **candidate != key).collect();
+            if candidates.len() > 1 {
+                warn!(
+                    "BuildRequires '{}' of {} is provided by several sources: {:?}",
+                    build_require, key, candidates
+                );
+            }
+            for candidate in candidates {
+                if !deps.contains(candidate) {
+                    deps.push((*candidate).clone());
+                }
+            }
+        }
+        deps.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        edges.insert(key.clone(), deps);
+    }
+
+    edges
+}
+
+/// Infer the dependencies of all sources of the tree from their spec files, then either report or add the
+/// edges that differ from the `dependencies:` lists.
+pub fn infer_dependencies(spec_tree: &mut SpecTree, workspace: &Path, mode: InferDeps) -> Result<()> {
+    let mut keys: Vec<SourceKey> = spec_tree.sources.keys().cloned().collect();
+    keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+
+    let mut capabilities = HashMap::new();
+    for key in &keys {
+        let source = &spec_tree.sources[key];
+        let caps = get_capabilities(key, source, workspace)
+            .with_context(|| format!("Failed to infer dependencies of {}", key))?;
+        debug!(
+            "Source {} has {} BuildRequires and {} Provides",
+            key,
+            caps.build_requires.len(),
+            caps.provides.len()
+        );
+        capabilities.insert(key.clone(), caps);
+    }
+
+    let inferred = infer_edges(&capabilities);
+
+    let mut missing_count = 0;
+    let mut added = Vec::new();
+    for key in &keys {
+        let inferred_deps = &inferred[key];
+
+        // A dependency is already satisfied if it is anywhere in the build repo of the source
+        let build_repo = resolve_dependencies(key, spec_tree)?;
+        for dep in inferred_deps {
+            if !build_repo.contains(dep) {
+                missing_count += 1;
+                match mode {
+                    InferDeps::Report => warn!("Missing dependency: {} build-requires {}", key, dep),
+                    InferDeps::Add => added.push((key.clone(), dep.clone())),
+                }
+            }
+        }
+
+        let source = &spec_tree.sources[key];
+        for dep_key in &source.dependencies {
+            let dep = Dependency::parse(dep_key.as_ref());
+            if !inferred_deps.iter().any(|inferred_dep| inferred_dep.as_ref() == dep.key()) {
+                warn!(
+                    "Extra dependency: {} depends on {} but no BuildRequires matches it",
+                    key,
+                    dep.key()
+                );
+            }
+        }
+    }
+
+    for (key, dep) in added {
+        info!("Adding inferred dependency: {} -> {}", key, dep);
+        spec_tree.sources.get_mut(&key).unwrap().dependencies.push(dep);
+    }
+
+    info!(
+        "Inferred dependencies of {} sources, {} missing",
+        keys.len(),
+        missing_count
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{infer_edges, parse_capability, SpecCapabilities};
+    use crate::SourceKey;
+    use std::collections::HashMap;
+
+    fn key(s: &str) -> SourceKey {
+        SourceKey::from(s.to_string())
+    }
+
+    fn caps(build_requires: &[&str], provides: &[&str]) -> SpecCapabilities {
+        SpecCapabilities {
+            build_requires: build_requires.iter().map(|s| s.to_string()).collect(),
+            provides: provides.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_capability() {
+        assert_eq!(parse_capability("gcc"), Some("gcc"));
+        assert_eq!(parse_capability("pkgconfig(alsa) >= 1.0"), Some("pkgconfig(alsa)"));
+        assert_eq!(parse_capability("(foo or bar)"), None);
+        assert_eq!(parse_capability("  "), None);
+    }
+
+    #[test]
+    fn test_infer_edges() {
+        let capabilities = HashMap::from([
+            (key("lib"), caps(&["gcc"], &["lib", "lib-devel", "pkgconfig(lib)"])),
+            (
+                key("app"),
+                caps(&["pkgconfig(lib)", "make", "app-devel"], &["app", "app-devel"]),
+            ),
+        ]);
+
+        let edges = infer_edges(&capabilities);
+        assert_eq!(edges[&key("app")], vec![key("lib")]);
+        assert!(edges[&key("lib")].is_empty());
+    }
+}
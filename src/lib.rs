@@ -0,0 +1,3405 @@
+//! Build trees of dependent RPM packages from a YAML specification.
+//!
+//! This is the library behind the `spectree` command. It loads spec trees, resolves their dependency graphs,
+//! computes the source and build hashes that build keys are made of, and plans and runs builds with [`Builder`].
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), spectree::Error> {
+//! let spec_tree = spectree::load_spec_tree("packages.yaml".as_ref(), &[])?;
+//! let app = spectree::SourceKey::from("app".to_string());
+//! println!("app is built with {:?}", spectree::resolve_dependencies(&app, &spec_tree)?);
+//!
+//! let mut options = spectree::BuildOptions::new("packages.yaml", "/workspace");
+//! options.root_sources.push(app);
+//! let builder = spectree::Builder::new(options);
+//! if builder.plan()?.needs_build {
+//!     builder.build().await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use nutype::nutype;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fs, path};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, error, info, span, warn, Instrument, Level};
+
+mod cache;
+mod check;
+mod cycles;
+mod dependents;
+mod diff;
+mod docker;
+mod error;
+mod graph;
+mod infer;
+mod lock;
+mod nvr;
+mod params;
+mod plan;
+mod release;
+mod remote_cache;
+mod repos;
+mod schema;
+mod shell;
+mod spec_file;
+mod stages;
+mod template;
+mod utils;
+mod why;
+
+pub use check::{check_spec_tree, Problem};
+pub use diff::{checkout_spec_file, diff_spec_files, Cause, Change, DiffEntry, DiffFormat, DiffReport};
+pub use error::{BoxError, Error};
+pub use graph::{render as render_graph, GraphFormat};
+pub use infer::InferDeps;
+pub use lock::{update_lock_file, LockFile, LockedSource, LOCK_FILE_NAME};
+pub use nvr::Nvr;
+pub use plan::{BuildStatus, PlanDependency, PlanEntry, PlanFormat, PlanReport};
+pub use release::ReleaseSuffix;
+pub use remote_cache::{CacheSyncReport, RemoteCache};
+pub use repos::RepoSpec;
+pub use schema::{spec_file_schema, validate_spec_files};
+pub use spec_file::SourceOrigin;
+pub use stages::Stage;
+pub use template::parse_var_assignment;
+pub use why::{find_dependency_paths, render_dependency_paths, DependencyPath};
+
+use shell::{Shell, ShellEscaped};
+
+use crate::utils::{
+    check_git_clean, copy_dir_all, export_git_revision, get_file_sha256, get_git_revision, get_git_tree_hash,
+};
+
+/// Split a target OS such as `epel9` or `fedora42` into its distribution and release.
+fn split_target_os(os: &str) -> Result<(&str, &str)> {
+    let index = os
+        .find(|c: char| c.is_ascii_digit())
+        .filter(|index| *index > 0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid target OS '{}': expected a name and a release, e.g. epel9", os))?;
+    let (distribution, release) = os.split_at(index);
+    if !release.chars().all(|c| c.is_ascii_digit()) {
+        anyhow::bail!("Invalid target OS '{}': expected a name and a release, e.g. epel9", os);
+    }
+    Ok((distribution, release))
+}
+
+/// The mock config that builds for a target OS, as given to `mock -r`. EPEL targets are built on Rocky Linux, like
+/// the Docker builder images.
+fn mock_config_for_os(os: &str) -> Result<String> {
+    let arch = std::env::consts::ARCH;
+    match split_target_os(os)? {
+        ("epel", release) => Ok(format!("rocky+epel-{}-{}", release, arch)),
+        ("fedora", release) => Ok(format!("fedora-{}-{}", release, arch)),
+        _ => anyhow::bail!("No mock config is known for target OS '{}'", os),
+    }
+}
+
+/// The Copr chroot that builds for a target OS, whose RPMs are the ones that local builds for the target depend on.
+fn copr_chroot_for_os(os: &str) -> Result<String> {
+    let arch = std::env::consts::ARCH;
+    match split_target_os(os)? {
+        ("epel", release) => Ok(format!("epel-{}-{}", release, arch)),
+        ("fedora", release) => Ok(format!("fedora-{}-{}", release, arch)),
+        _ => anyhow::bail!("No Copr chroot is known for target OS '{}'", os),
+    }
+}
+
+fn get_base_os() -> Result<String> {
+    let os_release_content = fs::read_to_string("/etc/os-release")?;
+
+    let mut id = None;
+    let mut version_id = None;
+
+    for line in os_release_content.lines() {
+        if let Some(value) = line.strip_prefix("ID=") {
+            id = Some(value.trim_matches('"'));
+        } else if let Some(value) = line.strip_prefix("VERSION_ID=") {
+            version_id = Some(value.trim_matches('"'));
+        }
+    }
+
+    match (id, version_id) {
+        (Some("rocky"), Some(version)) if version.starts_with("10") => Ok("epel10".to_string()),
+        (Some("rocky"), Some(version)) if version.starts_with("9") => Ok("epel9".to_string()),
+        (Some("rocky"), Some(version)) if version.starts_with("8") => Ok("epel8".to_string()),
+        (Some(id), Some(version)) => {
+            anyhow::bail!("Unsupported OS: ID={}, VERSION_ID={}", id, version)
+        }
+        _ => anyhow::bail!("Could not parse /etc/os-release"),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BuilderBackend {
+    #[default]
+    Mock,
+    Docker,
+    Null,
+    Copr,
+}
+
+impl FromStr for BuilderBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_lowercase().as_str() {
+            "mock" => Ok(BuilderBackend::Mock),
+            "null" => Ok(BuilderBackend::Null),
+            "docker" => Ok(BuilderBackend::Docker),
+            "copr" => Ok(BuilderBackend::Copr),
+            _ => Err(Error::InvalidValue(format!(
+                "Invalid builder backend: {}. Valid options: mock, null, docker, copr",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for BuilderBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuilderBackend::Mock => write!(f, "mock"),
+            BuilderBackend::Null => write!(f, "null"),
+            BuilderBackend::Docker => write!(f, "docker"),
+            BuilderBackend::Copr => write!(f, "copr"),
+        }
+    }
+}
+
+impl BuilderBackend {
+    pub fn is_remote(&self) -> bool {
+        matches!(self, BuilderBackend::Copr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dependency {
+    Regular(String),
+    OnlyDirect(String),
+}
+
+impl Dependency {
+    pub fn parse(dep_str: &str) -> Self {
+        if let Some(key) = dep_str.strip_prefix('~') {
+            Self::OnlyDirect(key.to_string())
+        } else {
+            Self::Regular(dep_str.to_string())
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Regular(key) => key,
+            Self::OnlyDirect(key) => key,
+        }
+    }
+
+    pub fn is_direct_only(&self) -> bool {
+        matches!(self, Self::OnlyDirect(_))
+    }
+
+    pub fn parse_list(dep_strings: &[String]) -> Vec<Self> {
+        dep_strings.iter().map(|s| Self::parse(s)).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "source", deny_unknown_fields)]
+pub enum SourceType {
+    /// A Git repository with a spec file, given by URL or by local path
+    #[serde(rename = "git")]
+    Git {
+        url: Option<String>,
+        path: Option<String>,
+        /// Directory of the repository that holds the spec file
+        subpath: Option<String>,
+        /// Revision to build instead of the checked out one
+        revision: Option<String>,
+    },
+
+    /// A source RPM file
+    #[serde(rename = "srpm")]
+    Srpm { path: String },
+}
+
+#[nutype(derive(
+    Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize, Display, From, Into, Borrow, AsRef
+))]
+pub struct SourceKey(String);
+
+#[nutype(derive(
+    Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize, Display, From, Into, Borrow, AsRef
+))]
+pub struct SourceHash(String);
+
+#[nutype(derive(
+    Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize, Display, From, Into, Borrow, AsRef
+))]
+pub struct BuildHash(String);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BuildKey {
+    pub source_key: SourceKey,
+    pub build_hash: BuildHash,
+}
+
+impl BuildKey {
+    pub fn new(source_key: SourceKey, build_hash: BuildHash) -> Self {
+        Self { source_key, build_hash }
+    }
+
+    pub fn build_dir_name(&self) -> String {
+        format!("{}-{}", self.source_key.as_ref(), self.build_hash.as_ref())
+    }
+}
+
+impl std::fmt::Display for BuildKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.build_dir_name())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct CoprBuildState {
+    pub build_key: String, // Using string instead of BuildKey for serialization simplicity
+    pub build_id: u64,
+    pub status: CoprBuildStatus,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub(crate) enum CoprBuildStatus {
+    Submitted,
+    InProgress,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct CoprStateFile {
+    pub builds: BTreeMap<String, CoprBuildState>, // key is build_key.to_string()
+}
+
+impl CoprStateFile {
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read Copr state file: {}", path.display()))?;
+            serde_yaml::from_str(&content)
+                .with_context(|| format!("Failed to parse Copr state file: {}", path.display()))
+        } else {
+            Ok(Self { builds: Default::default() })
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = serde_yaml::to_string(self).context("Failed to serialize Copr state to YAML")?;
+        fs::write(path, content).with_context(|| format!("Failed to write Copr state file: {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn get_build_state(&self, build_key: &BuildKey) -> Option<&CoprBuildState> {
+        self.builds.get(&build_key.to_string())
+    }
+
+    pub fn set_build_state(&mut self, build_key: &BuildKey, build_state: CoprBuildState) {
+        self.builds.insert(build_key.to_string(), build_state);
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    /// Where the source comes from
+    #[serde(rename = "type")]
+    pub typ: SourceType,
+    /// Keys of the sources to build first, with a `~` prefix for direct-only dependencies
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub dependencies: Vec<SourceKey>,
+    /// Raw rpmbuild options, limited to `--with`, `--without` and `--define`
+    #[serde(default)]
+    pub params: Vec<String>,
+    /// Bconds to enable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub with: Vec<String>,
+    /// Bconds to disable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub without: Vec<String>,
+    /// RPM macros to define
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub defines: BTreeMap<String, String>,
+    /// Whether the build has network access
+    #[serde(default)]
+    pub network: bool,
+    /// Targets to build this source for, when building for several
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only_targets: Vec<String>,
+    /// Targets not to build this source for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_targets: Vec<String>,
+    /// Backend to build this source with, instead of the one given on the command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<BuilderBackend>,
+    /// Target OS to build this source for, instead of the one given on the command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_os: Option<String>,
+    /// Extra package repositories for building this source, including those of the whole tree
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repos: Vec<repos::RepoSpec>,
+    /// Suffix to add to the release of the packages, so that rebuilds upgrade the packages of earlier builds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_suffix: Option<release::ReleaseSuffix>,
+    /// Bootstrap stages, each of which is expanded into its own source when the spec tree is loaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<stages::Stage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub source: Source,
+    pub git_revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srpm_nevra: Option<String>,
+    /// Name, epoch, version and release of the package, from the header of the source RPM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nvr: Option<Nvr>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SpecTree {
+    pub sources: HashMap<SourceKey, Source>,
+    pub origins: HashMap<SourceKey, spec_file::SourceOrigin>,
+    /// Target OSes from the `targets:` section, used when none are given on the command line
+    pub targets: Vec<String>,
+    /// Version from the `hash_version:` field, which is part of every build hash
+    pub hash_version: Option<u32>,
+}
+
+/// Load a spec tree from a spec file and the files it includes, with `vars` overriding the variables of their
+/// `vars:` sections.
+pub fn load_spec_tree(path: &Path, vars: &[(String, String)]) -> Result<SpecTree, Error> {
+    spec_file::load_spec_tree(path, vars).map_err(|err| Error::classify(err, Error::SpecTree))
+}
+
+/// How the builds copied to `--output-dir` are named.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputLayout {
+    /// `<key>-<build hash>`, as in the workspace
+    #[default]
+    BuildKey,
+    /// `<name>-<version>-<release>` of the package
+    Nvr,
+}
+
+/// What to build and how, as given to `spectree build`.
+#[derive(clap::Args, Debug, Clone)]
+#[non_exhaustive]
+pub struct BuildOptions {
+    #[arg(help = "Path to the YAML specification file")]
+    pub spec_file: PathBuf,
+
+    #[arg(short, long, help = "Workspace directory for builds and Git clones")]
+    pub workspace: PathBuf,
+
+    #[arg(help = "Root sources to start building from (can specify multiple)")]
+    pub root_sources: Vec<SourceKey>,
+
+    #[arg(
+        short,
+        long,
+        help = "Builder backend to use for RPM generation",
+        default_value = "mock"
+    )]
+    pub backend: BuilderBackend,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        action = clap::ArgAction::Append,
+        help = "Target OSes to build for, comma-separated or repeated (e.g., epel9,epel10); overrides 'targets:' in the spec file"
+    )]
+    pub target_os: Vec<String>,
+
+    #[arg(long, help = "Copr project name (required for Copr backend)")]
+    pub copr_project: Option<String>,
+
+    #[arg(
+        long,
+        help = "YAML file to store Copr build state mappings (required for Copr backend)"
+    )]
+    pub copr_state_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        action = clap::ArgAction::Append,
+        help = "Exclude chroot for Copr builds (can be specified multiple times)"
+    )]
+    pub exclude_chroot: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Regex pattern for source keys to assume are already built in Copr (skip building)"
+    )]
+    pub assume_built: Option<String>,
+
+    #[arg(
+        long,
+        help = "Debug mode: only prepare sources (rpmbuild -bp) and leave them for inspection. Build will fail intentionally."
+    )]
+    pub debug_prepare: bool,
+
+    #[arg(
+        long,
+        help = "Output directory to copy build results (root sources and their dependencies)"
+    )]
+    pub output_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "build-key",
+        requires = "output_dir",
+        help = "Name the build directories in the output directory by build key or by the NVR of their package"
+    )]
+    pub output_layout: OutputLayout,
+
+    #[arg(
+        long,
+        action = clap::ArgAction::Append,
+        help = "Under the docker build, create repo file in /etc/yum.repos.d/<name>.repo with comma-separated fields (format: <name>:<field1>,<field2>,...)"
+    )]
+    pub with_repo: Vec<String>,
+
+    #[arg(
+        long = "set",
+        value_name = "KEY=VALUE",
+        value_parser = template::parse_var_assignment,
+        action = clap::ArgAction::Append,
+        help = "Set a spec file variable, overriding its value from the vars: section (can be specified multiple times)"
+    )]
+    pub set: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Infer dependencies by matching each spec's BuildRequires against the Provides of the other sources"
+    )]
+    pub infer_deps: Option<infer::InferDeps>,
+
+    #[arg(
+        long,
+        value_name = "KEY",
+        value_delimiter = ',',
+        action = clap::ArgAction::Append,
+        help = "Build only the given sources and everything that depends on them; without root sources, the whole spec tree is considered"
+    )]
+    pub rebuild_dependents_of: Vec<SourceKey>,
+
+    #[arg(
+        long,
+        requires = "rebuild_dependents_of",
+        help = "Rebuild the sources selected by --rebuild-dependents-of even if they were already built"
+    )]
+    pub force: bool,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Build only the sources whose keys match this regex pattern"
+    )]
+    pub only: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Do not build the sources whose keys match this regex pattern"
+    )]
+    pub exclude: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "KEY",
+        help = "Build only this source and its dependencies, stopping before the sources that depend on it"
+    )]
+    pub up_to: Option<SourceKey>,
+
+    #[arg(
+        long,
+        env = "SPECTREE_CACHE",
+        help = "Artifact cache shared between workspaces: builds are taken from it instead of building them, and published to it when built"
+    )]
+    pub cache_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "SPECTREE_REMOTE_CACHE",
+        value_name = "URL_OR_DIR",
+        help = "Remote cache, an HTTP server with GET and PUT or a directory, that builds are pulled from instead of building them; 'cache push' uploads to it"
+    )]
+    pub remote_cache: Option<RemoteCache>,
+}
+
+impl BuildOptions {
+    /// Options for building nothing yet from a spec file, with the defaults of the command line. Root sources and
+    /// other options are set on the fields.
+    pub fn new(spec_file: impl Into<PathBuf>, workspace: impl Into<PathBuf>) -> Self {
+        Self {
+            spec_file: spec_file.into(),
+            workspace: workspace.into(),
+            root_sources: Vec::new(),
+            backend: BuilderBackend::default(),
+            target_os: Vec::new(),
+            copr_project: None,
+            copr_state_file: None,
+            exclude_chroot: Vec::new(),
+            assume_built: None,
+            debug_prepare: false,
+            output_dir: None,
+            output_layout: OutputLayout::BuildKey,
+            with_repo: Vec::new(),
+            set: Vec::new(),
+            infer_deps: None,
+            rebuild_dependents_of: Vec::new(),
+            force: false,
+            only: None,
+            exclude: None,
+            up_to: None,
+            cache_dir: None,
+            remote_cache: None,
+        }
+    }
+
+    /// The backend that builds a source: its own `backend:` if set, otherwise the one from the command line.
+    fn backend_for<'a>(&'a self, source: &'a Source) -> &'a BuilderBackend {
+        source.backend.as_ref().unwrap_or(&self.backend)
+    }
+}
+
+/// What a build depends on besides its source, its settings in the spec tree and its target OS, which goes into its
+/// build hash along with them.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct BuildEnvironment {
+    /// Backend of the sources that do not set their own
+    pub backend: BuilderBackend,
+    /// Repo files created in the Docker builder image, as given with `--with-repo`
+    pub with_repo: Vec<String>,
+    /// Version from the `hash_version:` field of the spec file
+    pub hash_version: Option<u32>,
+    /// OS of the build host, whose chroot or builder image the local builds use when there is no target OS
+    pub host_os: Option<String>,
+}
+
+impl BuildEnvironment {
+    /// The environment of the builds of a spec tree with the given options.
+    pub fn new(options: &BuildOptions, spec_tree: &SpecTree) -> Self {
+        Self {
+            backend: options.backend.clone(),
+            with_repo: options.with_repo.clone(),
+            hash_version: spec_tree.hash_version,
+            host_os: get_base_os().ok(),
+        }
+    }
+}
+
+fn setup_workspace(workspace: &Path) -> Result<()> {
+    fs::create_dir_all(workspace)
+        .with_context(|| format!("Failed to create workspace directory: {}", workspace.display()))?;
+    fs::create_dir_all(workspace.join("sources")).with_context(|| {
+        format!(
+            "Failed to create sources directory: {}",
+            workspace.join("sources").display()
+        )
+    })?;
+    fs::create_dir_all(workspace.join("builds")).with_context(|| {
+        format!(
+            "Failed to create builds directory: {}",
+            workspace.join("builds").display()
+        )
+    })?;
+    info!("Workspace setup at: {}", workspace.display());
+    Ok(())
+}
+
+fn clone_or_update_repo(url: &str, workspace: &Path, key: &str) -> Result<PathBuf> {
+    let sources_dir = workspace.join("sources");
+    let repo_path = sources_dir.join(key);
+
+    if repo_path.exists() {
+        info!("Updating existing repo for {}", key);
+        let shell = Shell::new(&repo_path);
+        shell
+            .run_sync("git fetch origin")
+            .with_context(|| format!("Failed to execute git fetch in repo: {}", repo_path.display()))?;
+
+        shell
+            .run_sync("git reset --hard origin/HEAD")
+            .with_context(|| format!("Failed to execute git reset in repo: {}", repo_path.display()))?;
+    } else {
+        info!("Cloning repo for {} from {}", key, url);
+        let parent_dir = repo_path.parent().unwrap_or_else(|| Path::new("."));
+        let shell = Shell::new(parent_dir);
+        shell
+            .run_sync(&format!(
+                "git clone {} {}",
+                url.shell_escaped(),
+                repo_path.shell_escaped()
+            ))
+            .with_context(|| format!("Failed to execute git clone from {} to {}", url, repo_path.display()))?;
+    }
+
+    Ok(repo_path)
+}
+
+/// Compute the build hash of a source from its key, its source hash, the build hashes of its direct dependencies,
+/// its build settings, the target OS it is built for and the environment it is built in.
+pub fn calculate_build_hash(
+    key: &SourceKey, source: &Source, source_content_hash: &SourceHash,
+    dependency_hashes: &HashMap<SourceKey, BuildHash>, target: Option<&str>, environment: &BuildEnvironment,
+) -> Result<BuildHash, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_ref().as_bytes());
+    hasher.update(source_content_hash.as_ref().as_bytes());
+
+    // Builds of the same source for different targets must not share a build directory. Local builds without a
+    // target are built for the host OS, so that builds on different hosts do not share one either.
+    let backend = source.backend.as_ref().unwrap_or(&environment.backend);
+    let os = match target {
+        Some(target) => Some(target),
+        None if !backend.is_remote() => environment.host_os.as_deref(),
+        None => None,
+    };
+    if let Some(os) = os {
+        hasher.update(format!("target:{}", os).as_bytes());
+    }
+
+    // Include dependency hashes in sorted order for consistent hashing
+    let mut dep_hashes = Vec::new();
+    for dep_str in &source.dependencies {
+        let dependency = Dependency::parse(dep_str.as_ref());
+        if let Some(dep_hash) = dependency_hashes.get(dependency.key()) {
+            dep_hashes.push((dependency.key().to_string(), dep_hash.clone(), false));
+        }
+    }
+    dep_hashes.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+    hasher.update(format!("{:?}", dep_hashes).as_bytes());
+
+    hasher.update(format!("{:?}", source.params).as_bytes());
+
+    // The settings below are only hashed when set, and the backend only when it is not mock, so that the build hashes
+    // of sources that do not use them stay the same as before the settings existed
+    if !source.with.is_empty() || !source.without.is_empty() || !source.defines.is_empty() {
+        hasher.update(
+            format!(
+                "with:{:?} without:{:?} defines:{:?}",
+                source.with, source.without, source.defines
+            )
+            .as_bytes(),
+        );
+    }
+
+    if !source.repos.is_empty() {
+        hasher.update(format!("repos:{:?}", source.repos).as_bytes());
+    }
+
+    // The counter itself is not hashed, as it changes with every build
+    if let Some(release_suffix) = &source.release_suffix {
+        hasher.update(format!("release_suffix:{:?}", release_suffix).as_bytes());
+    }
+
+    if *backend != BuilderBackend::Mock {
+        hasher.update(format!("backend:{}", backend).as_bytes());
+    }
+
+    if *backend == BuilderBackend::Docker {
+        if !environment.with_repo.is_empty() {
+            hasher.update(format!("with_repo:{:?}", environment.with_repo).as_bytes());
+        }
+
+        // The builder image is made from a Dockerfile that comes with spectree, and may change along with it
+        let os = os
+            .context("Cannot tell the builder image of the host OS, a target OS is required")
+            .map_err(|err| Error::Plan(err.into()))?;
+        let dockerfile = docker::get_builder_dockerfile_for_os(os).map_err(|err| Error::Plan(err.into()))?;
+        hasher.update(format!("dockerfile:{:x}", Sha256::digest(dockerfile.as_bytes())).as_bytes());
+    }
+
+    if let Some(hash_version) = environment.hash_version {
+        hasher.update(format!("hash_version:{}", hash_version).as_bytes());
+    }
+
+    Ok(BuildHash::new(format!("{:x}", hasher.finalize())))
+}
+
+impl Source {
+    /// Whether the source is built for a target, according to its `only_targets` and `skip_targets`.
+    ///
+    /// Without an explicit target there is nothing to filter on, so every source is built.
+    pub fn is_built_for_target(&self, target: Option<&str>) -> bool {
+        let Some(target) = target else {
+            return true;
+        };
+        (self.only_targets.is_empty() || self.only_targets.iter().any(|t| t == target))
+            && !self.skip_targets.iter().any(|t| t == target)
+    }
+
+    /// Expand the `${...}` references in all templated fields of the source.
+    fn expand_templates(&mut self, key: &SourceKey, vars: &template::TemplateVars) -> Result<()> {
+        let expand = |value: &mut String| -> Result<()> {
+            *value = vars.expand(value, key.as_ref())?;
+            Ok(())
+        };
+
+        match &mut self.typ {
+            SourceType::Git { url, path, subpath, revision } => {
+                for value in [url, path, subpath, revision].into_iter().flatten() {
+                    expand(value)?;
+                }
+            }
+            SourceType::Srpm { path } => expand(path)?,
+        }
+
+        for param in self.params.iter_mut() {
+            expand(param)?;
+        }
+
+        for value in self.defines.values_mut() {
+            expand(value)?;
+        }
+
+        for stage in self.stages.iter_mut() {
+            for value in stage.defines.values_mut() {
+                expand(value)?;
+            }
+        }
+
+        for repo in self.repos.iter_mut() {
+            expand(&mut repo.baseurl)?;
+        }
+
+        Ok(())
+    }
+
+    /// The rpmbuild settings of the source, combining its typed fields with its raw `params`.
+    pub(crate) fn build_params(&self) -> Result<params::BuildParams> {
+        params::BuildParams::new(&self.with, &self.without, &self.defines, &self.params)
+    }
+
+    fn get_repo_path(&self, key: &SourceKey, workspace: &Path, update: bool) -> Result<PathBuf> {
+        let repo_path = match &self.typ {
+            SourceType::Git { url, path, .. } => {
+                if let Some(path) = path {
+                    path::absolute(path).with_context(|| format!("Failed to get absolute path for: {}", path))?
+                } else if let Some(url) = url {
+                    if let Some(path) = url.strip_prefix("file://") {
+                        PathBuf::from(path)
+                    } else if !update {
+                        workspace.join("sources").join(key.as_ref())
+                    } else {
+                        clone_or_update_repo(url, workspace, key.as_ref())?
+                    }
+                } else {
+                    anyhow::bail!("Invalid Git source");
+                }
+            }
+            SourceType::Srpm { path } => {
+                let path =
+                    path::absolute(path).with_context(|| format!("Failed to get absolute path for: {}", path))?;
+                if !path.is_file() {
+                    anyhow::bail!("SRPM file for {} not found: {}", key, path.display());
+                }
+                path
+            }
+        };
+
+        Ok(repo_path)
+    }
+
+    fn get_working_path(&self, key: &SourceKey, workspace: &Path, update: bool) -> Result<PathBuf> {
+        match &self.typ {
+            SourceType::Git { revision, subpath, .. } => {
+                if let Some(revision) = revision {
+                    // For specific revisions, export to a revision-specific directory
+                    let source_repo_path = self.get_repo_path(key, workspace, update)?;
+
+                    // Resolve the revision to its full commit hash
+                    let shell = Shell::new(&source_repo_path);
+                    let full_revision = shell
+                        .run_with_output_sync(&format!("git rev-parse {}", revision.shell_escaped()))
+                        .with_context(|| format!("Failed to resolve git revision '{}' for source {}", revision, key))?;
+                    let export_key = format!("{}-{}", key.as_ref(), full_revision);
+                    let export_path = workspace.join("sources").join(&export_key);
+
+                    // Only export if the directory doesn't already exist
+                    if !export_path.exists() {
+                        info!("Exporting revision {} for source {}", revision, key);
+                        export_git_revision(&source_repo_path, revision, &export_path, subpath.as_deref())?;
+
+                        // Run spectool -g on the exported sources if there's a spec file
+                        self.run_spectool_on_exported_sources(&export_path)?;
+                    }
+
+                    Ok(export_path)
+                } else {
+                    // For HEAD/current revision, use the repo path directly
+                    self.get_repo_path(key, workspace, update)
+                }
+            }
+            _ => self.get_repo_path(key, workspace, update),
+        }
+    }
+
+    fn run_spectool_on_exported_sources(&self, export_path: &Path) -> Result<()> {
+        // Find spec files in the exported directory
+        let spec_files: Vec<_> = std::fs::read_dir(export_path)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let path = entry.path();
+                if path.is_file() && path.extension()? == "spec" {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if spec_files.is_empty() {
+            debug!("No spec files found in exported directory, skipping spectool");
+            return Ok(());
+        }
+
+        for spec_file in spec_files {
+            info!("Running spectool -g on {}", spec_file.display());
+            let shell = Shell::new(export_path);
+            let output = shell.run_with_output_sync(&format!("spectool -g {}", spec_file.shell_escaped()));
+
+            match output {
+                Ok(output) => {
+                    info!("Successfully ran spectool -g on {}", spec_file.display());
+                    debug!("spectool output: {}", output);
+                }
+                Err(e) => {
+                    info!(
+                        "spectool command not available or failed for {}: {}",
+                        spec_file.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Compute the hash of the sources of a source: the git tree of its repository, or the digest of its SRPM. Git
+/// sources that are given by URL are cloned or updated in the workspace first.
+pub fn calc_source_hash(key: &SourceKey, source: &Source, workspace: &Path) -> Result<SourceHash, Error> {
+    calc_source_hash_inner(key, source, workspace).map_err(|err| Error::Source { key: key.clone(), source: err.into() })
+}
+
+fn calc_source_hash_inner(key: &SourceKey, source: &Source, workspace: &Path) -> Result<SourceHash> {
+    if let SourceType::Srpm { .. } = &source.typ {
+        let srpm_path = source.get_repo_path(key, workspace, false)?;
+        let digest = get_file_sha256(&srpm_path)?;
+        debug!("Processed sources for source: {} (srpm: {})", key, digest);
+        return Ok(SourceHash::new(digest));
+    }
+
+    // Check if using a specific revision
+    let using_revision = match &source.typ {
+        SourceType::Git { revision, .. } => revision.is_some(),
+        _ => false,
+    };
+
+    let repo_path = source.get_repo_path(key, workspace, true)?;
+
+    // Skip git clean check when using a specific revision
+    if !using_revision && !check_git_clean(&repo_path)? {
+        anyhow::bail!("Git repository for {} has uncommitted changes", key);
+    }
+
+    // For specific revisions, we need to use the revision instead of the tree hash
+    let git_hash = match &source.typ {
+        SourceType::Git { revision: Some(revision), subpath, .. } => {
+            info!("Using specified revision '{}' for source {}", revision, key);
+            // For specific revisions, we use the revision as part of the hash
+            // But we still need to resolve it to a full commit hash for consistency
+            let shell = Shell::new(&repo_path);
+            let full_revision = shell
+                .run_with_output_sync(&format!("git rev-parse {}", revision.shell_escaped()))
+                .with_context(|| format!("Failed to resolve git revision '{}' for source {}", revision, key))?;
+
+            // If there's a subpath, we need to get the tree hash for that specific path at the revision
+            if let Some(subpath) = subpath {
+                shell
+                    .run_with_output_sync(&format!(
+                        "git rev-parse {}:{}",
+                        full_revision.shell_escaped(),
+                        subpath.shell_escaped()
+                    ))
+                    .with_context(|| {
+                        format!(
+                            "Failed to get tree hash for subpath '{}' at revision '{}' for source {}",
+                            subpath, revision, key
+                        )
+                    })?
+            } else {
+                // Use the tree hash of the full revision
+                shell
+                    .run_with_output_sync(&format!("git rev-parse {}^{{tree}}", full_revision.shell_escaped()))
+                    .with_context(|| {
+                        format!("Failed to get tree hash for revision '{}' for source {}", revision, key)
+                    })?
+            }
+        }
+        SourceType::Git { subpath, .. } => {
+            // Original behavior for HEAD/current revision
+            get_git_tree_hash(&repo_path, subpath.as_deref())?
+        }
+        SourceType::Srpm { .. } => unreachable!(),
+    };
+
+    // Extract subpath for debug message
+    let subpath = match &source.typ {
+        SourceType::Git { subpath, .. } => subpath.as_deref(),
+        _ => None,
+    };
+
+    debug!(
+        "Processed sources for source: {} (git: {}){}",
+        key,
+        git_hash,
+        subpath.map(|s| format!(" subpath: {}", s)).unwrap_or_default()
+    );
+
+    Ok(SourceHash::new(git_hash))
+}
+
+/// The source hashes of the sources of a spec tree, from which their build hashes are computed.
+#[derive(Debug, Clone, Default)]
+pub struct SourceHashes {
+    pub hashes: HashMap<SourceKey, SourceHash>,
+}
+
+fn get_source_hashes(
+    workspace: &Path, spec_tree: &SpecTree, all_sources: &Vec<SourceKey>,
+) -> Result<SourceHashes, Error> {
+    let mut hashes = HashMap::new();
+    for key in all_sources {
+        let source = spec_tree.sources.get(key).unwrap();
+        match calc_source_hash(key, source, workspace) {
+            Ok(hash) => {
+                hashes.insert(key.clone(), hash);
+                info!("✅ Source {} processed successfully", key);
+            }
+            Err(e) => {
+                // The error only names the source, its cause says what went wrong
+                match std::error::Error::source(&e) {
+                    Some(cause) => error!("❌ {}: {:#}", e, cause),
+                    None => error!("❌ {}", e),
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(SourceHashes { hashes })
+}
+
+/// Find every (dependent, dependency) edge reachable from the given sources, failing if they form cycles.
+pub fn find_all_dependency_pairs(
+    sources: &[SourceKey], spec_tree: &SpecTree,
+) -> Result<Vec<(SourceKey, SourceKey)>, Error> {
+    cycles::ensure_acyclic(sources, spec_tree)?;
+
+    let mut pairs = Vec::new();
+    let mut visited = HashSet::new();
+    let mut recursion_stack = Vec::new();
+
+    for source_key in sources {
+        find_dependency_pairs_recursive(source_key, spec_tree, &mut pairs, &mut visited, &mut recursion_stack)?;
+    }
+
+    Ok(pairs)
+}
+
+fn find_dependency_pairs_recursive(
+    source_key: &SourceKey, spec_tree: &SpecTree, pairs: &mut Vec<(SourceKey, SourceKey)>,
+    visited: &mut HashSet<SourceKey>, recursion_stack: &mut Vec<SourceKey>,
+) -> Result<(), Error> {
+    // Check for cycles - if this source is already in the recursion stack
+    if recursion_stack.contains(source_key) {
+        return Err(Error::Cycle(format!(
+            "Circular dependency detected: {}",
+            cycle_path(recursion_stack, source_key)
+        )));
+    }
+
+    // If we've already processed this source completely, skip it
+    if visited.contains(source_key) {
+        return Ok(());
+    }
+
+    // Add to recursion stack to detect cycles
+    recursion_stack.push(source_key.clone());
+
+    // Get the source definition
+    let source = spec_tree
+        .sources
+        .get(source_key)
+        .ok_or_else(|| Error::SourceNotFound(source_key.clone()))?;
+
+    // Process all dependencies of this source
+    for dep_key in &source.dependencies {
+        // Parse the dependency to handle ~ prefix
+        let dependency = Dependency::parse(dep_key.as_ref());
+        let actual_dep_key = SourceKey::from(dependency.key().to_string());
+
+        // Add the dependency pair (source -> dependency)
+        pairs.push((source_key.clone(), actual_dep_key.clone()));
+
+        // Recursively process the dependency
+        find_dependency_pairs_recursive(&actual_dep_key, spec_tree, pairs, visited, recursion_stack)?;
+    }
+
+    // Remove from recursion stack and mark as visited
+    recursion_stack.pop();
+    visited.insert(source_key.clone());
+
+    Ok(())
+}
+
+/// Format the part of the recursion stack that leads from `source_key` back to itself.
+fn cycle_path(recursion_stack: &[SourceKey], source_key: &SourceKey) -> String {
+    let start = recursion_stack.iter().position(|key| key == source_key).unwrap_or(0);
+    let mut path: Vec<&str> = recursion_stack[start..].iter().map(|key| key.as_ref()).collect();
+    path.push(source_key.as_ref());
+    path.join(" -> ")
+}
+
+/// The sources whose RPMs are in the build repo of a source: its direct dependencies, and their dependencies
+/// transitively except through direct-only (`~`) edges.
+pub fn resolve_dependencies(key: &SourceKey, spec_tree: &SpecTree) -> Result<Vec<SourceKey>, Error> {
+    let mut resolved = Vec::new();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+
+    queue.push_back((SourceKey::from(key.to_string()), true));
+
+    while let Some((key, direct)) = queue.pop_front() {
+        if visited.contains(&key) {
+            continue;
+        }
+        visited.insert(key.clone());
+
+        let source = spec_tree.sources.get(&key).ok_or_else(|| Error::SourceNotFound(key.clone()))?;
+
+        // Add current source to resolved list
+        resolved.push(key);
+
+        // Process dependencies
+        for dep_str in &source.dependencies {
+            let dependency = Dependency::parse(dep_str.as_ref());
+
+            // If dependency has ~ prefix, only include if this is a direct dependency
+            if direct || !dependency.is_direct_only() {
+                queue.push_back((SourceKey::from(dependency.key().to_string()), false));
+            }
+        }
+    }
+
+    // Remove the root source from the resolved list (we don't depend on ourselves)
+    resolved.retain(|k| k != key);
+
+    Ok(resolved)
+}
+
+fn copy_srpm_to_build_dir(srpm_file: &Path, build_dir: &Path) -> Result<PathBuf> {
+    let srpm_dir = build_dir.join("srpm");
+    fs::create_dir_all(&srpm_dir)
+        .with_context(|| format!("Failed to create SRPM directory: {}", srpm_dir.display()))?;
+
+    let file_name = srpm_file
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid SRPM path: {}", srpm_file.display()))?;
+    let srpm_path = srpm_dir.join(file_name);
+
+    // Prefer a hardlink, fall back to copying when the SRPM lives on another filesystem
+    if fs::hard_link(srpm_file, &srpm_path).is_err() {
+        fs::copy(srpm_file, &srpm_path).with_context(|| {
+            format!(
+                "Failed to copy SRPM from {} to {}",
+                srpm_file.display(),
+                srpm_path.display()
+            )
+        })?;
+    }
+
+    info!("Using source RPM {}", srpm_file.display());
+    Ok(srpm_path)
+}
+
+fn create_build_info_file(
+    build_key: &BuildKey, source: &Source, workspace: &Path, build_dir: &Path, nvr: Option<Nvr>,
+) -> Result<()> {
+    let git_revision = match &source.typ {
+        SourceType::Git { revision, .. } => {
+            // If a specific revision is provided, use that; otherwise get current revision
+            if let Some(rev) = revision {
+                Some(rev.clone())
+            } else {
+                let repo_path = source.get_repo_path(&build_key.source_key, workspace, false)?;
+                match get_git_revision(&repo_path) {
+                    Ok(revision) => Some(revision),
+                    Err(e) => {
+                        debug!("Failed to get git revision: {}", e);
+                        None
+                    }
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // Already read from the header of the source RPM along with the NVR
+    let srpm_nevra = match &source.typ {
+        SourceType::Srpm { .. } => nvr.as_ref().map(Nvr::srpm_nevra),
+        _ => None,
+    };
+
+    let build_info = BuildInfo { source: source.clone(), git_revision, srpm_nevra, nvr };
+
+    let build_info_path = build_dir.join("build_info.yaml");
+    let build_info_content =
+        serde_yaml::to_string(&build_info).with_context(|| "Failed to serialize build info to YAML")?;
+    fs::write(&build_info_path, build_info_content)
+        .with_context(|| format!("Failed to write build info file: {}", build_info_path.display()))?;
+    debug!("Created build info file: {}", build_info_path.display());
+
+    Ok(())
+}
+
+async fn build_source(
+    build_key: &BuildKey, source: &Source, target_os: Option<&str>, all_dependencies: &HashMap<SourceKey, BuildHash>,
+    args: &BuildOptions, force: bool, copr_state_mutex: &Mutex<()>,
+) -> Result<()> {
+    let backend = args.backend_for(source);
+    let target_os = source.target_os.as_deref().or(target_os);
+
+    // For remote builds, check Copr state instead of local directories
+    if backend.is_remote() {
+        let copr_state_file = args
+            .copr_state_file
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Copr state file is required for remote backend"))?;
+
+        // Atomically check build state
+        let existing_build_info = {
+            let _guard = copr_state_mutex.lock().await;
+            let state = CoprStateFile::load_or_create(copr_state_file)?;
+            state.get_build_state(build_key).cloned()
+        };
+
+        if let Some(existing_build) = existing_build_info {
+            match existing_build.status {
+                CoprBuildStatus::Completed if force => {
+                    info!(
+                        "Remote build {} already completed for {}, rebuilding as forced",
+                        existing_build.build_id, build_key
+                    );
+                }
+                CoprBuildStatus::Completed => {
+                    info!(
+                        "Remote build {} already completed for {}",
+                        existing_build.build_id, build_key
+                    );
+                    return Ok(());
+                }
+                CoprBuildStatus::Failed => {
+                    info!(
+                        "Previous Copr build {} failed for {}, will retry",
+                        existing_build.build_id, build_key
+                    );
+                    // Continue to generate SRPM and submit new build
+                }
+                CoprBuildStatus::Submitted | CoprBuildStatus::InProgress => {
+                    info!(
+                        "Copr build {} is in progress for {}, waiting...",
+                        existing_build.build_id, build_key
+                    );
+                    // Wait for existing build (no SRPM generation needed)
+                    wait_for_copr_build(existing_build.build_id, build_key, copr_state_file, copr_state_mutex).await?;
+                    return Ok(());
+                }
+            }
+        }
+    } else {
+        // For local builds, check if build directory already exists
+        let build_dir_final = args.workspace.join("builds").join(build_key.build_dir_name());
+        let build_subdir_final = build_dir_final.join("build");
+        if build_subdir_final.exists() {
+            if !force {
+                info!("Build already exists, skipping");
+                return Ok(());
+            }
+            // The existing build is only replaced once the new one succeeds
+            info!("Build already exists, rebuilding as forced");
+        } else if !force && fetch_cached_build(build_key, args).await? {
+            return Ok(());
+        }
+    }
+
+    // The release suffix is passed as a define, so that it reaches every builder along with the other ones
+    let source = &release::with_release_suffix(source, build_key, &args.workspace)?;
+
+    let build_dir = args
+        .workspace
+        .join("builds")
+        .join(format!("{}.tmp", build_key.build_dir_name()));
+
+    let _ = fs::remove_dir_all(&build_dir);
+
+    // Check if build already exists - if so, do nothing
+    let build_subdir = build_dir.join("build");
+
+    // Create build subdirectory
+    fs::create_dir_all(&build_subdir)
+        .with_context(|| format!("Failed to create build subdirectory: {}", build_subdir.display()))?;
+    debug!("Created build subdirectory: {}", build_subdir.display());
+
+    // If there are dependencies, create deps directory and hardlink them (skip for remote builds)
+    if !all_dependencies.is_empty() && !backend.is_remote() {
+        let deps_dir = build_dir.join("deps");
+        fs::create_dir_all(&deps_dir)
+            .with_context(|| format!("Failed to create deps directory: {}", deps_dir.display()))?;
+        info!("Created deps directory: {}", deps_dir.display());
+
+        // Hardlink each dependency's build directory
+        for (dep_key, dep_hash) in all_dependencies.iter() {
+            let dep_build_key = BuildKey::new(dep_key.clone(), dep_hash.clone());
+            let dep_build_dir = args.workspace.join("builds").join(dep_build_key.build_dir_name()).join("build");
+
+            let target_dir = deps_dir.join(dep_build_key.build_dir_name());
+
+            if !dep_build_dir.exists() {
+                // Dependencies built in Copr are only available from there
+                if let Some(copr_state_file) = &args.copr_state_file {
+                    download_copr_build(
+                        &dep_build_key, copr_state_file, copr_state_mutex, &target_dir, target_os,
+                    )
+                    .await?;
+                    continue;
+                }
+                anyhow::bail!("Dependency build directory does not exist: {}", dep_build_dir.display());
+            }
+
+            copy_dir_all(&dep_build_dir, &target_dir).with_context(|| {
+                format!(
+                    "Failed to copy dependency from {} to {}",
+                    dep_build_dir.display(),
+                    target_dir.display()
+                )
+            })?;
+            debug!("Hardlinked dependency {} to deps directory", dep_key);
+        }
+
+        // Run createrepo_c to create repository metadata (skip for Docker backend)
+        if *backend != BuilderBackend::Docker {
+            let shell = Shell::new(&deps_dir);
+            shell
+                .run_with_output("createrepo_c .")
+                .await
+                .context("Failed to create repository metadata with createrepo_c")?;
+            info!("Created repository metadata in deps directory");
+        }
+    }
+
+    // Get source working path (exported revision if specified, or repo path)
+    let repo_path = source.get_working_path(&build_key.source_key, &args.workspace, false)?;
+
+    let srpm_path = match &source.typ {
+        // SRPM sources are used as-is, the file is placed where a generated SRPM would be
+        SourceType::Srpm { .. } => copy_srpm_to_build_dir(&repo_path, &build_dir)?,
+        SourceType::Git { subpath, .. } => {
+            let subpath = subpath.as_deref();
+
+            // Determine the working directory for fedpkg
+            let fedpkg_working_dir = if let Some(subpath) = subpath {
+                let subpath_dir = repo_path.join(subpath);
+                if !subpath_dir.exists() {
+                    anyhow::bail!(
+                        "Subpath '{}' does not exist in repository at {}",
+                        subpath,
+                        repo_path.display()
+                    );
+                }
+                if !subpath_dir.is_dir() {
+                    anyhow::bail!(
+                        "Subpath '{}' is not a directory in repository at {}",
+                        subpath,
+                        repo_path.display()
+                    );
+                }
+                subpath_dir
+            } else {
+                repo_path
+            };
+
+            generate_srpm(
+                build_key, source, target_os, &build_dir, subpath, "srpm", fedpkg_working_dir,
+                false, // use_rpmbuild = false for regular fedpkg generation
+            )
+            .await?
+        }
+    };
+
+    let nvr = match nvr::query_srpm(&srpm_path) {
+        Ok(nvr) => {
+            info!("Building {} as {}", build_key.source_key, nvr);
+            Some(nvr)
+        }
+        Err(e) => {
+            warn!("Failed to read NVR of {}: {:#}", build_key.source_key, e);
+            None
+        }
+    };
+
+    // Create build information file
+    create_build_info_file(build_key, source, &args.workspace, &build_subdir, nvr)?;
+
+    // Build command based on backend
+    match backend {
+        BuilderBackend::Mock => {
+            build_with_mock(
+                source,
+                all_dependencies,
+                &args.workspace,
+                build_dir.clone(),
+                build_subdir,
+                &srpm_path,
+                target_os,
+            )
+            .await?;
+        }
+        BuilderBackend::Null => {
+            info!("🚫 Null backend");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        BuilderBackend::Docker => {
+            build_under_docker(
+                &args.workspace,
+                target_os,
+                build_dir.clone(),
+                &source.build_params()?,
+                args.debug_prepare,
+                source.network,
+                &args.with_repo,
+                &source.repos,
+            )
+            .await
+            .with_context(|| format!("Docker build failed for {}", build_key))?;
+        }
+        BuilderBackend::Copr => {
+            let copr_project = args
+                .copr_project
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Copr project name is required for Copr backend"))?;
+            let copr_state_file = args
+                .copr_state_file
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Copr state file is required for Copr backend"))?;
+
+            // If we reach here, we need to submit a new build (state already checked earlier)
+            build_with_copr(
+                build_key, source, &srpm_path, copr_project, &args.exclude_chroot, copr_state_file, copr_state_mutex,
+                &build_dir, target_os,
+            )
+            .await?;
+        }
+    }
+
+    // For remote builds, we don't need to rename directories since builds happen remotely
+    if !backend.is_remote() {
+        let build_dir_final = args.workspace.join("builds").join(build_key.build_dir_name());
+        move_build_into_place(&build_dir, &build_dir_final)?;
+
+        if let Some(cache_dir) = &args.cache_dir {
+            // Copying the build blocks, so it must not hold up the other build tasks
+            let (cache_dir, task_build_key, workspace) = (cache_dir.clone(), build_key.clone(), args.workspace.clone());
+            let published =
+                tokio::task::spawn_blocking(move || cache::publish(&cache_dir, &task_build_key, &workspace, force))
+                    .await
+                    .context("Publishing to the artifact cache panicked")?;
+            if let Err(e) = published {
+                warn!("Failed to publish {} to artifact cache: {:#}", build_key, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Rename a finished build directory into place, replacing the existing build of a forced rebuild. The existing build
+/// is moved aside first, so that it is kept if the rename fails.
+fn move_build_into_place(build_dir: &Path, build_dir_final: &Path) -> Result<()> {
+    let old_dir = build_dir_final.with_file_name(format!(
+        "{}.old",
+        build_dir_final.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let replacing = build_dir_final.exists();
+    if replacing {
+        let _ = fs::remove_dir_all(&old_dir);
+        fs::rename(build_dir_final, &old_dir).with_context(|| {
+            format!(
+                "Failed to move existing build directory {} aside",
+                build_dir_final.display()
+            )
+        })?;
+    }
+
+    if let Err(err) = fs::rename(build_dir, build_dir_final) {
+        if replacing {
+            let _ = fs::rename(&old_dir, build_dir_final);
+        }
+        return Err(err).with_context(|| {
+            format!(
+                "Failed to rename build directory from {} to {}",
+                build_dir.display(),
+                build_dir_final.display()
+            )
+        });
+    }
+
+    if replacing {
+        fs::remove_dir_all(&old_dir)
+            .with_context(|| format!("Failed to remove previous build directory: {}", old_dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Put a build in place in the workspace from the artifact cache, or else from the remote cache, returning whether
+/// either had it. Builds pulled from the remote cache are published to the artifact cache too.
+async fn fetch_cached_build(build_key: &BuildKey, args: &BuildOptions) -> Result<bool> {
+    if args.cache_dir.is_none() && args.remote_cache.is_none() {
+        return Ok(false);
+    }
+
+    // Copying and downloading builds blocks, so it must not hold up the other build tasks
+    let (build_key, args) = (build_key.clone(), args.clone());
+    tokio::task::spawn_blocking(move || fetch_cached_build_blocking(&build_key, &args))
+        .await
+        .context("Fetching a cached build panicked")
+}
+
+fn fetch_cached_build_blocking(build_key: &BuildKey, args: &BuildOptions) -> bool {
+    if let Some(cache_dir) = &args.cache_dir {
+        match cache::fetch(cache_dir, build_key, &args.workspace) {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(e) => warn!("Failed to fetch {} from artifact cache: {:#}", build_key, e),
+        }
+    }
+
+    if let Some(remote) = &args.remote_cache {
+        match remote_cache::pull_build(remote, build_key, &args.workspace) {
+            Ok(true) => {
+                if let Some(cache_dir) = &args.cache_dir {
+                    if let Err(e) = cache::publish(cache_dir, build_key, &args.workspace, false) {
+                        warn!("Failed to publish {} to artifact cache: {:#}", build_key, e);
+                    }
+                }
+                return true;
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Failed to pull {} from remote cache: {:#}", build_key, e),
+        }
+    }
+
+    false
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn generate_srpm(
+    build_key: &BuildKey, source: &Source, target_os: Option<&str>, build_dir: &Path, subpath: Option<&str>,
+    dirname: &str, fedpkg_working_dir: PathBuf, use_rpmbuild: bool,
+) -> Result<PathBuf, anyhow::Error> {
+    let base_os = match target_os {
+        Some(os) => os.to_string(),
+        None => get_base_os()?,
+    };
+
+    // Check for RHEL Git packaging mode (SOURCES subdirectory exists)
+    let sources_dir = fedpkg_working_dir.join("SOURCES");
+    let specs_dir = fedpkg_working_dir.join("SPECS");
+    let is_rhel_packaging = sources_dir.exists();
+
+    let fedpkg_defines = if is_rhel_packaging {
+        info!("Detected RHEL Git packaging mode (SOURCES directory found)");
+        format!(
+            " --define \"_sourcedir {}\" --define \"_specdir {}\"",
+            sources_dir.shell_escaped(),
+            specs_dir.shell_escaped()
+        )
+    } else {
+        String::new()
+    };
+
+    // Build the params string for fedpkg srpm (pass as extra args after --)
+    let fedpkg_params = source.build_params()?.to_command_args(" -- ");
+
+    let shell = Shell::new(&fedpkg_working_dir);
+    let build_srpm_dir = build_dir.join(dirname);
+
+    if use_rpmbuild {
+        // Use rpmbuild -bs directly (for repacking with baked parameters)
+        info!("Generating SRPM using rpmbuild -bs");
+
+        // Find the spec file
+        let specs_dir = fedpkg_working_dir.join("SPECS");
+        let spec_files: Vec<_> = std::fs::read_dir(&specs_dir)
+            .with_context(|| format!("Failed to read SPECS directory: {}", specs_dir.display()))?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let path = entry.path();
+                if path.extension()? == "spec" {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if spec_files.is_empty() {
+            anyhow::bail!("No spec file found for rpmbuild");
+        }
+
+        if spec_files.len() > 1 {
+            anyhow::bail!("Multiple spec files found for rpmbuild: {:?}", spec_files);
+        }
+
+        let spec_file = &spec_files[0];
+
+        shell
+            .run_with_output(&format!(
+                "rpmbuild -bs --define \"_topdir {}\" --define \"_srcrpmdir {}\" {}",
+                fedpkg_working_dir.shell_escaped(),
+                build_srpm_dir.shell_escaped(),
+                spec_file.shell_escaped()
+            ))
+            .await
+            .with_context(|| format!("Failed to generate SRPM with rpmbuild for {}", build_key.source_key))?;
+    } else {
+        // Use fedpkg srpm (original behavior)
+        info!(
+            "Generating source RPM using fedpkg{}{}",
+            subpath.map(|s| format!(" from subpath '{}'", s)).unwrap_or_default(),
+            if is_rhel_packaging { " (RHEL mode)" } else { "" }
+        );
+
+        shell
+            .run_with_output(&format!(
+                "fedpkg --release {} srpm --define \"_srcrpmdir {}\"{}{}",
+                base_os.shell_escaped(),
+                build_srpm_dir.shell_escaped(),
+                fedpkg_defines,
+                fedpkg_params
+            ))
+            .await
+            .with_context(|| format!("Failed to generate SRPM with fedpkg for {}", build_key.source_key))?;
+    }
+
+    // Find the generated SRPM file
+    let srpm_files: Vec<_> = std::fs::read_dir(&build_srpm_dir)
+        .with_context(|| format!("Failed to read SRPM directory: {}", build_srpm_dir.display()))?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path.extension()? == "rpm" && path.file_name()?.to_str()?.ends_with(".src.rpm") {
+                Some(path)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if srpm_files.is_empty() {
+        anyhow::bail!("No source RPM found after fedpkg srpm");
+    }
+
+    if srpm_files.len() > 1 {
+        anyhow::bail!(
+            "Multiple source RPMs found for {}: {:?}",
+            build_key.source_key.as_ref(),
+            srpm_files
+        );
+    }
+
+    let srpm_path = &srpm_files[0];
+
+    debug!("Found source RPM: {}", srpm_path.display());
+    Ok(srpm_path.clone())
+}
+
+fn parse_repo_spec(spec: &str) -> Result<(String, Vec<String>)> {
+    let parts: Vec<&str> = spec.splitn(2, ':').collect();
+    if parts.len() != 2 {
+        anyhow::bail!(
+            "Invalid repo spec format: '{}'. Expected format: <name>:<field1>,<field2>,...",
+            spec
+        );
+    }
+
+    let name = parts[0].trim().to_string();
+    if name.is_empty() {
+        anyhow::bail!("Repo name cannot be empty in spec: '{}'", spec);
+    }
+
+    let fields: Vec<String> = parts[1]
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    if fields.is_empty() {
+        anyhow::bail!("At least one field must be specified in repo spec: '{}'", spec);
+    }
+
+    Ok((name, fields))
+}
+
+fn create_repo_dockerfile_commands(repo_specs: &[String], source_repos: &[repos::RepoSpec]) -> Result<String> {
+    if repo_specs.is_empty() && source_repos.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut commands = Vec::new();
+
+    let mut all_repos = Vec::new();
+    for spec in repo_specs {
+        info!("Adding repo spec: {}", spec);
+        all_repos.push(parse_repo_spec(spec)?);
+    }
+    for repo in source_repos {
+        info!("Adding repo {}: {}", repo.name, repo.baseurl);
+        all_repos.push((repo.name.clone(), repo.repo_file_fields()));
+    }
+
+    for (name, fields) in all_repos {
+        // Create the repo file content
+        let mut repo_content = format!("[{}]\n", name);
+        for field in &fields {
+            repo_content.push_str(field);
+            repo_content.push('\n');
+        }
+
+        repo_content.push_str("enabled=1\n");
+
+        // Create a RUN command to write the repo file
+        let cmd = format!(
+            "RUN printf '{}' > /etc/yum.repos.d/{}.repo",
+            repo_content.replace('\n', "\\n"),
+            name
+        );
+        warn!("{:?}", cmd);
+        commands.push(cmd);
+    }
+
+    Ok(commands.join("\n"))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn build_under_docker(
+    workspace: &Path, target_os: Option<&str>, build_dir: PathBuf, params: &params::BuildParams, debug_prepare: bool,
+    network_enabled: bool, with_repo: &[String], source_repos: &[repos::RepoSpec],
+) -> Result<(), anyhow::Error> {
+    let base_os = match target_os {
+        Some(os) => os.to_string(),
+        None => get_base_os()?,
+    };
+
+    info!("Using base OS: {}", base_os);
+
+    let dockerfile = docker::get_builder_dockerfile_for_os(&base_os)?;
+    let mut image = match docker::ensure_image(&base_os, &dockerfile, "").await? {
+        Ok(image) => image,
+        Err(output) => anyhow::bail!(
+            "error creating base os image: {:?}",
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    };
+
+    // Chain in another docker build for repos before dependencies
+    if !with_repo.is_empty() || !source_repos.is_empty() {
+        let repo_commands = create_repo_dockerfile_commands(with_repo, source_repos)?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(format!("base: {}\n", &image));
+        hasher.update(format!("repos: {}\n", with_repo.join(",")));
+        if !source_repos.is_empty() {
+            hasher.update(format!("source repos: {:?}\n", source_repos));
+        }
+        let repo_image = format!("{}:{:x}", image, hasher.finalize());
+
+        let dockerfile_with_repos = format!("FROM {}\n{}", image, repo_commands);
+
+        debug!("repo dockerfile: {}", dockerfile_with_repos);
+        image = match docker::ensure_image(&repo_image, &dockerfile_with_repos, "--layers=false").await? {
+            Ok(image) => image,
+            Err(output) => anyhow::bail!(
+                "error creating repo image: {:?}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        };
+
+        info!("Created image with repositories: {}", image);
+    }
+
+    let shell = Shell::new(workspace)
+        .with_image(&image)
+        .with_mount(build_dir.to_string_lossy().as_ref(), "/workspace")
+        .with_network(network_enabled);
+
+    // Build the params string for rpmbuild
+    let params_str = params.to_command_args(" ");
+
+    let missing_deps = shell
+        .run_with_output(&format!(
+            r#"
+rpm -D "_topdir /workspace/build" -i /workspace/srpm/*.src.rpm
+
+list-missing-deps() {{
+    local param="-br"
+    if ! rpmbuild -br 2>/dev/null ; then
+        param="-bp"
+    fi
+
+    (rpmbuild ${{param}} "-D _topdir /workspace/build"{} /workspace/build/SPECS/*.spec 2>&1 || true) \
+        | (grep -v ^error: || true) \
+        | grep -E '([^ ]*) is needed by [^ ]+$' \
+        | sed -E 's/[\t]/ /g' \
+        | sed -E 's/ +(.*) is needed by [^ ]+$/\1/g'
+}}
+
+# >&2
+list-missing-deps
+        "#,
+            params_str
+        ))
+        .await?;
+
+    // Chain in another docker build for dependencies (replaces `image'`).
+    let mut deps: Vec<_> = missing_deps.lines().collect();
+    if !deps.is_empty() {
+        info!("Found {} dependencies", deps.len());
+        debug!("Dependencies: {:?}", deps);
+
+        let dep_repo = build_dir.join("deps").exists();
+
+        deps.sort();
+        let deps = deps.iter().map(|x| format!("{:?}", x)).collect::<Vec<_>>().join(" ");
+
+        let mut hasher = Sha256::new();
+        hasher.update(format!("base: {}\n", &image));
+        hasher.update("deps: ");
+        hasher.update(deps.as_bytes());
+        let deps_image = format!("{}:{:x}", image.split(':').next().unwrap_or(&image), hasher.finalize());
+        let dockerfile = if dep_repo {
+            format!(
+                r#"FROM {image}
+COPY --from=deps / /deps
+RUN createrepo_c /deps
+RUN dnf install --repofrompath=deps,file:///deps --setopt=deps.gpgcheck=0 --enablerepo=deps -y {deps}
+RUN rm -rf /deps
+"#
+            )
+        } else {
+            format!(
+                r#"FROM {image}
+RUN dnf install -y {deps}
+"#
+            )
+        };
+        debug!("image with deps Dockerfile: {:?}", dockerfile);
+        image = match docker::ensure_image(
+            &deps_image,
+            &dockerfile,
+            &if dep_repo {
+                format!("--layers=false --build-context deps={}/deps", build_dir.display())
+            } else {
+                "--layers=false".to_string()
+            },
+        )
+        .await?
+        {
+            Ok(image) => image,
+            Err(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let mut found = false;
+                for line in stderr.lines() {
+                    if let Some(package_start) = line.find("Error: Unable to find a match: ") {
+                        let package = &line[package_start + "Error: Unable to find a match: ".len()..];
+                        error!("Error: Unable to find a match: {}", package.replace(" \\t", " "));
+                        found = true;
+                        break;
+                    }
+                }
+                if !found {
+                    error!("Error: {}", stderr);
+                }
+                anyhow::bail!("not being able to install dependencies");
+            }
+        };
+
+        info!("Building on image {image}");
+    }
+
+    let shell = Shell::new(workspace)
+        .with_image(&image)
+        .with_mount(build_dir.to_string_lossy().as_ref(), "/workspace")
+        .with_network(network_enabled);
+
+    if debug_prepare {
+        info!("🔍 Debug mode: Running rpmbuild -bp (prepare only)");
+        shell
+            .run_logged(&format!(
+                r#"
+rpmbuild -bp -D "_topdir /workspace/build"{} /workspace/build/SPECS/*.spec
+            "#,
+                params_str
+            ))
+            .await
+            .context("Failed to prepare sources with rpmbuild -bp")?;
+
+        // Print the prepared source path
+        let build_sources_path = build_dir.join("build/BUILD");
+        info!("📁 Prepared sources available at: {}", build_sources_path.display());
+        info!("🔍 You can inspect the prepared sources by examining the BUILD directory");
+        info!("💡 The sources are left in the workspace for debugging purposes");
+
+        // Intentionally fail the build as requested
+        anyhow::bail!("Build intentionally stopped after prepare phase for debugging (--debug-prepare mode)");
+    } else {
+        shell
+            .run_logged(&format!(
+                r#"
+rpmbuild -ba -D "_topdir /workspace/build"{} /workspace/build/SPECS/*.spec
+            "#,
+                params_str
+            ))
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn repack_srpm_with_params(
+    build_key: &BuildKey, source: &Source, srpm_path: &Path, build_dir: &Path, target_os: Option<&str>,
+) -> Result<PathBuf> {
+    let repack_dir = build_dir.join("repack");
+
+    // Remove existing repack directory if it exists
+    if repack_dir.exists() {
+        fs::remove_dir_all(&repack_dir)
+            .with_context(|| format!("Failed to remove existing repack directory: {}", repack_dir.display()))?;
+    }
+
+    // Create repack directory
+    fs::create_dir_all(&repack_dir)
+        .with_context(|| format!("Failed to create repack directory: {}", repack_dir.display()))?;
+
+    info!("📦 Extracting SRPM to repack directory");
+
+    // Extract SRPM to repack directory using rpm -i
+    let shell = Shell::new(&repack_dir);
+    shell
+        .run_with_output(&format!(
+            "rpm -i --define \"_topdir {}\" \"{}\"",
+            repack_dir.display(),
+            srpm_path.display()
+        ))
+        .await
+        .with_context(|| format!("Failed to extract SRPM: {}", srpm_path.display()))?;
+
+    // Find and edit the spec file
+    let specs_dir = repack_dir.join("SPECS");
+    let spec_files: Vec<_> = std::fs::read_dir(&specs_dir)
+        .with_context(|| format!("Failed to read SPECS directory: {}", specs_dir.display()))?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path.extension()? == "spec" {
+                Some(path)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if spec_files.is_empty() {
+        anyhow::bail!("No spec file found in extracted SRPM");
+    }
+
+    if spec_files.len() > 1 {
+        anyhow::bail!("Multiple spec files found in extracted SRPM: {:?}", spec_files);
+    }
+
+    let spec_file = &spec_files[0];
+    info!("📝 Editing spec file: {}", spec_file.display());
+
+    // Read and modify spec file
+    let spec_content =
+        fs::read_to_string(spec_file).with_context(|| format!("Failed to read spec file: {}", spec_file.display()))?;
+
+    let modified_spec_content = params::modify_spec_for_params(&spec_content, &source.build_params()?)?;
+
+    // Write modified spec file
+    fs::write(spec_file, modified_spec_content)
+        .with_context(|| format!("Failed to write modified spec file: {}", spec_file.display()))?;
+
+    info!("🔧 Repacking SRPM with modified spec");
+
+    // Repack the SRPM using generate_srpm with rpmbuild
+    let repacked_srpm_path = generate_srpm(
+        build_key,
+        source,
+        target_os,
+        build_dir,
+        None, // subpath = None for repack
+        "srpm-params",
+        repack_dir.clone(),
+        true, // use_rpmbuild = true for repacking with baked parameters
+    )
+    .await?;
+
+    // Clean up repack directory
+    fs::remove_dir_all(&repack_dir)
+        .with_context(|| format!("Failed to remove repack directory: {}", repack_dir.display()))?;
+
+    info!("✅ Successfully repacked SRPM with parameters");
+    Ok(repacked_srpm_path)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn build_with_copr(
+    build_key: &BuildKey, source: &Source, srpm_path: &Path, copr_project: &str, exclude_chroots: &[String],
+    copr_state_file: &Path, state_mutex: &Mutex<()>, build_dir: &Path, target_os: Option<&str>,
+) -> Result<()> {
+    // Repack SRPM with baked-in build parameters for Copr
+    let final_srpm_path = if !source.build_params()?.is_empty() {
+        info!("🔄 Repacking SRPM with build parameters for Copr");
+        repack_srpm_with_params(build_key, source, srpm_path, build_dir, target_os).await?
+    } else {
+        srpm_path.to_path_buf()
+    };
+
+    // Submit new build
+    info!("Submitting Copr build for {}", build_key);
+    let mut copr_cmd = vec![
+        "copr".to_string(),
+        "build".to_string(),
+        "--nowait".to_string(),
+        copr_project.to_string(),
+        final_srpm_path.to_string_lossy().to_string(),
+    ];
+
+    // Add exclude-chroot arguments
+    for chroot in exclude_chroots {
+        copr_cmd.push("--exclude-chroot".to_string());
+        copr_cmd.push(chroot.clone());
+    }
+
+    // Add network flag if network access is enabled
+    if source.network {
+        copr_cmd.push("--enable-net".to_string());
+        copr_cmd.push("on".to_string());
+    }
+
+    let copr_command = copr_cmd.join(" ");
+    info!("Executing Copr command: {}", copr_command);
+
+    let current_dir = std::env::current_dir().context("Failed to get current working directory")?;
+    let shell = Shell::new(current_dir.as_path());
+    let output = shell
+        .run_with_output(&copr_command)
+        .await
+        .with_context(|| format!("Failed to execute Copr build command: {}", copr_command))?;
+
+    // Parse build ID from output
+    let build_id = extract_copr_build_id(&output)?;
+    info!("Copr build submitted with ID: {}", build_id);
+
+    // Atomically save build state
+    {
+        let _guard = state_mutex.lock().await;
+        let mut state = CoprStateFile::load_or_create(copr_state_file)?;
+        let build_state = CoprBuildState {
+            build_key: build_key.to_string(),
+            build_id,
+            status: CoprBuildStatus::Submitted,
+        };
+        state.set_build_state(build_key, build_state);
+        state.save(copr_state_file)?;
+    }
+
+    // Wait for build completion
+    wait_for_copr_build(build_id, build_key, copr_state_file, state_mutex).await
+}
+
+/// Prints the external repos of a Copr project, one per line. `copr-cli` has no command that shows them, so they are
+/// read with the Copr API client it is built on, which also resolves the owner of an unqualified project name.
+const COPR_PROJECT_REPOS_SCRIPT: &str = r#"
+import sys
+from copr.v3 import Client
+client = Client.create_from_config_file()
+owner, _, name = sys.argv[1].rpartition("/")
+for repo in client.project_proxy.get(owner or client.config["username"], name).additional_repos:
+    print(repo)
+"#;
+
+async fn get_copr_project_repos(copr_project: &str, shell: &Shell<'_>) -> Result<Vec<String>> {
+    let output = shell
+        .run_with_output(&format!(
+            "python3 -c {} {}",
+            COPR_PROJECT_REPOS_SCRIPT.shell_escaped(),
+            copr_project.shell_escaped()
+        ))
+        .await
+        .with_context(|| format!("Failed to get external repos of Copr project {}", copr_project))?;
+    Ok(output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// The external repos of the Copr project with the ones of the sources added, or `None` if it already has all of
+/// them. The existing repos are kept, as they may have been set for other packages of the project.
+fn merge_copr_repos(existing_repos: &[String], project_repos: &[RepoSpec]) -> Option<Vec<String>> {
+    let mut merged = existing_repos.to_vec();
+    for repo in project_repos {
+        if !merged.contains(&repo.baseurl) {
+            merged.push(repo.baseurl.clone());
+        }
+    }
+    (merged.len() > existing_repos.len()).then_some(merged)
+}
+
+/// Add the repos of the sources built in the Copr project to its external repos.
+///
+/// Copr applies external repos to the whole project, so every source built there sees the repos of all of them.
+async fn configure_copr_repos(copr_project: &str, sources: &[(String, &Source)]) -> Result<()> {
+    let mut project_repos = Vec::new();
+    for (_, source) in sources {
+        repos::add_repos(&mut project_repos, &source.repos)?;
+    }
+    if project_repos.is_empty() {
+        return Ok(());
+    }
+
+    for (name, source) in sources {
+        for repo in &project_repos {
+            if !source.repos.contains(repo) {
+                warn!(
+                    "Copr repos are project-wide: {} will also see repo '{}' of the other sources",
+                    name, repo.name
+                );
+            }
+        }
+    }
+
+    let current_dir = std::env::current_dir().context("Failed to get current working directory")?;
+    let shell = Shell::new(current_dir.as_path());
+
+    // `copr modify --repo` replaces the whole list of external repos of the project
+    let existing_repos = get_copr_project_repos(copr_project, &shell).await?;
+    let Some(merged_repos) = merge_copr_repos(&existing_repos, &project_repos) else {
+        debug!(
+            "Copr project {} already has the external repos of its sources",
+            copr_project
+        );
+        return Ok(());
+    };
+
+    let repo_args: Vec<String> = merged_repos
+        .iter()
+        .map(|baseurl| format!("--repo {}", baseurl.shell_escaped()))
+        .collect();
+    let modify_command = format!("copr modify {} {}", repo_args.join(" "), copr_project.shell_escaped());
+    info!("Setting external repos of Copr project: {}", modify_command);
+
+    shell
+        .run_with_output(&modify_command)
+        .await
+        .with_context(|| format!("Failed to set external repos of Copr project {}", copr_project))?;
+
+    Ok(())
+}
+
+async fn wait_for_copr_build(
+    build_id: u64, build_key: &BuildKey, copr_state_file: &Path, state_mutex: &Mutex<()>,
+) -> Result<()> {
+    info!("Waiting for Copr build {} to complete", build_id);
+
+    // Atomically update status to InProgress
+    {
+        let _guard = state_mutex.lock().await;
+        let mut state = CoprStateFile::load_or_create(copr_state_file)?;
+        if let Some(build_state) = state.builds.get_mut(&build_key.to_string()) {
+            build_state.status = CoprBuildStatus::InProgress;
+            state.save(copr_state_file)?;
+        }
+    }
+
+    let watch_command = format!("copr watch-build {}", build_id);
+    let current_dir = std::env::current_dir().context("Failed to get current working directory")?;
+    let shell = Shell::new(current_dir.as_path());
+
+    match shell
+        .run_with_output(&watch_command)
+        .await
+        .with_context(|| format!("Failed to execute Copr watch command: {}", watch_command))
+    {
+        Ok(_) => {
+            info!("✅ Copr build {} completed successfully", build_id);
+            // Atomically update status to Completed
+            {
+                let _guard = state_mutex.lock().await;
+                let mut state = CoprStateFile::load_or_create(copr_state_file)?;
+                if let Some(build_state) = state.builds.get_mut(&build_key.to_string()) {
+                    build_state.status = CoprBuildStatus::Completed;
+                    state.save(copr_state_file)?;
+                }
+            }
+            Ok(())
+        }
+        Err(e) => {
+            error!("❌ Copr build {} failed: {}", build_id, e);
+            // Atomically update status to Failed
+            {
+                let _guard = state_mutex.lock().await;
+                let mut state = CoprStateFile::load_or_create(copr_state_file)?;
+                if let Some(build_state) = state.builds.get_mut(&build_key.to_string()) {
+                    build_state.status = CoprBuildStatus::Failed;
+                    state.save(copr_state_file)?;
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Download the RPMs of a completed Copr build for the chroot of the target OS, or of the host OS when there is no
+/// target, as the Copr project may build for several chroots.
+async fn download_copr_build(
+    build_key: &BuildKey, copr_state_file: &Path, state_mutex: &Mutex<()>, dest_dir: &Path, target_os: Option<&str>,
+) -> Result<()> {
+    let build_state = {
+        let _guard = state_mutex.lock().await;
+        let state = CoprStateFile::load_or_create(copr_state_file)?;
+        state.get_build_state(build_key).cloned()
+    };
+
+    let build_id = match build_state {
+        Some(build_state) if build_state.status == CoprBuildStatus::Completed => build_state.build_id,
+        _ => anyhow::bail!(
+            "Dependency {} has neither a local build nor a completed Copr build",
+            build_key
+        ),
+    };
+
+    let target_os = match target_os {
+        Some(os) => os.to_string(),
+        None => get_base_os().context("Cannot tell the Copr chroot of the host OS, --target-os is required")?,
+    };
+    let chroot = copr_chroot_for_os(&target_os)?;
+
+    info!("Downloading Copr build {} of {} for {}", build_id, build_key, chroot);
+    fs::create_dir_all(dest_dir).with_context(|| format!("Failed to create directory: {}", dest_dir.display()))?;
+    let shell = Shell::new(dest_dir);
+    shell
+        .run_with_output(&format!(
+            "copr download-build --chroot {} --dest . {}",
+            chroot.shell_escaped(),
+            build_id
+        ))
+        .await
+        .with_context(|| format!("Failed to download Copr build {} of {}", build_id, build_key))?;
+
+    Ok(())
+}
+
+fn extract_copr_build_id(output: &str) -> Result<u64> {
+    for line in output.lines() {
+        if line.starts_with("Created builds: ") {
+            let id_str = line.strip_prefix("Created builds: ").unwrap().trim();
+            return id_str
+                .parse::<u64>()
+                .map_err(|e| anyhow::anyhow!("Failed to parse build ID '{}': {}", id_str, e));
+        }
+    }
+    anyhow::bail!("No 'Created builds:' line found in Copr output");
+}
+
+/// The mock command that builds a source RPM, in the chroot of the target OS if there is one, or else in the default
+/// chroot of the host.
+fn mock_command(
+    source: &Source, all_dependencies: &HashMap<SourceKey, BuildHash>, build_dir: &Path, build_subdir: &Path,
+    srpm_path: &Path, target_os: Option<&str>,
+) -> Result<String> {
+    let mut mock_cmd = vec!["mock".to_string()];
+    if let Some(target_os) = target_os {
+        mock_cmd.push("-r".to_string());
+        mock_cmd.push(mock_config_for_os(target_os)?);
+    }
+    mock_cmd.extend([
+        "--resultdir".to_string(),
+        build_subdir.to_string_lossy().to_string(),
+        srpm_path.to_string_lossy().to_string(),
+    ]);
+    if !all_dependencies.is_empty() {
+        let deps_dir = build_dir.join("deps");
+        mock_cmd.push("--addrepo".to_string());
+        mock_cmd.push(deps_dir.to_string_lossy().to_string());
+    }
+    for repo in &source.repos {
+        // Mock only takes the URL of additional repos
+        mock_cmd.push("--addrepo".to_string());
+        mock_cmd.push(repo.baseurl.shell_escaped().to_string());
+    }
+    let params = source.build_params()?.to_command_args("");
+    if !params.is_empty() {
+        mock_cmd.push(params);
+    }
+    Ok(mock_cmd.join(" "))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn build_with_mock(
+    source: &Source, all_dependencies: &HashMap<SourceKey, BuildHash>, workspace: &Path, build_dir: PathBuf,
+    build_subdir: PathBuf, srpm_path: &Path, target_os: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let mock_command = mock_command(
+        source, all_dependencies, &build_dir, &build_subdir, srpm_path, target_os,
+    )?;
+    info!("Executing mock: {}", mock_command);
+    let shell = Shell::new(workspace);
+    shell
+        .run_logged(&mock_command)
+        .await
+        .with_context(|| format!("Failed to execute mock build command: {}", mock_command))?;
+    info!("✅ Successfully built with mock");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn build_source_task(
+    build_key: BuildKey, source: Source, target_os: Option<String>, all_dependencies: HashMap<SourceKey, BuildHash>,
+    args: BuildOptions, force: bool, skip: bool, copr_state_mutex: std::sync::Arc<Mutex<()>>,
+    direct_dependency_receivers: Vec<(SourceKey, mpsc::Receiver<bool>)>,
+    direct_completion_senders: Vec<mpsc::Sender<bool>>,
+) -> Result<()> {
+    info!("🚀 Starting build task");
+
+    // Check if this source should be skipped, either as left out of the build or based on assume_built regex
+    let mut skip_reason = skip.then(|| "left out by --only or --exclude".to_string());
+    if let Some(pattern) = &args.assume_built {
+        let regex =
+            Regex::new(pattern).with_context(|| format!("Invalid regex pattern for assume_built: {}", pattern))?;
+
+        if regex.is_match(build_key.source_key.as_ref()) {
+            skip_reason = Some(format!("matches assume_built pattern: {}", pattern));
+        }
+    }
+
+    if let Some(reason) = skip_reason {
+        info!("⏭️  Skipping build for {} ({})", build_key.source_key, reason);
+
+        // Notify all waiting tasks that this build is "complete"
+        for sender in direct_completion_senders {
+            if let Err(e) = sender.send(true).await {
+                error!("Failed to notify completion: {}", e);
+            }
+        }
+        return Ok(());
+    }
+
+    // Wait for all dependencies to complete successfully
+    for (dep_key, mut receiver) in direct_dependency_receivers {
+        info!("⏳ Waiting for dependency {} to complete...", dep_key);
+        match receiver.recv().await {
+            Some(true) => {
+                info!("✅ Dependency {} completed successfully", dep_key);
+            }
+            Some(false) => {
+                error!("❌ Dependency {} failed to build", dep_key);
+                // Notify all waiting tasks that this build failed
+                for sender in direct_completion_senders {
+                    let _ = sender.send(false).await;
+                }
+                anyhow::bail!("Dependency {} failed, cannot build", dep_key);
+            }
+            None => {
+                error!("❌ Dependency {} channel closed unexpectedly", dep_key);
+                // Notify all waiting tasks that this build failed
+                for sender in direct_completion_senders {
+                    let _ = sender.send(false).await;
+                }
+                anyhow::bail!("Dependency {} channel closed, cannot build {}", dep_key, build_key.source_key);
+            }
+        }
+    }
+
+    info!("🔨 All dependencies ready");
+
+    // Use block_in_place to call the synchronous build_source function
+    let build_result = build_source(
+        &build_key,
+        &source,
+        target_os.as_deref(),
+        &all_dependencies,
+        &args,
+        force,
+        &copr_state_mutex,
+    )
+    .await;
+
+    // Determine success/failure and notify all waiting tasks
+    let success = match &build_result {
+        Ok(()) => {
+            info!("✅ Build completed successfully");
+            true
+        }
+        Err(e) => {
+            error!("❌ Build failed, error chain:");
+            let mut index = 0;
+            e.chain().for_each(|cause| {
+                tracing::error!("  [{}]: {}", index, cause);
+                index += 1;
+            });
+
+            false
+        }
+    };
+
+    // Notify all tasks waiting for this build to complete
+    for sender in direct_completion_senders {
+        if let Err(e) = sender.send(success).await {
+            error!("Failed to notify completion: {}", e);
+        }
+    }
+
+    if success {
+        info!("🎉 Build task completed successfully");
+    }
+
+    build_result
+}
+
+/// Compute the build hashes of the given sources and of all their dependencies, for a target OS or for the default
+/// target of the backend.
+pub fn compute_all_build_hashes(
+    sources: &[SourceKey], spec_tree: &SpecTree, source_hashes: &SourceHashes, target: Option<&str>,
+    environment: &BuildEnvironment,
+) -> Result<HashMap<SourceKey, BuildHash>, Error> {
+    cycles::ensure_acyclic(sources, spec_tree)?;
+
+    let mut build_hashes = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut recursion_stack = Vec::new();
+
+    for source_key in sources {
+        let _ = compute_build_hash_recursive(
+            source_key, spec_tree, source_hashes, target, environment, &mut build_hashes, &mut visited,
+            &mut recursion_stack,
+        )?;
+    }
+
+    Ok(build_hashes)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compute_build_hash_recursive(
+    source_key: &SourceKey, spec_tree: &SpecTree, source_hashes: &SourceHashes, target: Option<&str>,
+    environment: &BuildEnvironment, build_hashes: &mut HashMap<SourceKey, BuildHash>, visited: &mut HashSet<SourceKey>,
+    recursion_stack: &mut Vec<SourceKey>,
+) -> Result<BuildHash, Error> {
+    // Check for cycles - if this source is already in the recursion stack
+    if recursion_stack.contains(source_key) {
+        return Err(Error::Cycle(format!(
+            "Circular dependency detected during build hash calculation: {}",
+            cycle_path(recursion_stack, source_key)
+        )));
+    }
+
+    // If we've already processed this source completely, return the cached hash
+    if let Some(existing_hash) = build_hashes.get(source_key) {
+        return Ok(existing_hash.clone());
+    }
+
+    // Add to recursion stack to detect cycles
+    recursion_stack.push(source_key.clone());
+
+    // Get the source definition
+    let source = spec_tree
+        .sources
+        .get(source_key)
+        .ok_or_else(|| Error::SourceNotFound(source_key.clone()))?;
+
+    // First, recursively compute build hashes for all dependencies and collect them
+    let mut dep_build_hashes = HashMap::new();
+    for dep_key in &source.dependencies {
+        // Parse the dependency to handle ~ prefix
+        let dependency = Dependency::parse(dep_key.as_ref());
+        let actual_dep_key = SourceKey::from(dependency.key().to_string());
+
+        // Recursively compute the dependency's build hash
+        let dep_build_hash = compute_build_hash_recursive(
+            &actual_dep_key, spec_tree, source_hashes, target, environment, build_hashes, visited, recursion_stack,
+        )?;
+
+        dep_build_hashes.insert(actual_dep_key, dep_build_hash);
+    }
+
+    // Now compute the build hash for this source
+    let source_hash = source_hashes
+        .hashes
+        .get(source_key)
+        .ok_or_else(|| Error::MissingSourceHash(source_key.clone()))?;
+
+    // Calculate the build hash
+    let target = source.target_os.as_deref().or(target);
+    let build_hash = calculate_build_hash(source_key, source, source_hash, &dep_build_hashes, target, environment)?;
+    build_hashes.insert(source_key.clone(), build_hash.clone());
+
+    // Remove from recursion stack and mark as visited
+    recursion_stack.pop();
+    visited.insert(source_key.clone());
+
+    Ok(build_hash)
+}
+
+fn copy_build_results_to_output_dir(
+    output_dir: &Path, root_sources: &[SourceKey],
+    all_dependencies_map: &HashMap<SourceKey, HashMap<SourceKey, BuildHash>>,
+    build_hashes: &HashMap<SourceKey, BuildHash>, workspace: &Path, layout: OutputLayout,
+) -> Result<()> {
+    info!("Copying build results to output directory: {}", output_dir.display());
+
+    // Create output directory if it doesn't exist
+    std::fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create output directory: {}", output_dir.display()))?;
+
+    // Collect all sources to copy: root sources and their dependencies
+    let mut sources_to_copy = HashSet::new();
+
+    // Add all root sources
+    for root_source in root_sources {
+        sources_to_copy.insert(root_source.clone());
+
+        // Add all dependencies of this root source
+        if let Some(dependencies) = all_dependencies_map.get(root_source) {
+            for dep_key in dependencies.keys() {
+                sources_to_copy.insert(dep_key.clone());
+            }
+        }
+    }
+
+    // Copy each source's build directory
+    let mut dest_names: HashMap<String, SourceKey> = HashMap::new();
+    for source_key in sources_to_copy {
+        let build_hash = build_hashes.get(&source_key).unwrap();
+        let build_key = BuildKey::new(source_key.clone(), build_hash.clone());
+        let source_build_dir = workspace.join("builds").join(build_key.build_dir_name());
+
+        if source_build_dir.exists() {
+            let dest_name = match layout {
+                OutputLayout::BuildKey => build_key.build_dir_name(),
+                OutputLayout::Nvr => match nvr::read_build_nvr(&source_build_dir) {
+                    Some(nvr) => nvr.file_name(),
+                    None => {
+                        warn!("NVR of {} is unknown, naming it by its build key", build_key);
+                        build_key.build_dir_name()
+                    }
+                },
+            };
+            if let Some(other_key) = dest_names.insert(dest_name.clone(), source_key.clone()) {
+                anyhow::bail!(
+                    "Sources '{}' and '{}' both build {}; use --output-layout build-key to copy both",
+                    other_key,
+                    source_key,
+                    dest_name
+                );
+            }
+
+            let dest_dir = output_dir.join(dest_name);
+            info!("Copying {} to {}", source_build_dir.display(), dest_dir.display());
+
+            // Copy the entire build directory
+            copy_dir_all(&source_build_dir, &dest_dir).with_context(|| {
+                format!(
+                    "Failed to copy build directory from {} to {}",
+                    source_build_dir.display(),
+                    dest_dir.display()
+                )
+            })?;
+        } else {
+            info!(
+                "Build directory does not exist (remote build?): {}",
+                source_build_dir.display()
+            );
+        }
+    }
+
+    info!("✅ Build results copied to output directory successfully!");
+    Ok(())
+}
+
+/// Everything known about the build for one target before any build task is spawned.
+struct BuildPlan {
+    /// Target OS of the builds, or `None` for the default target of the backend
+    target: Option<String>,
+    /// Root sources that are built for this target
+    root_sources: Vec<SourceKey>,
+    /// The spec tree without the sources that are not built for this target
+    spec_tree: SpecTree,
+    dependency_pairs: Vec<(SourceKey, SourceKey)>,
+    all_sources: Vec<SourceKey>,
+    source_hashes: SourceHashes,
+    build_hashes: HashMap<SourceKey, BuildHash>,
+    all_dependencies_map: HashMap<SourceKey, HashMap<SourceKey, BuildHash>>,
+    /// Sources to rebuild even if they were already built
+    forced: HashSet<SourceKey>,
+    /// Sources that are part of the plan for the hashes of their dependents, but are not built
+    skipped: HashSet<SourceKey>,
+}
+
+impl BuildPlan {
+    /// Name of a source for log and error messages, qualified by the target if there is one.
+    fn describe(&self, source_key: &SourceKey) -> String {
+        match &self.target {
+            Some(target) => format!("{} ({})", source_key, target),
+            None => source_key.to_string(),
+        }
+    }
+
+    /// The built sources that no other built source depends on. Their builds finish last, so waiting for them waits
+    /// for every build. These are not only root sources: the dependencies of a root that is left out become leaves.
+    fn leaf_sources(&self) -> Vec<SourceKey> {
+        let dependency_sources: HashSet<&SourceKey> = self
+            .dependency_pairs
+            .iter()
+            .filter(|(dependent, _)| !self.skipped.contains(dependent))
+            .map(|(_, dependency)| dependency)
+            .collect();
+
+        self.all_sources
+            .iter()
+            .filter(|source| !dependency_sources.contains(source) && !self.skipped.contains(*source))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The targets to build for: those from the command line, or else those from the spec file.
+fn resolve_targets(target_os: &[String], spec_tree: &SpecTree) -> Vec<Option<String>> {
+    let targets = if target_os.is_empty() { &spec_tree.targets } else { target_os };
+    if targets.is_empty() {
+        return vec![None];
+    }
+
+    let mut resolved: Vec<Option<String>> = Vec::new();
+    for target in targets {
+        if !resolved.iter().any(|t| t.as_deref() == Some(target.as_str())) {
+            resolved.push(Some(target.clone()));
+        }
+    }
+    resolved
+}
+
+/// The spec tree as seen by one target: sources that are not built for the target are removed, along with the
+/// dependencies on them, which the target OS is expected to provide.
+pub fn spec_tree_for_target(spec_tree: &SpecTree, target: Option<&str>) -> SpecTree {
+    let mut target_tree = spec_tree.clone();
+    target_tree.sources.retain(|_, source| source.is_built_for_target(target));
+
+    let skipped: HashSet<&SourceKey> = spec_tree
+        .sources
+        .keys()
+        .filter(|key| !target_tree.sources.contains_key(*key))
+        .collect();
+    for (key, source) in target_tree.sources.iter_mut() {
+        source.dependencies.retain(|dep_str| {
+            let dependency = Dependency::parse(dep_str.as_ref());
+            let dep_key = SourceKey::from(dependency.key().to_string());
+            if skipped.contains(&dep_key) {
+                warn!(
+                    "Dropping dependency {} -> {}, which is not built for {} and must be provided by it",
+                    key,
+                    dep_key,
+                    target.unwrap_or_default()
+                );
+                return false;
+            }
+            true
+        });
+    }
+
+    target_tree
+}
+
+/// The sources that `--only` and `--exclude` leave out of the build.
+fn select_skipped_sources(args: &BuildOptions, all_sources: &[SourceKey]) -> Result<HashSet<SourceKey>> {
+    let only = args
+        .only
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("Invalid regex pattern for --only")?;
+    let exclude = args
+        .exclude
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("Invalid regex pattern for --exclude")?;
+
+    Ok(all_sources
+        .iter()
+        .filter(|key| {
+            only.as_ref().is_some_and(|only| !only.is_match(key.as_ref()))
+                || exclude.as_ref().is_some_and(|exclude| exclude.is_match(key.as_ref()))
+        })
+        .cloned()
+        .collect())
+}
+
+/// Fail if a source that is not built is needed by a source that is, unless it was already built or is assumed
+/// to be built.
+fn check_skipped_sources(args: &BuildOptions, build_plan: &BuildPlan) -> Result<()> {
+    if build_plan.skipped.is_empty() {
+        return Ok(());
+    }
+
+    let assume_built = args
+        .assume_built
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("Invalid regex pattern for --assume-built")?;
+
+    let mut needed: HashSet<&SourceKey> = HashSet::new();
+    for (dependent, dependency) in &build_plan.dependency_pairs {
+        if !build_plan.skipped.contains(dependent) {
+            needed.insert(dependency);
+        }
+    }
+    for (key, dependencies) in &build_plan.all_dependencies_map {
+        if !build_plan.skipped.contains(key) {
+            needed.extend(dependencies.keys());
+        }
+    }
+
+    let mut missing = Vec::new();
+    for key in build_plan.all_sources.iter().filter(|key| build_plan.skipped.contains(*key)) {
+        if !needed.contains(key) || assume_built.as_ref().is_some_and(|regex| regex.is_match(key.as_ref())) {
+            continue;
+        }
+        let source = &build_plan.spec_tree.sources[key];
+        let build_key = BuildKey::new(key.clone(), build_plan.build_hashes[key].clone());
+        if plan::get_build_status(args, args.backend_for(source), &build_key)? != plan::BuildStatus::Cached {
+            missing.push(build_plan.describe(key));
+        }
+    }
+
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Sources left out of the build are needed by the selected sources, but were not built yet: {}; \
+             build them first or use --assume-built",
+            missing.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Resolve the dependency graph of the root sources for every target and compute the source and build hashes of
+/// every source involved, without building anything.
+///
+/// Source hashes do not depend on the target, so each source is fetched and hashed only once. Hashing needs the
+/// sources, so this sets up the workspace, clones or fetches the git sources fetched from a URL, and exports pinned
+/// revisions into it. The commits that the sources fetched from a URL resolved to are only written to the lock file
+/// with `record_lock`, for builds, so that dry runs leave the checkout of the spec file alone.
+fn prepare_build_plans(args: &BuildOptions, record_lock: bool) -> Result<Vec<BuildPlan>> {
+    setup_workspace(&args.workspace)?;
+
+    let mut spec_tree = load_spec_tree(&args.spec_file, &args.set)?;
+
+    // Sources fetched from a URL are built at the commits they were locked at
+    let lock_path = lock::LockFile::path_for(&args.spec_file);
+    let mut lock_file = lock::LockFile::load(&lock_path)?;
+    lock_file.pin(&mut spec_tree);
+
+    if let Some(mode) = args.infer_deps {
+        infer::infer_dependencies(&mut spec_tree, &args.workspace, mode)?;
+    }
+
+    // Verify all root sources exist
+    if args.root_sources.is_empty() && args.rebuild_dependents_of.is_empty() {
+        anyhow::bail!("At least one root source must be specified");
+    }
+
+    for root_source in args.root_sources.iter().chain(&args.rebuild_dependents_of).chain(&args.up_to) {
+        if !spec_tree.sources.contains_key(root_source) {
+            anyhow::bail!("Root source '{}' not found in spec tree", root_source);
+        }
+    }
+
+    let targets = resolve_targets(&args.target_os, &spec_tree);
+    if targets.len() > 1 {
+        let mut keys: Vec<&SourceKey> = spec_tree.sources.keys().collect();
+        keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        if let Some(key) = keys.into_iter().find(|key| spec_tree.sources[*key].target_os.is_some()) {
+            anyhow::bail!(
+                "Source '{}' sets target_os, which cannot be combined with building for several targets; \
+                 use only_targets or skip_targets instead",
+                key
+            );
+        }
+    }
+
+    let mut build_plans = Vec::new();
+    for target in targets {
+        let target_tree = spec_tree_for_target(&spec_tree, target.as_deref());
+        let target_name = target.as_deref().unwrap_or("the default target");
+
+        let mut root_sources: Vec<SourceKey> = args
+            .root_sources
+            .iter()
+            .filter(|root_source| target_tree.sources.contains_key(*root_source))
+            .cloned()
+            .collect();
+        if root_sources.is_empty() && args.root_sources.is_empty() {
+            root_sources = target_tree.sources.keys().cloned().collect();
+            root_sources.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        }
+        if root_sources.is_empty() {
+            warn!("None of the root sources are built for {}, skipping it", target_name);
+            continue;
+        }
+
+        // Narrow the build down to the changed sources and their dependents
+        let mut forced = HashSet::new();
+        if !args.rebuild_dependents_of.is_empty() {
+            let dependents = dependents::find_dependents(&args.rebuild_dependents_of, &root_sources, &target_tree)?;
+            if dependents.roots.is_empty() {
+                warn!(
+                    "None of the sources to rebuild are reachable for {}, skipping it",
+                    target_name
+                );
+                continue;
+            }
+            info!(
+                "Rebuilding {} sources that depend on {} for {}",
+                dependents.selected.len(),
+                args.rebuild_dependents_of
+                    .iter()
+                    .map(|key| key.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                target_name
+            );
+            root_sources = dependents.roots;
+            if args.force {
+                forced = dependents.selected;
+            }
+        }
+
+        // Find all dependency pairs starting from the root sources
+        let mut dependency_pairs = find_all_dependency_pairs(&root_sources, &target_tree)?;
+
+        if let Some(up_to) = &args.up_to {
+            if !root_sources.contains(up_to) && !dependency_pairs.iter().any(|(_, dependency)| dependency == up_to) {
+                warn!(
+                    "'{}' is not reachable from the root sources for {}, skipping it",
+                    up_to, target_name
+                );
+                continue;
+            }
+            root_sources = vec![up_to.clone()];
+            dependency_pairs = find_all_dependency_pairs(&root_sources, &target_tree)?;
+        }
+
+        info!(
+            "Found {} dependency relationships for {} root sources for {}",
+            dependency_pairs.len(),
+            root_sources.len(),
+            target_name
+        );
+
+        // Log all dependency pairs for visibility
+        for (source, dependency) in &dependency_pairs {
+            debug!("Dependency: {} -> {}", source, dependency);
+        }
+
+        let mut all_sources = HashSet::new();
+        for root_source in &root_sources {
+            all_sources.insert(root_source.clone());
+        }
+        for (source, dependency) in &dependency_pairs {
+            all_sources.insert(source.clone());
+            all_sources.insert(dependency.clone());
+        }
+
+        let mut all_sources: Vec<SourceKey> = all_sources.into_iter().collect();
+        all_sources.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        info!(
+            "Total sources to build for {}: {} (including root and all dependencies)",
+            target_name,
+            all_sources.len()
+        );
+
+        let skipped = select_skipped_sources(args, &all_sources)?;
+        if skipped.len() == all_sources.len() {
+            anyhow::bail!("--only and --exclude leave no sources to build for {}", target_name);
+        }
+
+        build_plans.push(BuildPlan {
+            target,
+            root_sources,
+            spec_tree: target_tree,
+            dependency_pairs,
+            all_sources,
+            source_hashes: SourceHashes { hashes: HashMap::new() },
+            build_hashes: HashMap::new(),
+            all_dependencies_map: HashMap::new(),
+            forced,
+            skipped,
+        });
+    }
+
+    if build_plans.is_empty() && !args.rebuild_dependents_of.is_empty() {
+        anyhow::bail!("None of the sources to rebuild are reachable from the root sources");
+    }
+    if build_plans.is_empty() {
+        anyhow::bail!("None of the root sources are built for any of the targets");
+    }
+
+    // Calculate source hashes for the sources of all targets
+    let mut all_sources: Vec<SourceKey> = build_plans.iter().flat_map(|plan| plan.all_sources.clone()).collect();
+    all_sources.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    all_sources.dedup();
+    let source_hashes = get_source_hashes(&args.workspace, &spec_tree, &all_sources)?;
+    info!("Calculated source hashes for {} sources", source_hashes.hashes.len());
+
+    if record_lock && lock_file.record(&spec_tree, &source_hashes, &args.workspace)? {
+        lock_file.save(&lock_path)?;
+        info!("Updated lock file {}", lock_path.display());
+    }
+
+    let environment = BuildEnvironment::new(args, &spec_tree);
+    for build_plan in build_plans.iter_mut() {
+        // Calculate build hashes for all sources using recursion
+        let build_hashes = compute_all_build_hashes(
+            &build_plan.all_sources,
+            &build_plan.spec_tree,
+            &source_hashes,
+            build_plan.target.as_deref(),
+            &environment,
+        )?;
+        info!("Calculated build hashes for {} sources", build_hashes.len());
+
+        // Create all_dependencies mapping: HashMap<SourceKey, HashMap<SourceKey, BuildHash>>
+        let mut all_dependencies_map: HashMap<SourceKey, HashMap<SourceKey, BuildHash>> = HashMap::new();
+
+        for source_key in &build_plan.all_sources {
+            // Get all dependencies for this source
+            let source_deps = resolve_dependencies(source_key, &build_plan.spec_tree)?;
+
+            // Create the dependency mapping with their build hashes
+            let dependencies_with_hashes: HashMap<SourceKey, BuildHash> = source_deps
+                .iter()
+                .filter_map(|dep_key| build_hashes.get(dep_key).map(|hash| (dep_key.clone(), hash.clone())))
+                .collect();
+
+            all_dependencies_map.insert(source_key.clone(), dependencies_with_hashes);
+
+            debug!(
+                "Source {} has {} dependencies: {:?}",
+                build_plan.describe(source_key),
+                source_deps.len(),
+                source_deps
+            );
+        }
+
+        info!("Created dependency mappings for {} sources", all_dependencies_map.len());
+
+        // Log summary information
+        for (source_key, deps) in &all_dependencies_map {
+            info!(
+                "Source '{}' depends on {} sources",
+                build_plan.describe(source_key),
+                deps.len()
+            );
+        }
+
+        build_plan.source_hashes = source_hashes.clone();
+        build_plan.build_hashes = build_hashes;
+        build_plan.all_dependencies_map = all_dependencies_map;
+
+        check_backend_dependencies(args, build_plan)?;
+        check_skipped_sources(args, build_plan)?;
+    }
+
+    Ok(build_plans)
+}
+
+/// Builds in Copr can only use dependencies that are in the Copr project as well. The other way around works,
+/// as local builds download their dependencies from Copr.
+fn check_backend_dependencies(args: &BuildOptions, build_plan: &BuildPlan) -> Result<()> {
+    for source_key in &build_plan.all_sources {
+        let backend = args.backend_for(&build_plan.spec_tree.sources[source_key]);
+        if !backend.is_remote() {
+            continue;
+        }
+
+        let mut dep_keys: Vec<&SourceKey> = build_plan.all_dependencies_map[source_key].keys().collect();
+        dep_keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        for dep_key in dep_keys {
+            let dep_backend = args.backend_for(&build_plan.spec_tree.sources[dep_key]);
+            if !dep_backend.is_remote() {
+                anyhow::bail!(
+                    "Source '{}' is built with {} but depends on '{}', which is built with {}; \
+                     builds in {} can only depend on sources built there as well",
+                    build_plan.describe(source_key),
+                    backend,
+                    dep_key,
+                    dep_backend,
+                    backend
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+type BuildTask = tokio::task::JoinHandle<Result<()>>;
+
+/// Spawn a build task for every source of a build plan, connected by channels along the dependency pairs.
+fn spawn_build_tasks(
+    args: &BuildOptions, build_plan: &BuildPlan, copr_state_mutex: &std::sync::Arc<Mutex<()>>,
+) -> Vec<(SourceKey, BuildTask)> {
+    // Create channels for each dependency pair and organize by source
+    let mut source_completion_senders: HashMap<SourceKey, Vec<mpsc::Sender<bool>>> = HashMap::new();
+    let mut source_dependency_receivers: HashMap<SourceKey, Vec<(SourceKey, mpsc::Receiver<bool>)>> = HashMap::new();
+
+    // Initialize empty vectors for all sources
+    for source_key in &build_plan.all_sources {
+        source_completion_senders.insert(source_key.clone(), Vec::new());
+        source_dependency_receivers.insert(source_key.clone(), Vec::new());
+    }
+
+    // Create channels for each dependency pair, except for sources that are not built and so wait for nothing
+    for (dependent, dependency) in &build_plan.dependency_pairs {
+        if build_plan.skipped.contains(dependent) {
+            continue;
+        }
+        let (tx, rx) = mpsc::channel::<bool>(1);
+
+        // The dependency source gets the sender to notify when it completes
+        source_completion_senders.get_mut(dependency).unwrap().push(tx);
+
+        // The dependent source gets the receiver to wait for the dependency
+        source_dependency_receivers
+            .get_mut(dependent)
+            .unwrap()
+            .push((dependency.clone(), rx));
+
+        info!("Created channel for dependency pair: {} -> {}", dependency, dependent);
+    }
+
+    // For each source, create the direct dependency receivers and completion senders
+    let mut source_tasks = Vec::new();
+
+    for source_key in &build_plan.all_sources {
+        let source = build_plan.spec_tree.sources.get(source_key).unwrap().clone();
+        let source_build_hash = build_plan.build_hashes.get(source_key).unwrap().clone();
+        let source_deps = build_plan.all_dependencies_map.get(source_key).cloned().unwrap_or_default();
+
+        // Get dependency receivers for this source (to wait for dependencies)
+        let direct_dependency_receivers = source_dependency_receivers.remove(source_key).unwrap_or_default();
+        // Get completion senders for this source (to notify dependents)
+        let direct_completion_senders = source_completion_senders.remove(source_key).unwrap_or_default();
+
+        info!(
+            "Source {} has {} dependency receivers and {} completion senders",
+            build_plan.describe(source_key),
+            direct_dependency_receivers.len(),
+            direct_completion_senders.len()
+        );
+
+        // Spawn the build task
+        let task_source_key = source_key.clone();
+        let task_target = build_plan.target.clone();
+        let task_args = args.clone();
+        let task_force = build_plan.forced.contains(source_key);
+        let task_skip = build_plan.skipped.contains(source_key);
+        let task_copr_state_mutex = copr_state_mutex.clone();
+        let key = build_plan.describe(source_key);
+
+        let task = tokio::spawn(async move {
+            let task_build_key = BuildKey::new(task_source_key, source_build_hash);
+            build_source_task(
+                task_build_key, source, task_target, source_deps, task_args, task_force, task_skip,
+                task_copr_state_mutex, direct_dependency_receivers, direct_completion_senders,
+            )
+            .instrument(span!(Level::INFO, "task", key = %key))
+            .await
+        });
+
+        source_tasks.push((source_key.clone(), task));
+    }
+
+    source_tasks
+}
+
+/// Wait until every built source of a build plan is built, or fail on the first failed build.
+async fn wait_for_build_tasks(build_plan: &BuildPlan, source_tasks: Vec<(SourceKey, BuildTask)>) -> Result<()> {
+    // A failed build fails its dependents, so waiting for the leaves catches every failure
+    let leaf_sources = build_plan.leaf_sources();
+    info!("Waiting for sources to complete: {:?}", leaf_sources);
+
+    for (source_key, task) in source_tasks {
+        if leaf_sources.contains(&source_key) {
+            match task.await {
+                Ok(Ok(())) => {
+                    info!(
+                        "✅ Source '{}' completed successfully!",
+                        build_plan.describe(&source_key)
+                    );
+                }
+                Ok(Err(e)) => {
+                    anyhow::bail!("❌ Source '{}' failed: {}", build_plan.describe(&source_key), e);
+                }
+                Err(e) => {
+                    anyhow::bail!("❌ Source '{}' task panicked: {}", build_plan.describe(&source_key), e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Plans and runs the builds of a spec tree, as `spectree plan` and `spectree build` do.
+pub struct Builder {
+    options: BuildOptions,
+}
+
+impl Builder {
+    pub fn new(options: BuildOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &BuildOptions {
+        &self.options
+    }
+
+    /// Resolve the sources to build for every target and compute their hashes, without building anything. Like a
+    /// build, this creates the workspace and clones or fetches the sources into it, in order to hash them.
+    pub fn plan(&self) -> Result<PlanReport, Error> {
+        let build_plans = prepare_build_plans(&self.options, false).map_err(|err| Error::classify(err, Error::Plan))?;
+        PlanReport::new(&self.options, &build_plans).map_err(|err| Error::classify(err, Error::Plan))
+    }
+
+    /// Push the builds of the plan that are in the workspace to the remote cache.
+    pub fn push_to_remote_cache(&self) -> Result<CacheSyncReport, Error> {
+        self.sync_remote_cache(remote_cache::push_builds)
+    }
+
+    /// Pull the builds of the plan that are not in the workspace from the remote cache.
+    pub fn pull_from_remote_cache(&self) -> Result<CacheSyncReport, Error> {
+        self.sync_remote_cache(remote_cache::pull_builds)
+    }
+
+    fn sync_remote_cache(
+        &self, sync: fn(&RemoteCache, &BuildOptions, &[BuildPlan]) -> Result<CacheSyncReport>,
+    ) -> Result<CacheSyncReport, Error> {
+        let remote = self.options.remote_cache.as_ref().ok_or_else(|| {
+            Error::InvalidValue("A remote cache must be given with --remote-cache or SPECTREE_REMOTE_CACHE".to_string())
+        })?;
+        let build_plans = prepare_build_plans(&self.options, false).map_err(|err| Error::classify(err, Error::Plan))?;
+        sync(remote, &self.options, &build_plans).map_err(|err| Error::classify(err, Error::RemoteCache))
+    }
+
+    /// Build every source of the plan that was not built yet, and copy the results to the output directory.
+    pub async fn build(&self) -> Result<(), Error> {
+        run_build(&self.options).await.map_err(|err| Error::classify(err, Error::Build))
+    }
+}
+
+/// Remove the tagged builder images that Docker builds leave behind, keeping the `latest` ones.
+pub async fn clean_docker_images() -> Result<(), Error> {
+    docker::clean_images().await.map_err(|err| Error::classify(err, Error::Build))
+}
+
+async fn run_build(args: &BuildOptions) -> Result<()> {
+    // Always create the mutex (simpler than conditional logic)
+    let copr_state_mutex = std::sync::Arc::new(Mutex::new(()));
+
+    let build_plans = prepare_build_plans(args, true)?;
+
+    // Sources may override the backend, so validate against all the backends in use
+    let backends: HashSet<&BuilderBackend> = build_plans
+        .iter()
+        .flat_map(|plan| {
+            plan.all_sources
+                .iter()
+                .map(|key| args.backend_for(&plan.spec_tree.sources[key]))
+        })
+        .collect();
+
+    // Validate Copr arguments if using Copr backend
+    if backends.contains(&BuilderBackend::Copr) {
+        if args.copr_project.is_none() {
+            anyhow::bail!("--copr-project is required when using Copr backend");
+        }
+        if args.copr_state_file.is_none() {
+            anyhow::bail!("--copr-state-file is required when using Copr backend");
+        }
+    }
+
+    if let Some(copr_project) = &args.copr_project {
+        let copr_sources: Vec<(String, &Source)> = build_plans
+            .iter()
+            .flat_map(|plan| plan.all_sources.iter().map(move |key| (plan, key)))
+            .map(|(plan, key)| (plan.describe(key), &plan.spec_tree.sources[key]))
+            .filter(|(_, source)| args.backend_for(source).is_remote())
+            .collect();
+        configure_copr_repos(copr_project, &copr_sources).await?;
+    }
+
+    // Validate debug_prepare is only used with Docker backend
+    if args.debug_prepare && !backends.contains(&BuilderBackend::Docker) {
+        anyhow::bail!("--debug-prepare can only be used with Docker backend");
+    }
+
+    // Validate with_repo is only used with Docker backend
+    if !args.with_repo.is_empty() && !backends.contains(&BuilderBackend::Docker) {
+        anyhow::bail!("--with-repo can only be used with Docker backend");
+    }
+
+    // Each (source, target) pair is a task of its own; the tasks of different targets run side by side
+    let mut target_tasks = Vec::new();
+    for build_plan in &build_plans {
+        let source_tasks = spawn_build_tasks(args, build_plan, &copr_state_mutex);
+        info!("Spawned {} build tasks", source_tasks.len());
+        target_tasks.push((build_plan, source_tasks));
+    }
+
+    for (build_plan, source_tasks) in target_tasks {
+        wait_for_build_tasks(build_plan, source_tasks).await?;
+    }
+
+    // Copy build results to output directory if specified
+    if let Some(output_dir) = &args.output_dir {
+        for build_plan in &build_plans {
+            // With several targets, the results are grouped in a subdirectory per target
+            let target_output_dir = match &build_plan.target {
+                Some(target) if build_plans.len() > 1 => output_dir.join(target),
+                _ => output_dir.clone(),
+            };
+            copy_build_results_to_output_dir(
+                &target_output_dir, &build_plan.root_sources, &build_plan.all_dependencies_map,
+                &build_plan.build_hashes, &args.workspace, args.output_layout,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        copr_chroot_for_os, merge_copr_repos, mock_command, move_build_into_place, wait_for_build_tasks, BuildPlan,
+        SourceHashes,
+    };
+    use crate::spec_file::load_test_tree;
+    use crate::{calculate_build_hash, BuildEnvironment, BuildHash, RepoSpec, SourceHash, SourceKey};
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn key(name: &str) -> SourceKey {
+        SourceKey::from(name.to_string())
+    }
+
+    #[test]
+    fn test_mock_command() {
+        let tree = load_test_tree("app: {dependencies: [lib], with: [docs]}\nlib: {}\n");
+        let deps = HashMap::from([(key("lib"), BuildHash::from("abc".to_string()))]);
+        let command = |target_os| {
+            mock_command(
+                &tree.sources[&key("app")],
+                &deps,
+                Path::new("/ws/builds/app"),
+                Path::new("/ws/builds/app/build"),
+                Path::new("/ws/builds/app/srpm/app-1-1.src.rpm"),
+                target_os,
+            )
+        };
+        let arch = std::env::consts::ARCH;
+
+        assert_eq!(
+            command(Some("epel9")).unwrap(),
+            format!(
+                "mock -r rocky+epel-9-{} --resultdir /ws/builds/app/build /ws/builds/app/srpm/app-1-1.src.rpm \
+                 --addrepo /ws/builds/app/deps --with docs",
+                arch
+            )
+        );
+        assert!(command(Some("fedora42"))
+            .unwrap()
+            .starts_with(&format!("mock -r fedora-42-{} --resultdir", arch)));
+        assert!(command(None).unwrap().starts_with("mock --resultdir"));
+        assert!(command(Some("debian12")).is_err());
+        assert!(command(Some("epel")).is_err());
+    }
+
+    #[test]
+    fn test_copr_chroot_for_os() {
+        let arch = std::env::consts::ARCH;
+        assert_eq!(copr_chroot_for_os("epel9").unwrap(), format!("epel-9-{}", arch));
+        assert_eq!(copr_chroot_for_os("fedora42").unwrap(), format!("fedora-42-{}", arch));
+        assert!(copr_chroot_for_os("debian12").is_err());
+    }
+
+    #[test]
+    fn test_build_hash_host_os() {
+        let spec_tree = load_test_tree("app: {}\nremote: {backend: copr}\n");
+        let hash = |name: &str, target: Option<&str>, host_os: Option<&str>| {
+            let environment = BuildEnvironment { host_os: host_os.map(str::to_string), ..Default::default() };
+            calculate_build_hash(
+                &key(name),
+                &spec_tree.sources[&key(name)],
+                &SourceHash::from("0123456789abcdef".to_string()),
+                &HashMap::new(),
+                target,
+                &environment,
+            )
+            .unwrap()
+        };
+
+        // Local builds without a target are built for the host OS
+        assert_ne!(hash("app", None, Some("epel9")), hash("app", None, Some("epel10")));
+        assert_eq!(hash("app", None, Some("epel9")), hash("app", Some("epel9"), None));
+        assert_eq!(
+            hash("app", Some("epel9"), Some("epel9")),
+            hash("app", Some("epel9"), Some("epel10"))
+        );
+        // Remote ones are not
+        assert_eq!(
+            hash("remote", None, Some("epel9")),
+            hash("remote", None, Some("epel10"))
+        );
+    }
+
+    #[test]
+    fn test_merge_copr_repos() {
+        let repo = |name: &str, baseurl: &str| RepoSpec {
+            name: name.to_string(),
+            baseurl: baseurl.to_string(),
+            gpgcheck: None,
+            priority: None,
+            module_hotfixes: None,
+        };
+        let existing = vec!["https://example.com/other".to_string()];
+
+        assert_eq!(
+            merge_copr_repos(&existing, &[repo("extra", "https://example.com/extra")]),
+            Some(vec![
+                "https://example.com/other".to_string(),
+                "https://example.com/extra".to_string()
+            ])
+        );
+        assert_eq!(
+            merge_copr_repos(&existing, &[repo("other", "https://example.com/other")]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_dependencies_of_excluded_root() {
+        let spec_tree = load_test_tree("app: {dependencies: [lib]}\nlib: {}\ntool: {}\n");
+        let build_plan = BuildPlan {
+            target: None,
+            root_sources: vec![key("app"), key("tool")],
+            spec_tree,
+            dependency_pairs: vec![(key("app"), key("lib"))],
+            all_sources: vec![key("lib"), key("app"), key("tool")],
+            source_hashes: SourceHashes { hashes: HashMap::new() },
+            build_hashes: HashMap::new(),
+            all_dependencies_map: HashMap::new(),
+            forced: HashSet::new(),
+            skipped: HashSet::from([key("app")]),
+        };
+        assert_eq!(build_plan.leaf_sources(), vec![key("lib"), key("tool")]);
+
+        let spawn = |name: &str, fails: bool| {
+            let task = tokio::spawn(async move {
+                if fails {
+                    anyhow::bail!("build failed");
+                }
+                Ok(())
+            });
+            (key(name), task)
+        };
+        let tasks = vec![spawn("tool", false), spawn("app", false), spawn("lib", true)];
+        let err = wait_for_build_tasks(&build_plan, tasks).await.unwrap_err();
+        assert!(err.to_string().contains("Source 'lib' failed"), "{}", err);
+
+        let tasks = vec![spawn("tool", false), spawn("app", false), spawn("lib", false)];
+        wait_for_build_tasks(&build_plan, tasks).await.unwrap();
+    }
+
+    #[test]
+    fn test_move_build_into_place() {
+        let dir = TempDir::new().unwrap();
+        let build_dir = dir.path().join("hello-0123.tmp");
+        let build_dir_final = dir.path().join("hello-0123");
+        let built = |content: &str| {
+            fs::create_dir_all(build_dir.join("build")).unwrap();
+            fs::write(build_dir.join("build/hello.rpm"), content).unwrap();
+        };
+        let installed = || fs::read_to_string(build_dir_final.join("build/hello.rpm")).unwrap();
+
+        built("first");
+        move_build_into_place(&build_dir, &build_dir_final).unwrap();
+        assert_eq!(installed(), "first");
+
+        // A rebuild replaces the previous build
+        built("second");
+        move_build_into_place(&build_dir, &build_dir_final).unwrap();
+        assert_eq!(installed(), "second");
+        assert!(!build_dir.exists());
+        assert!(!dir.path().join("hello-0123.old").exists());
+
+        // The previous build is kept when the new one cannot be moved into place
+        assert!(move_build_into_place(&build_dir, &build_dir_final).is_err());
+        assert_eq!(installed(), "second");
+        assert!(!dir.path().join("hello-0123.old").exists());
+    }
+}
@@ -0,0 +1,428 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::shell::{Shell, ShellEscaped};
+use crate::{
+    calc_source_hash, load_spec_tree, setup_workspace, Error, Source, SourceHashes, SourceKey, SourceType, SpecTree,
+};
+
+/// The name of the lock file, which is kept next to the spec file.
+pub const LOCK_FILE_NAME: &str = "spectree.lock";
+
+/// The revision that a source fetched from a URL resolved to.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockedSource {
+    pub url: String,
+    /// The revision given in the spec file, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    pub commit: String,
+    /// The git tree of the commit, or of its subpath, which is the source hash of the source
+    pub tree: String,
+}
+
+/// The revisions that the sources fetched from a URL resolved to, so that they are built from the same code until
+/// they are updated explicitly.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockFile {
+    #[serde(default)]
+    pub sources: BTreeMap<String, LockedSource>,
+}
+
+/// The URL and revision of a source that is fetched from a URL, rather than being given by a local path. A
+/// `file://` URL is locked like any other, as its repo may move on just as well.
+fn remote_origin(source: &Source) -> Option<(&str, Option<&str>)> {
+    match &source.typ {
+        SourceType::Git { url: Some(url), path: None, revision, .. } => Some((url.as_str(), revision.as_deref())),
+        _ => None,
+    }
+}
+
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(12)]
+}
+
+impl LockFile {
+    /// The lock file of a spec file.
+    pub fn path_for(spec_file: &Path) -> PathBuf {
+        spec_file.with_file_name(LOCK_FILE_NAME)
+    }
+
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read lock file: {}", path.display()))?;
+        serde_yaml::from_str(&content).with_context(|| format!("Failed to parse lock file: {}", path.display()))
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        let content = serde_yaml::to_string(self).context("Failed to serialize lock file to YAML")?;
+        fs::write(path, content).with_context(|| format!("Failed to write lock file: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The entry of a source, unless its URL or revision changed in the spec file since it was locked.
+    fn entry_for(&self, key: &SourceKey, source: &Source) -> Option<&LockedSource> {
+        let (url, revision) = remote_origin(source)?;
+        self.sources
+            .get(key.as_ref())
+            .filter(|entry| entry.url == url && entry.revision.as_deref() == revision)
+    }
+
+    /// Build the locked sources of a spec tree at their locked commits, as if they were given as their `revision`.
+    pub(crate) fn pin(&self, spec_tree: &mut SpecTree) {
+        for (key, source) in spec_tree.sources.iter_mut() {
+            let Some(commit) = self.entry_for(key, source).map(|entry| entry.commit.clone()) else {
+                continue;
+            };
+            if let SourceType::Git { revision, .. } = &mut source.typ {
+                *revision = Some(commit);
+            }
+        }
+    }
+
+    /// Lock the sources fetched from a URL that were hashed, at the commits they resolved to, and forget the
+    /// sources that are no longer in the spec tree. Returns whether the lock file changed.
+    pub(crate) fn record(
+        &mut self, spec_tree: &SpecTree, source_hashes: &SourceHashes, workspace: &Path,
+    ) -> Result<bool> {
+        let before = self.clone();
+        self.sources.retain(|key, _| {
+            spec_tree
+                .sources
+                .get(key.as_str())
+                .is_some_and(|source| remote_origin(source).is_some())
+        });
+
+        let mut keys: Vec<&SourceKey> = source_hashes.hashes.keys().collect();
+        keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        for key in keys {
+            let source = &spec_tree.sources[key];
+            let Some((url, revision)) = remote_origin(source) else {
+                continue;
+            };
+            let tree = source_hashes.hashes[key].to_string();
+
+            // Pinned sources have their locked commit as their revision
+            if let Some(entry) = self.sources.get_mut(key.as_ref()) {
+                if Some(entry.commit.as_str()) == revision {
+                    entry.tree = tree;
+                    continue;
+                }
+            }
+
+            let commit = resolve_commit(key, source, revision, workspace)?;
+            info!("Locking {} at {}", key, short(&commit));
+            self.sources.insert(
+                key.to_string(),
+                LockedSource {
+                    url: url.to_string(),
+                    revision: revision.map(str::to_string),
+                    commit,
+                    tree,
+                },
+            );
+        }
+
+        Ok(*self != before)
+    }
+}
+
+/// The commit that the revision of a source, or the head of its clone, resolves to.
+fn resolve_commit(key: &SourceKey, source: &Source, revision: Option<&str>, workspace: &Path) -> Result<String> {
+    let repo_path = source.get_repo_path(key, workspace, false)?;
+    let revision = revision.unwrap_or("HEAD");
+    Shell::new(&repo_path)
+        .run_with_output_sync(&format!(
+            "git rev-parse {}",
+            format!("{}^{{commit}}", revision).shell_escaped()
+        ))
+        .with_context(|| format!("Failed to resolve git revision '{}' for source {}", revision, key))
+}
+
+/// Fetch the latest revisions of the given sources, or of all the sources fetched from a URL, and lock them in the
+/// lock file of the spec file.
+pub fn update_lock_file(
+    spec_file: &Path, workspace: &Path, keys: &[SourceKey], vars: &[(String, String)],
+) -> Result<LockFile, Error> {
+    update_lock_file_inner(spec_file, workspace, keys, vars).map_err(|err| Error::classify(err, Error::Lock))
+}
+
+fn update_lock_file_inner(
+    spec_file: &Path, workspace: &Path, keys: &[SourceKey], vars: &[(String, String)],
+) -> Result<LockFile> {
+    setup_workspace(workspace)?;
+    let spec_tree = load_spec_tree(spec_file, vars)?;
+    let lock_path = LockFile::path_for(spec_file);
+    let mut lock_file = LockFile::load(&lock_path)?;
+
+    let mut keys = keys.to_vec();
+    if keys.is_empty() {
+        keys = spec_tree
+            .sources
+            .iter()
+            .filter(|(_, source)| remote_origin(source).is_some())
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    }
+
+    let mut source_hashes = SourceHashes::default();
+    for key in &keys {
+        let source = spec_tree.sources.get(key).ok_or_else(|| Error::SourceNotFound(key.clone()))?;
+        if remote_origin(source).is_none() {
+            anyhow::bail!("Source '{}' is not fetched from a URL, so it is not locked", key);
+        }
+        source_hashes
+            .hashes
+            .insert(key.clone(), calc_source_hash(key, source, workspace)?);
+    }
+
+    // Sources that are not updated keep their locked commits
+    let previous = lock_file.clone();
+    for key in &keys {
+        lock_file.sources.remove(key.as_ref());
+    }
+    lock_file.record(&spec_tree, &source_hashes, workspace)?;
+
+    for key in &keys {
+        let old = previous.sources.get(key.as_ref()).map(|entry| entry.commit.as_str());
+        let new = &lock_file.sources[key.as_ref()].commit;
+        match old {
+            Some(old) if old != new => info!("Updated {}: {} -> {}", key, short(old), short(new)),
+            Some(_) => info!("{} is up to date at {}", key, short(new)),
+            None => {}
+        }
+    }
+
+    lock_file.save(&lock_path)?;
+    Ok(lock_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update_lock_file, LockFile, LockedSource};
+    use crate::spec_file::load_test_tree;
+    use crate::utils::{test_commit as commit, test_git as git};
+    use crate::{
+        prepare_build_plans, BuildOptions, Builder, BuilderBackend, SourceHash, SourceHashes, SourceKey, SourceType,
+        SpecTree,
+    };
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn key(name: &str) -> SourceKey {
+        SourceKey::from(name.to_string())
+    }
+
+    fn revision(tree: &SpecTree, key: &str) -> Option<String> {
+        match &tree.sources[&SourceKey::from(key.to_string())].typ {
+            SourceType::Git { revision, .. } => revision.clone(),
+            _ => None,
+        }
+    }
+
+    fn locked(url: &str, revision: Option<&str>, commit: &str) -> LockedSource {
+        LockedSource {
+            url: url.to_string(),
+            revision: revision.map(str::to_string),
+            commit: commit.to_string(),
+            tree: "0".repeat(40),
+        }
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut tree = load_test_tree(
+            "a: {type: {source: git, url: \"https://example.com/a.git\"}}\n\
+             b: {type: {source: git, url: \"https://example.com/b.git\", revision: v1}}\n\
+             c: {type: {source: git, url: \"https://example.com/c.git\"}}\n\
+             d: {type: {source: git, path: d}}\n",
+        );
+        let mut lock_file = LockFile::default();
+        lock_file
+            .sources
+            .insert("a".into(), locked("https://example.com/a.git", None, "aaaa"));
+        lock_file
+            .sources
+            .insert("b".into(), locked("https://example.com/b.git", Some("v1"), "bbbb"));
+        // Locked for another URL, so stale
+        lock_file
+            .sources
+            .insert("c".into(), locked("https://example.com/old-c.git", None, "cccc"));
+        lock_file.sources.insert("d".into(), locked("d", None, "dddd"));
+
+        lock_file.pin(&mut tree);
+        assert_eq!(revision(&tree, "a").as_deref(), Some("aaaa"));
+        assert_eq!(revision(&tree, "b").as_deref(), Some("bbbb"));
+        assert_eq!(revision(&tree, "c"), None);
+        assert_eq!(revision(&tree, "d"), None);
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        let dir = TempDir::new().unwrap();
+        let path = LockFile::path_for(&dir.path().join("tree.yaml"));
+        assert_eq!(path, dir.path().join("spectree.lock"));
+        assert_eq!(LockFile::load(&path).unwrap(), LockFile::default());
+
+        let mut lock_file = LockFile::default();
+        lock_file
+            .sources
+            .insert("a".into(), locked("https://example.com/a.git", Some("main"), "aaaa"));
+        lock_file.save(&path).unwrap();
+        assert_eq!(LockFile::load(&path).unwrap(), lock_file);
+
+        fs::write(&path, "sources: {a: {url: x}}\n").unwrap();
+        assert!(LockFile::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = TempDir::new().unwrap();
+        let repo = dir.path().join("repo");
+        let first = commit(&repo, "1");
+        git(&repo, "tag v1");
+        let second = commit(&repo, "2");
+        let url = format!("file://{}", repo.display());
+
+        let tree = load_test_tree(&format!(
+            "a: {{type: {{source: git, url: \"{url}\", revision: v1}}}}\n\
+             b: {{type: {{source: git, url: \"{url}\"}}}}\n"
+        ));
+        let source_hashes = SourceHashes {
+            hashes: [key("a"), key("b")]
+                .into_iter()
+                .map(|key| (key, SourceHash::from("1".repeat(40))))
+                .collect(),
+        };
+
+        let mut lock_file = LockFile::default();
+        // Locked before the revision was given
+        lock_file.sources.insert("a".into(), locked(&url, None, &second));
+        // Locked for another URL
+        lock_file
+            .sources
+            .insert("b".into(), locked("https://example.com/b.git", None, &first));
+        // No longer in the spec tree
+        lock_file
+            .sources
+            .insert("c".into(), locked("https://example.com/c.git", None, &first));
+
+        assert!(lock_file.record(&tree, &source_hashes, dir.path()).unwrap());
+        assert_eq!(lock_file.sources.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(lock_file.sources["a"].commit, first);
+        assert_eq!(lock_file.sources["a"].revision.as_deref(), Some("v1"));
+        assert_eq!(lock_file.sources["b"].commit, second);
+        assert_eq!(lock_file.sources["b"].url, url);
+        assert_eq!(lock_file.sources["b"].tree, "1".repeat(40));
+
+        // Sources built at their locked commits keep them, even after their repos moved on
+        commit(&repo, "3");
+        let mut pinned = tree.clone();
+        lock_file.pin(&mut pinned);
+        assert!(!lock_file.record(&pinned, &source_hashes, dir.path()).unwrap());
+        assert_eq!(lock_file.sources["b"].commit, second);
+    }
+
+    #[test]
+    fn test_update_lock_file() {
+        let dir = TempDir::new().unwrap();
+        let workspace = dir.path().join("workspace");
+        let spec_file = dir.path().join("tree.yaml");
+        let write_spec = |keys: &[&str]| {
+            let content: String = keys
+                .iter()
+                .map(|key| {
+                    format!(
+                        "{}: {{type: {{source: git, url: \"file://{}\"}}}}\n",
+                        key,
+                        dir.path().join(key).display()
+                    )
+                })
+                .collect();
+            fs::write(&spec_file, content).unwrap();
+        };
+        let commits = |lock_file: &LockFile| -> Vec<(String, String)> {
+            lock_file
+                .sources
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.commit.clone()))
+                .collect()
+        };
+
+        let a1 = commit(&dir.path().join("a"), "1");
+        let b1 = commit(&dir.path().join("b"), "1");
+        write_spec(&["a", "b"]);
+        let lock_file = update_lock_file(&spec_file, &workspace, &[], &[]).unwrap();
+        assert_eq!(
+            commits(&lock_file),
+            [("a".to_string(), a1.clone()), ("b".to_string(), b1.clone())]
+        );
+        assert_eq!(LockFile::load(&LockFile::path_for(&spec_file)).unwrap(), lock_file);
+
+        // Only the given sources are updated
+        let a2 = commit(&dir.path().join("a"), "2");
+        commit(&dir.path().join("b"), "2");
+        let lock_file = update_lock_file(&spec_file, &workspace, &[key("a")], &[]).unwrap();
+        assert_eq!(
+            commits(&lock_file),
+            [("a".to_string(), a2.clone()), ("b".to_string(), b1)]
+        );
+
+        // Removed sources are dropped
+        write_spec(&["a"]);
+        let lock_file = update_lock_file(&spec_file, &workspace, &[key("a")], &[]).unwrap();
+        assert_eq!(commits(&lock_file), [("a".to_string(), a2)]);
+    }
+
+    #[test]
+    fn test_plan_keeps_lock_file() {
+        let dir = TempDir::new().unwrap();
+        let spec_file = dir.path().join("tree.yaml");
+        let lock_path = LockFile::path_for(&spec_file);
+        let write_spec = |keys: &[&str]| {
+            let content: String = keys
+                .iter()
+                .map(|key| {
+                    format!(
+                        "{}: {{type: {{source: git, url: \"file://{}\"}}}}\n",
+                        key,
+                        dir.path().join(key).display()
+                    )
+                })
+                .collect();
+            fs::write(&spec_file, content).unwrap();
+        };
+        let options = |keys: &[&str]| {
+            let mut options = BuildOptions::new(&spec_file, dir.path().join("workspace"));
+            options.backend = BuilderBackend::Null;
+            options.root_sources = keys.iter().map(|name| key(name)).collect();
+            options
+        };
+
+        let a1 = commit(&dir.path().join("a"), "1");
+        commit(&dir.path().join("b"), "1");
+        write_spec(&["a"]);
+        Builder::new(options(&["a"])).plan().unwrap();
+        assert!(!lock_path.exists());
+
+        // Builds lock the sources
+        prepare_build_plans(&options(&["a"]), true).unwrap();
+        let content = fs::read_to_string(&lock_path).unwrap();
+        assert_eq!(LockFile::load(&lock_path).unwrap().sources["a"].commit, a1);
+
+        // Plans do not lock new sources
+        write_spec(&["a", "b"]);
+        Builder::new(options(&["a", "b"])).plan().unwrap();
+        assert_eq!(fs::read_to_string(&lock_path).unwrap(), content);
+    }
+}
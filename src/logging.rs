@@ -21,6 +21,7 @@ use tracing_subscriber::{
     fmt::{
         self,
         format::{Format, Json, JsonFields},
+        writer::BoxMakeWriter,
     },
     layer::SubscriberExt,
     reload, EnvFilter, Layer, Registry,
@@ -38,14 +39,16 @@ pub struct LoggingArgs {
     pub log_dir_level: Option<String>,
 }
 
-pub fn start(args: &LoggingArgs) -> anyhow::Result<()> {
+/// Start logging to stdout, or to stderr for commands whose output on stdout is meant to be parsed.
+pub fn start(args: &LoggingArgs, to_stderr: bool) -> anyhow::Result<()> {
     init_logging(
         from_str(&args.log_level, EnvFilter::new("info"))?,
         from_str(&args.log_level, EnvFilter::new("info"))?,
+        to_stderr,
     )?;
 
     if let Some(log_dir) = &args.log_dir {
-        update_logging_dir(&log_dir, from_str(&args.log_dir_level, EnvFilter::new("debug"))?);
+        update_logging_dir(log_dir, from_str(&args.log_dir_level, EnvFilter::new("debug"))?);
     }
 
     Ok(())
@@ -76,7 +79,7 @@ fn from_str(s: &Option<String>, def: EnvFilter) -> anyhow::Result<EnvFilter> {
     if let Some(l) = s {
         Ok(EnvFilter::new(l.as_str()))
     } else {
-        return Ok(def);
+        Ok(def)
     }
 }
 
@@ -141,17 +144,30 @@ impl tracing_subscriber::fmt::time::FormatTime for CustomTimeFormatter {
     }
 }
 
-fn init_logging(env_filter: EnvFilter, env_filter_2: EnvFilter) -> anyhow::Result<()> {
-    let stdout_layer = fmt::layer()
-        .with_writer(std::io::stdout)
+fn init_logging(env_filter: EnvFilter, env_filter_2: EnvFilter, to_stderr: bool) -> anyhow::Result<()> {
+    let writer = || {
+        if to_stderr {
+            BoxMakeWriter::new(std::io::stderr)
+        } else {
+            BoxMakeWriter::new(std::io::stdout)
+        }
+    };
+    let is_terminal = if to_stderr {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+
+    let output_layer = fmt::layer()
+        .with_writer(writer())
         .with_timer(CustomTimeFormatter) // Use the custom time formatter
         .with_target(false) // Hide the target, which is the module path
         .with_thread_ids(false) // Hide thread ids
         .with_thread_names(false) // Hide thread names
         .with_filter(env_filter);
 
-    let stdout_layer_no_terminal = fmt::layer()
-        .with_writer(std::io::stdout)
+    let output_layer_no_terminal = fmt::layer()
+        .with_writer(writer())
         .with_timer(CustomTimeFormatter) // Use the custom time formatter
         .with_target(false) // Hide the target, which is the module path
         .with_thread_ids(false) // Hide thread ids
@@ -167,11 +183,11 @@ fn init_logging(env_filter: EnvFilter, env_filter_2: EnvFilter) -> anyhow::Resul
         *handle = Some((None, reload_handle));
     }
 
-    if std::io::stdout().is_terminal() {
-        tracing::subscriber::set_global_default(Registry::default().with(reloading_layer).with(stdout_layer))
+    if is_terminal {
+        tracing::subscriber::set_global_default(Registry::default().with(reloading_layer).with(output_layer))
     } else {
         tracing::subscriber::set_global_default(
-            Registry::default().with(reloading_layer).with(stdout_layer_no_terminal),
+            Registry::default().with(reloading_layer).with(output_layer_no_terminal),
         )
     }
     .expect("Could not set global default subscriber");
@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use tracing::debug;
+
+use crate::shell::{Shell, ShellEscaped};
+use crate::{infer, Source, SourceKey, SourceType};
+
+/// Query format for the name, epoch, version and release of a package, one per line.
+const NVR_QUERY_FORMAT: &str = "%{NAME}\\n%{EPOCH}\\n%{VERSION}\\n%{RELEASE}\\n";
+
+/// The name, epoch, version and release of the package built from a source.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Nvr {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<u32>,
+    pub version: String,
+    pub release: String,
+}
+
+impl std::fmt::Display for Nvr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.epoch {
+            Some(epoch) => write!(f, "{}-{}:{}-{}", self.name, epoch, self.version, self.release),
+            None => write!(f, "{}-{}-{}", self.name, self.version, self.release),
+        }
+    }
+}
+
+impl Nvr {
+    /// The NVR without the epoch, as used in the file names of RPMs.
+    pub fn file_name(&self) -> String {
+        format!("{}-{}-{}", self.name, self.version, self.release)
+    }
+
+    /// The NEVRA of the source RPM, with the epoch always present, e.g. `hello-0:2.12-1.fc42.src`.
+    pub fn srpm_nevra(&self) -> String {
+        format!(
+            "{}-{}:{}-{}.src",
+            self.name,
+            self.epoch.unwrap_or(0),
+            self.version,
+            self.release
+        )
+    }
+
+    /// Parse the output of an rpm query with [`NVR_QUERY_FORMAT`].
+    fn parse_query(output: &str) -> Result<Self> {
+        let fields: Vec<&str> = output.lines().map(str::trim).collect();
+        let [name, epoch, version, release] = fields[..] else {
+            anyhow::bail!("Unexpected output of rpm query: {:?}", output);
+        };
+        let epoch = match epoch {
+            "(none)" => None,
+            epoch => Some(epoch.parse().with_context(|| format!("Invalid epoch: {}", epoch))?),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            epoch,
+            version: version.to_string(),
+            release: release.to_string(),
+        })
+    }
+}
+
+/// Read the NVR from the header of a source RPM.
+pub(crate) fn query_srpm(srpm_path: &Path) -> Result<Nvr> {
+    let shell = Shell::new(srpm_path.parent().unwrap_or_else(|| Path::new(".")));
+    let output = shell
+        .run_with_output_sync(&format!(
+            "rpm -qp --nosignature --qf {} {}",
+            NVR_QUERY_FORMAT.shell_escaped(),
+            srpm_path.shell_escaped()
+        ))
+        .with_context(|| format!("Failed to query NVR of SRPM: {}", srpm_path.display()))?;
+    Nvr::parse_query(&output)
+}
+
+/// Evaluate the NVR of a source that is not built yet, from its spec file with `rpmspec`, or from the header of the
+/// source RPM for SRPM sources. Macros like the dist tag are the ones of the host rather than of the build.
+pub(crate) fn query_source(key: &SourceKey, source: &Source, workspace: &Path) -> Result<Nvr> {
+    match &source.typ {
+        SourceType::Git { subpath, .. } => {
+            let spec_file = infer::git_spec_file(key, source, workspace, subpath.as_deref())?;
+            let output = infer::rpmspec_query(
+                &spec_file,
+                source,
+                &format!("--srpm --qf {}", NVR_QUERY_FORMAT.shell_escaped()),
+            )?;
+            Nvr::parse_query(&output)
+        }
+        SourceType::Srpm { .. } => query_srpm(&source.get_repo_path(key, workspace, false)?),
+    }
+}
+
+#[derive(Deserialize)]
+struct BuildInfoNvr {
+    nvr: Option<Nvr>,
+}
+
+/// The NVR of a finished local build, from its `build_info.yaml`, or else from the header of its source RPM for
+/// builds that predate recording it.
+pub(crate) fn read_build_nvr(build_dir: &Path) -> Option<Nvr> {
+    let recorded = fs::read_to_string(build_dir.join("build").join("build_info.yaml"))
+        .ok()
+        .and_then(|content| serde_yaml::from_str::<BuildInfoNvr>(&content).ok())
+        .and_then(|build_info| build_info.nvr);
+    if recorded.is_some() {
+        return recorded;
+    }
+
+    let srpm_path = fs::read_dir(build_dir.join("srpm"))
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find(|path| path.to_string_lossy().ends_with(".src.rpm"))?;
+    match query_srpm(&srpm_path) {
+        Ok(nvr) => Some(nvr),
+        Err(err) => {
+            debug!("{:#}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_build_nvr, Nvr};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_query() {
+        let nvr = Nvr::parse_query("hello\n(none)\n2.12\n1.fc42\n").unwrap();
+        assert_eq!(nvr.to_string(), "hello-2.12-1.fc42");
+        assert_eq!(nvr.srpm_nevra(), "hello-0:2.12-1.fc42.src");
+
+        let nvr = Nvr::parse_query("glibc\n2\n2.41\n3.fc42\n").unwrap();
+        assert_eq!(nvr.epoch, Some(2));
+        assert_eq!(nvr.to_string(), "glibc-2:2.41-3.fc42");
+        assert_eq!(nvr.file_name(), "glibc-2.41-3.fc42");
+
+        assert!(Nvr::parse_query("hello\n(none)\n2.12\n").is_err());
+        assert!(Nvr::parse_query("hello\nx\n2.12\n1\n").is_err());
+    }
+
+    #[test]
+    fn test_read_build_nvr() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("build")).unwrap();
+        assert_eq!(read_build_nvr(dir.path()), None);
+
+        fs::write(
+            dir.path().join("build/build_info.yaml"),
+            "source: {type: {source: git, path: x}}\ngit_revision: null\nnvr: {name: a, version: '1', release: '2'}\n",
+        )
+        .unwrap();
+        assert_eq!(read_build_nvr(dir.path()).unwrap().to_string(), "a-1-2");
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+use tracing::info;
+
+use crate::shell::ShellEscaped;
+
+/// RPM build settings of a source: bconds to enable or disable, and macros to define.
+///
+/// These come from the `with:`, `without:` and `defines:` fields of a source, and from its legacy `params:`,
+/// which must consist of `--with`, `--without` and `--define`/`-D` options only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildParams {
+    pub with: Vec<String>,
+    pub without: Vec<String>,
+    pub defines: BTreeMap<String, String>,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl BuildParams {
+    pub fn new(
+        with: &[String], without: &[String], defines: &BTreeMap<String, String>, legacy_params: &[String],
+    ) -> Result<Self> {
+        let mut params = Self::parse_legacy(legacy_params)?;
+        for feature in with {
+            params.add_with(feature)?;
+        }
+        for feature in without {
+            params.add_without(feature)?;
+        }
+        for (name, value) in defines {
+            params.add_define(name, value)?;
+        }
+        Ok(params)
+    }
+
+    /// Parse `params:` as given to rpmbuild, e.g. `["--with", "foo", "--define", "dist .el9"]`.
+    pub fn parse_legacy(legacy_params: &[String]) -> Result<Self> {
+        let mut params = Self::default();
+        let mut iter = legacy_params.iter();
+
+        while let Some(param) = iter.next() {
+            let (option, inline_value) = match param.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => (option, Some(value.to_string())),
+                _ => (param.as_str(), None),
+            };
+            if !matches!(option, "--with" | "--without" | "--define" | "-D") {
+                anyhow::bail!(
+                    "Unsupported build parameter '{}', only --with, --without and --define are supported",
+                    param
+                );
+            }
+
+            let value = match inline_value {
+                Some(value) => value,
+                None => iter
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("Build parameter '{}' is missing its value", param))?,
+            };
+
+            match option {
+                "--with" => params.add_with(&value)?,
+                "--without" => params.add_without(&value)?,
+                _ => {
+                    let (name, value) = value.trim().split_once([' ', '\t']).unwrap_or((value.trim(), ""));
+                    params.add_define(name, value.trim())?;
+                }
+            }
+        }
+
+        Ok(params)
+    }
+
+    fn add_with(&mut self, feature: &str) -> Result<()> {
+        if !is_valid_name(feature) {
+            anyhow::bail!("Invalid bcond name '{}'", feature);
+        }
+        if self.without.iter().any(|f| f == feature) {
+            anyhow::bail!("Feature '{}' is both enabled and disabled", feature);
+        }
+        if !self.with.iter().any(|f| f == feature) {
+            self.with.push(feature.to_string());
+        }
+        Ok(())
+    }
+
+    fn add_without(&mut self, feature: &str) -> Result<()> {
+        if !is_valid_name(feature) {
+            anyhow::bail!("Invalid bcond name '{}'", feature);
+        }
+        if self.with.iter().any(|f| f == feature) {
+            anyhow::bail!("Feature '{}' is both enabled and disabled", feature);
+        }
+        if !self.without.iter().any(|f| f == feature) {
+            self.without.push(feature.to_string());
+        }
+        Ok(())
+    }
+
+    fn add_define(&mut self, name: &str, value: &str) -> Result<()> {
+        if !is_valid_name(name) {
+            anyhow::bail!("Invalid macro name '{}'", name);
+        }
+        if let Some(existing) = self.defines.get(name) {
+            if existing != value {
+                anyhow::bail!("Macro '{}' is defined twice, as '{}' and as '{}'", name, existing, value);
+            }
+        }
+        self.defines.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.with.is_empty() && self.without.is_empty() && self.defines.is_empty()
+    }
+
+    /// The parameters as rpmbuild arguments.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for feature in &self.with {
+            args.push("--with".to_string());
+            args.push(feature.clone());
+        }
+        for feature in &self.without {
+            args.push("--without".to_string());
+            args.push(feature.clone());
+        }
+        for (name, value) in &self.defines {
+            args.push("--define".to_string());
+            args.push(format!("{} {}", name, value));
+        }
+        args
+    }
+
+    /// The parameters as shell-escaped rpmbuild arguments, preceded by `prefix` unless there are none.
+    pub fn to_command_args(&self, prefix: &str) -> String {
+        let args = self.to_args();
+        if args.is_empty() {
+            return String::new();
+        }
+        let escaped: Vec<String> = args.iter().map(|arg| arg.shell_escaped().into_owned()).collect();
+        format!("{}{}", prefix, escaped.join(" "))
+    }
+}
+
+/// Bake build parameters into a spec file, for builders that cannot be given rpmbuild options.
+pub fn modify_spec_for_params(spec_content: &str, params: &BuildParams) -> Result<String> {
+    let lines: Vec<&str> = spec_content.lines().collect();
+    let mut modified_lines = Vec::new();
+
+    let with_features: HashSet<&str> = params.with.iter().map(String::as_str).collect();
+    let without_features: HashSet<&str> = params.without.iter().map(String::as_str).collect();
+    let defines = &params.defines;
+    let mut replaced_defines = HashSet::new();
+
+    // Compile regex patterns for bcond directives and %global definitions
+    let bcond_with_regex =
+        Regex::new(r"^(%bcond_with)[\t ]+([^\t ]+)[\t ]*(.*)").context("Failed to compile bcond_with regex")?;
+    let bcond_without_regex =
+        Regex::new(r"^(%bcond_without)[\t ]+([^\t ]+)[\t ]*(.*)").context("Failed to compile bcond_without regex")?;
+    let global_regex = Regex::new(r"^(%global)[\t ]+([^\t ]+)[\t ]+(.*)").context("Failed to compile global regex")?;
+
+    // Process each line
+    for line in lines {
+        let mut modified_line = line.to_string();
+
+        // Check for %bcond_with patterns
+        if let Some(captures) = bcond_with_regex.captures(line) {
+            let feature = captures.get(2).unwrap().as_str();
+            let trailing = captures.get(3).map(|m| m.as_str()).unwrap_or("");
+
+            if with_features.contains(feature) {
+                info!("🔄 Changing %bcond_with {} to %bcond_without {}", feature, feature);
+                // Reconstruct the line with %bcond_without
+                if trailing.is_empty() {
+                    modified_line = format!("%bcond_without {}", feature);
+                } else {
+                    modified_line = format!("%bcond_without {} {}", feature, trailing);
+                }
+            }
+        }
+        // Check for %bcond_without patterns
+        else if let Some(captures) = bcond_without_regex.captures(line) {
+            let feature = captures.get(2).unwrap().as_str();
+            let trailing = captures.get(3).map(|m| m.as_str()).unwrap_or("");
+
+            if without_features.contains(feature) {
+                info!("🔄 Changing %bcond_without {} to %bcond_with {}", feature, feature);
+                // Reconstruct the line with %bcond_with
+                if trailing.is_empty() {
+                    modified_line = format!("%bcond_with {}", feature);
+                } else {
+                    modified_line = format!("%bcond_with {} {}", feature, trailing);
+                }
+            }
+        }
+        // Check for %global patterns
+        else if let Some(captures) = global_regex.captures(line) {
+            let var_name = captures.get(2).unwrap().as_str();
+
+            if let Some(new_value) = defines.get(var_name) {
+                info!("🔄 Replacing %global {} with new value: {}", var_name, new_value);
+                modified_line = format!("%global {} {}", var_name, new_value);
+                replaced_defines.insert(var_name.to_string());
+            }
+        }
+
+        modified_lines.push(modified_line);
+    }
+
+    // Macros that the spec does not define itself are defined at its top, as `--define` would
+    let mut added_lines = Vec::new();
+    for (name, value) in defines {
+        if !replaced_defines.contains(name) {
+            info!("➕ Adding %global {} {}", name, value);
+            added_lines.push(format!("%global {} {}", name, value));
+        }
+    }
+    added_lines.append(&mut modified_lines);
+
+    Ok(added_lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{modify_spec_for_params, BuildParams};
+    use std::collections::BTreeMap;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_legacy() {
+        let params =
+            BuildParams::parse_legacy(&strings(&["--with", "docs", "--without=tests", "-D", "dist .el9"])).unwrap();
+        assert_eq!(params.with, vec!["docs"]);
+        assert_eq!(params.without, vec!["tests"]);
+        assert_eq!(params.defines["dist"], ".el9");
+
+        let params = BuildParams::parse_legacy(&strings(&["--define", "bootstrap"])).unwrap();
+        assert_eq!(params.defines["bootstrap"], "");
+    }
+
+    #[test]
+    fn test_parse_legacy_errors() {
+        assert!(BuildParams::parse_legacy(&strings(&["--nocheck"])).is_err());
+        assert!(BuildParams::parse_legacy(&strings(&["--with"])).is_err());
+        assert!(BuildParams::parse_legacy(&strings(&["--with", "docs", "--without", "docs"])).is_err());
+        assert!(BuildParams::parse_legacy(&strings(&["--define", "1abc x"])).is_err());
+    }
+
+    #[test]
+    fn test_typed_fields() {
+        let defines = BTreeMap::from([("dist".to_string(), ".el9".to_string())]);
+        let params = BuildParams::new(&strings(&["docs"]), &[], &defines, &strings(&["--without", "tests"])).unwrap();
+        assert_eq!(params.with, vec!["docs"]);
+        assert_eq!(params.without, vec!["tests"]);
+        assert_eq!(params.defines["dist"], ".el9");
+
+        let conflicting = strings(&["--define", "dist .el10"]);
+        assert!(BuildParams::new(&[], &[], &defines, &conflicting).is_err());
+    }
+
+    #[test]
+    fn test_to_command_args() {
+        let defines = BTreeMap::from([("vendor".to_string(), "Acme's builds".to_string())]);
+        let params = BuildParams::new(&strings(&["docs"]), &[], &defines, &[]).unwrap();
+        assert_eq!(
+            params.to_command_args(" -- "),
+            " -- --with docs --define 'vendor Acme'\\''s builds'"
+        );
+        assert_eq!(BuildParams::default().to_command_args(" -- "), "");
+    }
+
+    #[test]
+    fn test_modify_spec_for_params() {
+        let spec = "%global commit abc\n%bcond_with docs\n%bcond_without tests\nName: foo";
+        let defines = BTreeMap::from([
+            ("commit".to_string(), "def".to_string()),
+            ("vendor".to_string(), "Acme".to_string()),
+        ]);
+        let params = BuildParams::new(&strings(&["docs"]), &strings(&["tests"]), &defines, &[]).unwrap();
+        assert_eq!(
+            modify_spec_for_params(spec, &params).unwrap(),
+            "%global vendor Acme\n%global commit def\n%bcond_without docs\n%bcond_with tests\nName: foo"
+        );
+    }
+}
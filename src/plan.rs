@@ -0,0 +1,453 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::PathBuf;
+use tracing::debug;
+
+use crate::{
+    cache, nvr, BuildKey, BuildOptions, BuildPlan, BuilderBackend, CoprBuildStatus, CoprStateFile, Dependency, Error,
+    Nvr, RemoteCache, SourceKey,
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum PlanFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildStatus {
+    /// A finished build for this build key already exists
+    Cached,
+    /// The source will be built
+    Stale,
+    /// The last attempt to build this build key did not complete
+    Failed,
+    /// The source is left out of the build by `--only` or `--exclude`
+    Excluded,
+}
+
+impl BuildStatus {
+    pub fn will_build(&self) -> bool {
+        matches!(self, BuildStatus::Stale | BuildStatus::Failed)
+    }
+}
+
+impl std::fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildStatus::Cached => write!(f, "cached"),
+            BuildStatus::Stale => write!(f, "will build"),
+            BuildStatus::Failed => write!(f, "failed, will rebuild"),
+            BuildStatus::Excluded => write!(f, "excluded"),
+        }
+    }
+}
+
+/// Determine whether a build key was already built, either in the workspace, in the artifact cache, in the remote
+/// cache or in Copr.
+pub(crate) fn get_build_status(
+    args: &BuildOptions, backend: &BuilderBackend, build_key: &BuildKey,
+) -> Result<BuildStatus> {
+    if backend.is_remote() {
+        if let Some(copr_state_file) = &args.copr_state_file {
+            let state = CoprStateFile::load_or_create(copr_state_file)?;
+            if let Some(build_state) = state.get_build_state(build_key) {
+                match build_state.status {
+                    CoprBuildStatus::Completed => return Ok(BuildStatus::Cached),
+                    CoprBuildStatus::Failed => return Ok(BuildStatus::Failed),
+                    CoprBuildStatus::Submitted | CoprBuildStatus::InProgress => {}
+                }
+            }
+        }
+        return Ok(BuildStatus::Stale);
+    }
+
+    let builds_dir = args.workspace.join("builds");
+    let in_cache = |cache_dir: &PathBuf| cache::lookup(cache_dir, build_key).is_some();
+    let in_remote_cache = |remote: &RemoteCache| match remote.contains(build_key) {
+        Ok(contains) => contains,
+        Err(e) => {
+            debug!("Failed to look up {} in remote cache: {:#}", build_key, e);
+            false
+        }
+    };
+    if builds_dir.join(build_key.build_dir_name()).join("build").exists()
+        || args.cache_dir.as_ref().is_some_and(in_cache)
+        || args.remote_cache.as_ref().is_some_and(in_remote_cache)
+    {
+        Ok(BuildStatus::Cached)
+    } else if builds_dir.join(format!("{}.tmp", build_key.build_dir_name())).exists() {
+        // Leftover temporary build directory from a build that never got renamed into place
+        Ok(BuildStatus::Failed)
+    } else {
+        Ok(BuildStatus::Stale)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanReport {
+    pub roots: Vec<SourceKey>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+    pub needs_build: bool,
+    pub sources: Vec<PlanEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanEntry {
+    pub key: SourceKey,
+    /// Target OS of the build, when building for explicit targets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub backend: BuilderBackend,
+    pub build_key: String,
+    pub source_hash: String,
+    pub build_hash: String,
+    pub status: BuildStatus,
+    /// Name, epoch, version and release of the package, of the build if it exists, or else as evaluated from the spec
+    /// file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nvr: Option<Nvr>,
+    pub dependencies: Vec<PlanDependency>,
+    /// All sources whose RPMs are made available to this build
+    pub build_repo: Vec<SourceKey>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanDependency {
+    pub key: SourceKey,
+    pub direct_only: bool,
+}
+
+impl PlanReport {
+    pub(crate) fn new(args: &BuildOptions, plans: &[BuildPlan]) -> Result<Self> {
+        let mut sources = Vec::new();
+
+        for plan in plans {
+            Self::add_plan_entries(args, plan, &mut sources)?;
+        }
+
+        let targets = plans.iter().filter_map(|plan| plan.target.clone()).collect();
+        let mut roots: Vec<SourceKey> = Vec::new();
+        for root in plans.iter().flat_map(|plan| &plan.root_sources) {
+            if !roots.contains(root) {
+                roots.push(root.clone());
+            }
+        }
+        let needs_build = sources.iter().any(|entry| entry.status.will_build());
+
+        Ok(Self { roots, targets, needs_build, sources })
+    }
+
+    fn add_plan_entries(args: &BuildOptions, plan: &BuildPlan, sources: &mut Vec<PlanEntry>) -> Result<()> {
+        for key in &plan.all_sources {
+            let source = plan.spec_tree.sources.get(key).unwrap();
+            let build_hash = plan.build_hashes.get(key).unwrap();
+            let build_key = BuildKey::new(key.clone(), build_hash.clone());
+            let backend = args.backend_for(source);
+            let status = if plan.skipped.contains(key) {
+                BuildStatus::Excluded
+            } else if plan.forced.contains(key) {
+                BuildStatus::Stale
+            } else {
+                get_build_status(args, backend, &build_key)?
+            };
+            let nvr = match status {
+                BuildStatus::Cached if !backend.is_remote() => {
+                    let build_dir = args.workspace.join("builds").join(build_key.build_dir_name());
+                    match &args.cache_dir {
+                        Some(cache_dir) if !build_dir.exists() => {
+                            cache::lookup(cache_dir, &build_key).and_then(|entry| nvr::read_build_nvr(&entry))
+                        }
+                        _ => nvr::read_build_nvr(&build_dir),
+                    }
+                }
+                // Not built yet, so the NVR is what the spec file evaluates to
+                _ => match nvr::query_source(key, source, &args.workspace) {
+                    Ok(nvr) => Some(nvr),
+                    Err(e) => {
+                        debug!("Failed to read NVR of {}: {:#}", key, e);
+                        None
+                    }
+                },
+            };
+
+            let dependencies = source
+                .dependencies
+                .iter()
+                .map(|dep| {
+                    let dependency = Dependency::parse(dep.as_ref());
+                    PlanDependency {
+                        key: SourceKey::from(dependency.key().to_string()),
+                        direct_only: dependency.is_direct_only(),
+                    }
+                })
+                .collect();
+
+            let mut build_repo: Vec<SourceKey> = plan
+                .all_dependencies_map
+                .get(key)
+                .map(|deps| deps.keys().cloned().collect())
+                .unwrap_or_default();
+            build_repo.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+
+            sources.push(PlanEntry {
+                key: key.clone(),
+                target: plan.target.clone(),
+                backend: backend.clone(),
+                build_key: build_key.to_string(),
+                source_hash: plan.source_hashes.hashes.get(key).map(|h| h.to_string()).unwrap_or_default(),
+                build_hash: build_hash.to_string(),
+                status,
+                nvr,
+                dependencies,
+                build_repo,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn entry(&self, target: Option<&str>, key: &SourceKey) -> Option<&PlanEntry> {
+        self.sources
+            .iter()
+            .find(|entry| &entry.key == key && entry.target.as_deref() == target)
+    }
+
+    pub fn render(&self, format: PlanFormat) -> Result<String, Error> {
+        match format {
+            PlanFormat::Json => Ok(serde_json::to_string_pretty(self)? + "\n"),
+            PlanFormat::Text => Ok(self.render_text()),
+        }
+    }
+
+    fn render_text(&self) -> String {
+        let mut out = String::new();
+
+        if self.targets.is_empty() {
+            let mut printed = HashSet::new();
+            for root in &self.roots {
+                self.render_text_node(&mut out, None, root, false, "", "", &mut printed);
+            }
+        } else {
+            for (index, target) in self.targets.iter().enumerate() {
+                let _ = writeln!(out, "{}{}:", if index > 0 { "\n" } else { "" }, target);
+                let mut printed = HashSet::new();
+                for root in &self.roots {
+                    self.render_text_node(&mut out, Some(target), root, false, "", "", &mut printed);
+                }
+            }
+        }
+
+        let count = |wanted: fn(&BuildStatus) -> bool| self.sources.iter().filter(|e| wanted(&e.status)).count();
+        let to_build = count(BuildStatus::will_build);
+        let excluded = count(|status| *status == BuildStatus::Excluded);
+        let _ = write!(
+            out,
+            "\n{} sources: {} cached, {} to build",
+            self.sources.len(),
+            self.sources.len() - to_build - excluded,
+            to_build
+        );
+        if excluded > 0 {
+            let _ = write!(out, ", {} excluded", excluded);
+        }
+        out.push('\n');
+
+        out
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_text_node(
+        &self, out: &mut String, target: Option<&str>, key: &SourceKey, direct_only: bool, prefix: &str,
+        child_prefix: &str, printed: &mut HashSet<SourceKey>,
+    ) {
+        let Some(entry) = self.entry(target, key) else {
+            return;
+        };
+
+        let marker = if direct_only { "~" } else { "" };
+        let seen = !printed.insert(key.clone());
+        let _ = writeln!(
+            out,
+            "{}{}{}{} [{}]{}",
+            prefix,
+            marker,
+            entry.build_key,
+            entry.nvr.as_ref().map(|nvr| format!(" ({})", nvr)).unwrap_or_default(),
+            entry.status,
+            if seen && !entry.dependencies.is_empty() {
+                " (see above)"
+            } else {
+                ""
+            }
+        );
+
+        if seen {
+            return;
+        }
+
+        let count = entry.dependencies.len();
+        for (index, dep) in entry.dependencies.iter().enumerate() {
+            let last = index + 1 == count;
+            let (branch, continuation) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+            self.render_text_node(
+                out,
+                target,
+                &dep.key,
+                dep.direct_only,
+                &format!("{}{}", child_prefix, branch),
+                &format!("{}{}", child_prefix, continuation),
+                printed,
+            );
+        }
+    }
+}
+
+/// A plan entry of a source built with mock at the build hash `0123456789abcdef`, with `~` marking the direct-only
+/// dependencies.
+#[cfg(test)]
+pub(crate) fn test_entry(name: &str, status: BuildStatus, dependencies: &[&str]) -> PlanEntry {
+    let build_key = BuildKey::new(
+        SourceKey::from(name.to_string()),
+        crate::BuildHash::from("0123456789abcdef".to_string()),
+    );
+    PlanEntry {
+        key: build_key.source_key.clone(),
+        target: None,
+        backend: BuilderBackend::Mock,
+        build_key: build_key.to_string(),
+        source_hash: "fedcba".to_string(),
+        build_hash: build_key.build_hash.to_string(),
+        status,
+        nvr: None,
+        dependencies: dependencies
+            .iter()
+            .map(|dep| PlanDependency {
+                key: SourceKey::from(dep.trim_start_matches('~').to_string()),
+                direct_only: dep.starts_with('~'),
+            })
+            .collect(),
+        build_repo: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_build_status, test_entry as entry, BuildStatus, PlanEntry, PlanFormat, PlanReport};
+    use crate::{BuildHash, BuildKey, BuildOptions, BuilderBackend, Nvr, SourceKey};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn key(name: &str) -> SourceKey {
+        SourceKey::from(name.to_string())
+    }
+
+    fn report() -> PlanReport {
+        let mut hello = entry("hello", BuildStatus::Cached, &[]);
+        hello.nvr = Some(Nvr {
+            name: "hello".to_string(),
+            epoch: None,
+            version: "2.12".to_string(),
+            release: "1.fc42".to_string(),
+        });
+        PlanReport {
+            roots: vec![key("app")],
+            targets: Vec::new(),
+            needs_build: true,
+            sources: vec![
+                hello,
+                entry("lib", BuildStatus::Failed, &["hello"]),
+                entry("tool", BuildStatus::Excluded, &["~hello"]),
+                entry("app", BuildStatus::Stale, &["lib", "tool"]),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_render_text() {
+        assert_eq!(
+            report().render(PlanFormat::Text).unwrap(),
+            "\
+app-0123456789abcdef [will build]
+├── lib-0123456789abcdef [failed, will rebuild]
+│   └── hello-0123456789abcdef (hello-2.12-1.fc42) [cached]
+└── tool-0123456789abcdef [excluded]
+    └── ~hello-0123456789abcdef (hello-2.12-1.fc42) [cached]
+
+4 sources: 1 cached, 2 to build, 1 excluded
+"
+        );
+    }
+
+    #[test]
+    fn test_render_text_with_targets() {
+        let targets = ["epel9", "epel10"];
+        let report = PlanReport {
+            roots: vec![key("hello")],
+            targets: targets.iter().map(|target| target.to_string()).collect(),
+            needs_build: true,
+            sources: targets
+                .iter()
+                .zip([BuildStatus::Cached, BuildStatus::Stale])
+                .map(|(target, status)| PlanEntry { target: Some(target.to_string()), ..entry("hello", status, &[]) })
+                .collect(),
+        };
+
+        assert_eq!(
+            report.render(PlanFormat::Text).unwrap(),
+            "epel9:\nhello-0123456789abcdef [cached]\n\nepel10:\nhello-0123456789abcdef [will build]\n\n2 sources: 1 cached, 1 to build\n"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let json: serde_json::Value = serde_json::from_str(&report().render(PlanFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["roots"], serde_json::json!(["app"]));
+        assert_eq!(json["needs_build"], true);
+        assert!(json.get("targets").is_none());
+
+        let sources = json["sources"].as_array().unwrap();
+        assert_eq!(sources.len(), 4);
+        assert_eq!(sources[0]["key"], "hello");
+        assert_eq!(sources[0]["status"], "cached");
+        assert_eq!(
+            sources[0]["nvr"],
+            serde_json::json!({"name": "hello", "version": "2.12", "release": "1.fc42"})
+        );
+        assert_eq!(sources[1]["status"], "failed");
+        assert_eq!(sources[2]["status"], "excluded");
+        assert_eq!(
+            sources[2]["dependencies"],
+            serde_json::json!([{"key": "hello", "direct_only": true}])
+        );
+        assert_eq!(sources[3]["status"], "stale");
+        assert!(sources[3].get("nvr").is_none());
+        assert!(sources[3].get("target").is_none());
+    }
+
+    #[test]
+    fn test_get_build_status() {
+        let workspace = TempDir::new().unwrap();
+        let options = BuildOptions::new("tree.yaml", workspace.path());
+        let build_key = BuildKey::new(key("lib"), BuildHash::from("0123456789abcdef".to_string()));
+        let build_dir = workspace.path().join("builds").join(build_key.build_dir_name());
+        let status = || get_build_status(&options, &BuilderBackend::Mock, &build_key).unwrap();
+
+        assert_eq!(status(), BuildStatus::Stale);
+        // A build that never got renamed into place
+        fs::create_dir_all(build_dir.with_file_name(format!("{}.tmp", build_key.build_dir_name()))).unwrap();
+        assert_eq!(status(), BuildStatus::Failed);
+        fs::create_dir_all(build_dir.join("build")).unwrap();
+        assert_eq!(status(), BuildStatus::Cached);
+        // Remote builds are not looked up in the workspace
+        assert_eq!(
+            get_build_status(&options, &BuilderBackend::Copr, &build_key).unwrap(),
+            BuildStatus::Stale
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use tracing::info;
+
+use crate::{BuildKey, Source};
+
+/// The macro that the suffix is passed as. `%dist` starts with it on Fedora and EL 9 and later, so the suffix ends
+/// up in the release of every package whose `Release:` uses `%{?dist}`.
+const SUFFIX_MACRO: &str = "distprefix";
+
+/// A suffix for the release of the packages of a source, so that a rebuild with changed dependencies is not
+/// mistaken for the same package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseSuffix {
+    /// `.h<build hash>`: every build key has its own release, though not necessarily a greater one
+    Hash,
+    /// `.st<counter>`: a counter kept per source in the workspace, so that every build upgrades the previous ones
+    Counter,
+}
+
+/// The file that the counters of all sources are kept in, in the workspace.
+const COUNTERS_FILE: &str = "release_counters.yaml";
+
+/// Increment the build counter of a source, returning its new value.
+fn next_counter(workspace: &Path, build_key: &BuildKey) -> Result<u64> {
+    let path = workspace.join(COUNTERS_FILE);
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Failed to open release counters: {}", path.display()))?;
+    // Sources are built concurrently, possibly by several spectree processes sharing the workspace, and they all
+    // share the counters file. The lock is released when the file is closed.
+    file.lock()
+        .with_context(|| format!("Failed to lock release counters: {}", path.display()))?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .with_context(|| format!("Failed to read release counters: {}", path.display()))?;
+    let mut counters: BTreeMap<String, u64> = if content.trim().is_empty() {
+        BTreeMap::new()
+    } else {
+        serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse release counters: {}", path.display()))?
+    };
+
+    let counter = counters.entry(build_key.source_key.to_string()).or_insert(0);
+    *counter += 1;
+    let value = *counter;
+
+    let content = serde_yaml::to_string(&counters).context("Failed to serialize release counters to YAML")?;
+    file.set_len(0)
+        .and_then(|()| file.rewind())
+        .and_then(|()| file.write_all(content.as_bytes()))
+        .with_context(|| format!("Failed to write release counters: {}", path.display()))?;
+    Ok(value)
+}
+
+/// The source as it is built, with its release suffix, if it has one, defined as a macro along with its other
+/// defines. A counter is incremented for every call, so this is only done for builds that actually run.
+pub(crate) fn with_release_suffix(source: &Source, build_key: &BuildKey, workspace: &Path) -> Result<Source> {
+    let Some(mode) = source.release_suffix else {
+        return Ok(source.clone());
+    };
+    if source.defines.contains_key(SUFFIX_MACRO) {
+        anyhow::bail!(
+            "Source '{}' defines {} itself, which release_suffix would override",
+            build_key.source_key,
+            SUFFIX_MACRO
+        );
+    }
+
+    let suffix = match mode {
+        ReleaseSuffix::Hash => {
+            let hash = build_key.build_hash.as_ref();
+            format!(".h{}", hash.get(..10).unwrap_or(hash))
+        }
+        ReleaseSuffix::Counter => format!(".st{}", next_counter(workspace, build_key)?),
+    };
+    info!("Building {} with release suffix {}", build_key.source_key, suffix);
+
+    let mut source = source.clone();
+    source.defines.insert(SUFFIX_MACRO.to_string(), suffix);
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_counter, with_release_suffix};
+    use crate::spec_file::load_spec_tree;
+    use crate::{BuildHash, BuildKey, SourceKey};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_release_suffix() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tree.yaml");
+        fs::write(
+            &path,
+            "defaults: {type: {source: git, path: x}}\n\
+             counted: {release_suffix: counter}\nhashed: {release_suffix: hash}\nplain: {}\n\
+             clash: {release_suffix: counter, defines: {distprefix: .x}}\n",
+        )
+        .unwrap();
+        let tree = load_spec_tree(&path, &[]).unwrap();
+
+        let build = |key: &str| {
+            let key = SourceKey::from(key.to_string());
+            let build_key = BuildKey::new(key.clone(), BuildHash::from("0123456789abcdef".to_string()));
+            with_release_suffix(&tree.sources[&key], &build_key, dir.path())
+        };
+        let suffix = |key: &str| build(key).unwrap().defines.get("distprefix").cloned();
+
+        assert_eq!(suffix("counted").as_deref(), Some(".st1"));
+        assert_eq!(suffix("counted").as_deref(), Some(".st2"));
+        assert_eq!(suffix("hashed").as_deref(), Some(".h0123456789"));
+        assert_eq!(suffix("plain"), None);
+        assert!(build("clash").is_err());
+
+        let key = SourceKey::from("hashed".to_string());
+        let build_key = BuildKey::new(key.clone(), BuildHash::from("0123".to_string()));
+        let source = with_release_suffix(&tree.sources[&key], &build_key, dir.path()).unwrap();
+        assert_eq!(source.defines["distprefix"], ".h0123");
+    }
+
+    #[test]
+    fn test_concurrent_counters() {
+        let dir = TempDir::new().unwrap();
+        let build_key = BuildKey::new(
+            SourceKey::from("counted".to_string()),
+            BuildHash::from("0123456789abcdef".to_string()),
+        );
+
+        let mut counters: Vec<u64> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| next_counter(dir.path(), &build_key).unwrap()))
+                .collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+        counters.sort();
+        assert_eq!(counters, (1..=8).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,487 @@
+use anyhow::{Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, info};
+
+use crate::shell::{Shell, ShellEscaped};
+use crate::utils::get_file_sha256;
+use crate::{BuildKey, BuildOptions, BuildPlan, Error};
+
+/// The manifest of a build in the remote cache, which is uploaded after all of its files, so that a build is only
+/// found once it is complete.
+const MANIFEST_NAME: &str = "manifest.yaml";
+
+/// The characters that are kept as is in a path segment of a URL, which are the unreserved ones of RFC 3986.
+const URL_PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// The URL of a file of the cache, with every segment of its path percent-encoded, as file names of RPMs may contain
+/// characters like `^` and `+`.
+fn file_url(base_url: &str, path: &str) -> String {
+    let segments: Vec<String> = path
+        .split('/')
+        .map(|segment| utf8_percent_encode(segment, URL_PATH_SEGMENT).to_string())
+        .collect();
+    format!("{}/{}", base_url, segments.join("/"))
+}
+
+/// A binary cache that builds are pushed to and pulled from: either a plain HTTP server that supports GET and PUT,
+/// or a directory, e.g. a mounted network share.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteCache {
+    Http(String),
+    Directory(PathBuf),
+}
+
+impl FromStr for RemoteCache {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(RemoteCache::Http(s.trim_end_matches('/').to_string()));
+        }
+        let path = s.strip_prefix("file://").unwrap_or(s);
+        if path.is_empty() {
+            return Err(Error::InvalidValue(
+                "Invalid remote cache: expected an http(s):// URL or a directory".to_string(),
+            ));
+        }
+        Ok(RemoteCache::Directory(PathBuf::from(path)))
+    }
+}
+
+impl std::fmt::Display for RemoteCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteCache::Http(url) => write!(f, "{}", url),
+            RemoteCache::Directory(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// The files of a build in the remote cache.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    build_key: String,
+    /// SHA256 of every file of the `build` directory, by path relative to it
+    files: BTreeMap<String, String>,
+}
+
+impl RemoteCache {
+    /// Download a file of the cache, returning whether it exists.
+    fn get(&self, path: &str, dest: &Path) -> Result<bool> {
+        match self {
+            RemoteCache::Http(url) => {
+                let url = file_url(url, path);
+                let shell = Shell::new(dest.parent().unwrap_or_else(|| Path::new(".")));
+                let status = shell
+                    .run_with_output_sync(&format!(
+                        "curl -sS -o {} -w '%{{http_code}}' {}",
+                        dest.shell_escaped(),
+                        url.shell_escaped()
+                    ))
+                    .with_context(|| format!("Failed to download {}", url))?;
+                match status.as_str() {
+                    "200" => Ok(true),
+                    "404" => {
+                        let _ = fs::remove_file(dest);
+                        Ok(false)
+                    }
+                    status => anyhow::bail!("Failed to download {}: HTTP status {}", url, status),
+                }
+            }
+            RemoteCache::Directory(dir) => {
+                let src = dir.join(path);
+                if !src.is_file() {
+                    return Ok(false);
+                }
+                fs::copy(&src, dest)
+                    .with_context(|| format!("Failed to copy {} to {}", src.display(), dest.display()))?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Upload a file to the cache, replacing it if it exists.
+    fn put(&self, src: &Path, path: &str) -> Result<()> {
+        match self {
+            RemoteCache::Http(url) => {
+                let url = file_url(url, path);
+                let shell = Shell::new(src.parent().unwrap_or_else(|| Path::new(".")));
+                shell
+                    .run_with_output_sync(&format!("curl -fsS -T {} {}", src.shell_escaped(), url.shell_escaped()))
+                    .with_context(|| format!("Failed to upload {}", url))?;
+            }
+            RemoteCache::Directory(dir) => {
+                let dest = dir.join(path);
+                let parent = dest.parent().unwrap_or(dir);
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+                // Copied next to the file and renamed into place, so that readers never see it half-written
+                let tmp = dest.with_file_name(format!(
+                    ".{}.tmp",
+                    dest.file_name().unwrap_or_default().to_string_lossy()
+                ));
+                fs::copy(src, &tmp)
+                    .with_context(|| format!("Failed to copy {} to {}", src.display(), tmp.display()))?;
+                fs::rename(&tmp, &dest)
+                    .with_context(|| format!("Failed to rename {} to {}", tmp.display(), dest.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The manifest of a build, if the build is in the cache.
+    fn manifest(&self, build_key: &BuildKey) -> Result<Option<Manifest>> {
+        let dir = tempfile::tempdir().context("Failed to create temporary directory")?;
+        let path = dir.path().join(MANIFEST_NAME);
+        if !self.get(&format!("{}/{}", build_key.build_hash, MANIFEST_NAME), &path)? {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)?;
+        let manifest = serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse manifest of {} in remote cache {}", build_key, self))?;
+        Ok(Some(manifest))
+    }
+
+    /// Whether a build is in the cache.
+    pub(crate) fn contains(&self, build_key: &BuildKey) -> Result<bool> {
+        Ok(self.manifest(build_key)?.is_some())
+    }
+}
+
+/// The paths of the files under a directory, relative to it, in a stable order.
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<String>) -> Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        .collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &format!("{}/", name), files)?;
+        } else {
+            files.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Upload the `build` directory of a build in the workspace to the cache, unless it is there already. Returns
+/// whether it was uploaded.
+pub(crate) fn push_build(remote: &RemoteCache, build_key: &BuildKey, workspace: &Path) -> Result<bool> {
+    if remote.contains(build_key)? {
+        debug!("{} is already in remote cache", build_key);
+        return Ok(false);
+    }
+
+    let build_subdir = workspace.join("builds").join(build_key.build_dir_name()).join("build");
+    let mut paths = Vec::new();
+    list_files(&build_subdir, "", &mut paths)?;
+
+    let mut files = BTreeMap::new();
+    for path in paths {
+        let local_path = build_subdir.join(&path);
+        files.insert(path.clone(), get_file_sha256(&local_path)?);
+        remote.put(&local_path, &format!("{}/build/{}", build_key.build_hash, path))?;
+    }
+
+    let manifest = Manifest { build_key: build_key.to_string(), files };
+    let dir = tempfile::tempdir().context("Failed to create temporary directory")?;
+    let manifest_path = dir.path().join(MANIFEST_NAME);
+    fs::write(
+        &manifest_path,
+        serde_yaml::to_string(&manifest).context("Failed to serialize manifest to YAML")?,
+    )?;
+    remote.put(&manifest_path, &format!("{}/{}", build_key.build_hash, MANIFEST_NAME))?;
+
+    info!("Pushed {} to remote cache {}", build_key, remote);
+    Ok(true)
+}
+
+/// Download a build from the cache into the workspace, verifying the checksum of every file, returning whether it
+/// was in the cache. It is assembled in a temporary directory that is removed if the download fails, rather than in
+/// the `.tmp` directory of a local build, which would be taken for a failed build, and renamed into place.
+pub(crate) fn pull_build(remote: &RemoteCache, build_key: &BuildKey, workspace: &Path) -> Result<bool> {
+    let Some(manifest) = remote.manifest(build_key)? else {
+        return Ok(false);
+    };
+
+    let builds_dir = workspace.join("builds");
+    fs::create_dir_all(&builds_dir).with_context(|| format!("Failed to create directory: {}", builds_dir.display()))?;
+    let staging = tempfile::Builder::new()
+        .prefix(".tmp-")
+        .tempdir_in(&builds_dir)
+        .with_context(|| format!("Failed to create temporary directory in {}", builds_dir.display()))?;
+    // Temporary directories are only accessible to their owner, unlike the build directories
+    fs::set_permissions(staging.path(), fs::Permissions::from_mode(0o755))?;
+    let build_dir = staging.path();
+    let build_dir_final = builds_dir.join(build_key.build_dir_name());
+
+    for (path, sha256) in &manifest.files {
+        // The manifest comes from outside, so it must not lead out of the build directory
+        if !Path::new(path).components().all(|c| matches!(c, Component::Normal(_))) {
+            anyhow::bail!("Invalid path in manifest of {}: {}", build_key, path);
+        }
+        let local_path = build_dir.join("build").join(path);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        if !remote.get(&format!("{}/build/{}", build_key.build_hash, path), &local_path)? {
+            anyhow::bail!("File {} of {} is missing from remote cache {}", path, build_key, remote);
+        }
+        let actual = get_file_sha256(&local_path)?;
+        if actual != *sha256 {
+            anyhow::bail!(
+                "Checksum mismatch for file {} of {} from remote cache {}: expected {}, got {}",
+                path,
+                build_key,
+                remote,
+                sha256,
+                actual
+            );
+        }
+    }
+
+    fs::create_dir_all(build_dir.join("build"))?;
+    fs::rename(build_dir, &build_dir_final).with_context(|| {
+        format!(
+            "Failed to rename build directory from {} to {}",
+            build_dir.display(),
+            build_dir_final.display()
+        )
+    })?;
+
+    info!("Pulled {} from remote cache {}", build_key, remote);
+    Ok(true)
+}
+
+/// The builds of a plan that were synced with the remote cache.
+#[derive(Debug, Default)]
+pub struct CacheSyncReport {
+    /// Builds that were pushed or pulled
+    pub transferred: Vec<BuildKey>,
+    /// Builds that were already in the remote cache when pushing, or in the workspace when pulling
+    pub present: Vec<BuildKey>,
+    /// Builds that were not built in the workspace when pushing, or not in the remote cache when pulling
+    pub missing: Vec<BuildKey>,
+}
+
+/// The build keys of the sources of the build plans that are built locally, once each.
+fn local_build_keys(args: &BuildOptions, build_plans: &[BuildPlan]) -> Vec<BuildKey> {
+    let mut build_keys = Vec::new();
+    for plan in build_plans {
+        for key in plan.all_sources.iter().filter(|key| !plan.skipped.contains(*key)) {
+            if args.backend_for(&plan.spec_tree.sources[key]).is_remote() {
+                continue;
+            }
+            let build_key = BuildKey::new(key.clone(), plan.build_hashes[key].clone());
+            if !build_keys.contains(&build_key) {
+                build_keys.push(build_key);
+            }
+        }
+    }
+    build_keys
+}
+
+/// Push the builds of the build plans that are in the workspace to the remote cache.
+pub(crate) fn push_builds(
+    remote: &RemoteCache, args: &BuildOptions, build_plans: &[BuildPlan],
+) -> Result<CacheSyncReport> {
+    let mut report = CacheSyncReport::default();
+    for build_key in local_build_keys(args, build_plans) {
+        let build_subdir = args.workspace.join("builds").join(build_key.build_dir_name()).join("build");
+        if !build_subdir.exists() {
+            report.missing.push(build_key);
+        } else if push_build(remote, &build_key, &args.workspace)? {
+            report.transferred.push(build_key);
+        } else {
+            report.present.push(build_key);
+        }
+    }
+    Ok(report)
+}
+
+/// Pull the builds of the build plans that are not in the workspace from the remote cache.
+pub(crate) fn pull_builds(
+    remote: &RemoteCache, args: &BuildOptions, build_plans: &[BuildPlan],
+) -> Result<CacheSyncReport> {
+    let mut report = CacheSyncReport::default();
+    for build_key in local_build_keys(args, build_plans) {
+        let build_subdir = args.workspace.join("builds").join(build_key.build_dir_name()).join("build");
+        if build_subdir.exists() {
+            report.present.push(build_key);
+        } else if pull_build(remote, &build_key, &args.workspace)? {
+            report.transferred.push(build_key);
+        } else {
+            report.missing.push(build_key);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_url, pull_build, push_build, RemoteCache};
+    use crate::{BuildHash, BuildKey, SourceKey};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    /// A minimal HTTP server that keeps the files PUT to it in memory and serves them with GET.
+    fn serve_http() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let files: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::default();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let (method, path) = (parts.next().unwrap().to_string(), parts.next().unwrap().to_string());
+
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(':').unwrap();
+                    match name.to_lowercase().as_str() {
+                        "content-length" => content_length = value.trim().parse().unwrap(),
+                        "expect" => stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap(),
+                        _ => {}
+                    }
+                }
+
+                let (status, body) = match method.as_str() {
+                    "PUT" => {
+                        let mut body = vec![0; content_length];
+                        reader.read_exact(&mut body).unwrap();
+                        files.lock().unwrap().insert(path, body);
+                        ("201 Created", Vec::new())
+                    }
+                    _ => match files.lock().unwrap().get(&path) {
+                        Some(body) => ("200 OK", body.clone()),
+                        None => ("404 Not Found", Vec::new()),
+                    },
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        url
+    }
+
+    fn build_key() -> BuildKey {
+        BuildKey::new(
+            SourceKey::from("hello".to_string()),
+            BuildHash::from("0123456789abcdef".to_string()),
+        )
+    }
+
+    fn build_dir(workspace: &Path) -> PathBuf {
+        workspace.join("builds").join(build_key().build_dir_name())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "https://cache.example.com/builds/".parse::<RemoteCache>().unwrap(),
+            RemoteCache::Http("https://cache.example.com/builds".to_string())
+        );
+        assert_eq!(
+            "file:///mnt/cache".parse::<RemoteCache>().unwrap(),
+            RemoteCache::Directory(PathBuf::from("/mnt/cache"))
+        );
+        assert_eq!(
+            "/mnt/cache".parse::<RemoteCache>().unwrap(),
+            RemoteCache::Directory(PathBuf::from("/mnt/cache"))
+        );
+        assert!("".parse::<RemoteCache>().is_err());
+    }
+
+    #[test]
+    fn test_file_url() {
+        assert_eq!(
+            file_url("https://cache.example.com", "abc/build/hello-1.0^git1-1.x86_64.rpm"),
+            "https://cache.example.com/abc/build/hello-1.0%5Egit1-1.x86_64.rpm"
+        );
+        assert_eq!(
+            file_url("https://cache.example.com", "abc/build/a b+c~d"),
+            "https://cache.example.com/abc/build/a%20b%2Bc~d"
+        );
+    }
+
+    #[test]
+    fn test_push_and_pull() {
+        let store = TempDir::new().unwrap();
+        for remote in [
+            RemoteCache::Directory(store.path().to_path_buf()),
+            serve_http().parse().unwrap(),
+        ] {
+            let first = TempDir::new().unwrap();
+            let second = TempDir::new().unwrap();
+            fs::create_dir_all(build_dir(first.path()).join("build/repodata")).unwrap();
+            fs::write(build_dir(first.path()).join("build/hello-1-1.x86_64.rpm"), "rpm").unwrap();
+            fs::write(build_dir(first.path()).join("build/repodata/repomd.xml"), "xml").unwrap();
+            fs::write(build_dir(first.path()).join("build/hello-1^git1-1.src.rpm"), "srpm").unwrap();
+
+            assert!(!pull_build(&remote, &build_key(), second.path()).unwrap());
+            assert!(push_build(&remote, &build_key(), first.path()).unwrap());
+            assert!(!push_build(&remote, &build_key(), first.path()).unwrap());
+
+            assert!(pull_build(&remote, &build_key(), second.path()).unwrap());
+            let pulled = build_dir(second.path());
+            assert_eq!(
+                fs::read_to_string(pulled.join("build/hello-1-1.x86_64.rpm")).unwrap(),
+                "rpm"
+            );
+            assert_eq!(
+                fs::read_to_string(pulled.join("build/repodata/repomd.xml")).unwrap(),
+                "xml"
+            );
+            assert_eq!(
+                fs::read_to_string(pulled.join("build/hello-1^git1-1.src.rpm")).unwrap(),
+                "srpm"
+            );
+        }
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let store = TempDir::new().unwrap();
+        let workspace = TempDir::new().unwrap();
+        let remote = RemoteCache::Directory(store.path().to_path_buf());
+        fs::create_dir_all(build_dir(workspace.path()).join("build")).unwrap();
+        fs::write(build_dir(workspace.path()).join("build/hello.rpm"), "rpm").unwrap();
+        push_build(&remote, &build_key(), workspace.path()).unwrap();
+
+        fs::write(store.path().join("0123456789abcdef/build/hello.rpm"), "tampered").unwrap();
+        let other = TempDir::new().unwrap();
+        let err = pull_build(&remote, &build_key(), other.path()).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+        // Nothing is left behind in the workspace
+        assert_eq!(fs::read_dir(other.path().join("builds")).unwrap().count(), 0);
+    }
+}
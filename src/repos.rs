@@ -0,0 +1,133 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// An extra package repository made available to the builds of a source.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RepoSpec {
+    pub name: String,
+    pub baseurl: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpgcheck: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module_hotfixes: Option<bool>,
+}
+
+impl RepoSpec {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || self.name.contains(|c: char| c == '/' || c.is_whitespace()) {
+            anyhow::bail!("Invalid repo name '{}'", self.name);
+        }
+        if self.baseurl.is_empty() {
+            anyhow::bail!("Repo '{}' has an empty baseurl", self.name);
+        }
+        Ok(())
+    }
+
+    /// The fields of the repo as lines of a `.repo` file, excluding the section header.
+    pub fn repo_file_fields(&self) -> Vec<String> {
+        let mut fields = vec![format!("baseurl={}", self.baseurl)];
+        if let Some(gpgcheck) = self.gpgcheck {
+            fields.push(format!("gpgcheck={}", gpgcheck as u8));
+        }
+        if let Some(priority) = self.priority {
+            fields.push(format!("priority={}", priority));
+        }
+        if let Some(module_hotfixes) = self.module_hotfixes {
+            fields.push(format!("module_hotfixes={}", module_hotfixes as u8));
+        }
+        fields
+    }
+}
+
+/// Merge the repos of the whole tree into the repos of a source. A repo of the source replaces the tree repo
+/// of the same name.
+pub fn merge_repos(tree_repos: &[RepoSpec], source_repos: &mut Vec<RepoSpec>) {
+    let mut merged: Vec<RepoSpec> = tree_repos
+        .iter()
+        .filter(|repo| !source_repos.iter().any(|own| own.name == repo.name))
+        .cloned()
+        .collect();
+    merged.append(source_repos);
+    *source_repos = merged;
+}
+
+/// Add repos to a list, failing if a repo of the same name is already there with different settings.
+pub fn add_repos(repos: &mut Vec<RepoSpec>, new_repos: &[RepoSpec]) -> Result<()> {
+    for repo in new_repos {
+        match repos.iter().find(|existing| existing.name == repo.name) {
+            Some(existing) if existing != repo => {
+                anyhow::bail!("Repo '{}' is defined more than once with different settings", repo.name)
+            }
+            Some(_) => {}
+            None => repos.push(repo.clone()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_repos, merge_repos, RepoSpec};
+
+    fn repo(name: &str, baseurl: &str) -> RepoSpec {
+        RepoSpec {
+            name: name.to_string(),
+            baseurl: baseurl.to_string(),
+            gpgcheck: None,
+            priority: None,
+            module_hotfixes: None,
+        }
+    }
+
+    #[test]
+    fn test_repo_file_fields() {
+        let mut toolset = repo("toolset", "https://example.com/toolset/$basearch/");
+        assert_eq!(
+            toolset.repo_file_fields(),
+            vec!["baseurl=https://example.com/toolset/$basearch/"]
+        );
+
+        toolset.gpgcheck = Some(false);
+        toolset.priority = Some(10);
+        toolset.module_hotfixes = Some(true);
+        assert_eq!(
+            toolset.repo_file_fields(),
+            vec!["baseurl=https://example.com/toolset/$basearch/", "gpgcheck=0", "priority=10", "module_hotfixes=1"]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(repo("toolset", "https://example.com").validate().is_ok());
+        assert!(repo("tool set", "https://example.com").validate().is_err());
+        assert!(repo("../toolset", "https://example.com").validate().is_err());
+        assert!(repo("toolset", "").validate().is_err());
+    }
+
+    #[test]
+    fn test_merge_repos() {
+        let tree_repos = vec![repo("a", "https://a"), repo("b", "https://b")];
+        let mut source_repos = vec![repo("b", "https://other-b"), repo("c", "https://c")];
+        merge_repos(&tree_repos, &mut source_repos);
+        assert_eq!(
+            source_repos,
+            vec![
+                repo("a", "https://a"),
+                repo("b", "https://other-b"),
+                repo("c", "https://c")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_repos() {
+        let mut repos = vec![repo("a", "https://a")];
+        add_repos(&mut repos, &[repo("a", "https://a"), repo("b", "https://b")]).unwrap();
+        assert_eq!(repos.len(), 2);
+        assert!(add_repos(&mut repos, &[repo("a", "https://other-a")]).is_err());
+    }
+}
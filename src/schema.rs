@@ -0,0 +1,286 @@
+use anyhow::{Context, Result};
+use schemars::{JsonSchema, Schema};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::Marker;
+
+use crate::check::Problem;
+use crate::repos::RepoSpec;
+use crate::spec_file::SourceOrigin;
+use crate::{Error, Source};
+
+/// A spectree spec file: sources by key, along with a few reserved top-level keys.
+// Only used for generating the schema, as the loader reads spec files key by key
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[schemars(title = "spectree spec file")]
+struct SpecFile {
+    /// Other spec files to merge into the tree, relative to this file
+    #[serde(default)]
+    include: Vec<String>,
+    /// Fields merged into every source of this file and of the files it includes, unless the source sets them
+    #[serde(default)]
+    defaults: Option<Source>,
+    /// Variables for `${...}` references, which `--set` can override
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+    /// Target OSes to build for, unless given with `--target-os`
+    #[serde(default)]
+    targets: Vec<String>,
+    /// A version that is part of every build hash, to be bumped to rebuild all sources
+    #[serde(default)]
+    hash_version: Option<u32>,
+    /// Package repositories made available to the builds of all sources
+    #[serde(default)]
+    repos: Vec<RepoSpec>,
+    /// Sources, by key
+    #[serde(flatten)]
+    sources: BTreeMap<String, Source>,
+}
+
+/// The JSON Schema of spec files.
+pub fn spec_file_schema() -> Schema {
+    let mut schema = schemars::schema_for!(SpecFile);
+
+    // A source may get its type from `defaults:`, so none of its fields are required on their own
+    if let Some(source) = schema.pointer_mut("/$defs/Source").and_then(|source| source.as_object_mut()) {
+        source.remove("required");
+    }
+
+    schema
+}
+
+enum Frame {
+    Mapping { pointer: String, key: Option<String> },
+    Sequence { pointer: String, index: usize },
+}
+
+/// Positions of the nodes of a YAML document, by JSON pointer. The position of a mapping entry is that of its key.
+#[derive(Default)]
+struct Positions {
+    frames: Vec<Frame>,
+    positions: HashMap<String, Marker>,
+}
+
+fn escape_pointer_segment(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+impl Positions {
+    fn end_node(&mut self) {
+        match self.frames.last_mut() {
+            Some(Frame::Mapping { key, .. }) => *key = None,
+            Some(Frame::Sequence { index, .. }) => *index += 1,
+            None => {}
+        }
+    }
+
+    /// The position of a node, or of its closest ancestor that has one.
+    fn find(&self, pointer: &str) -> Option<&Marker> {
+        let mut pointer = pointer;
+        loop {
+            if let Some(marker) = self.positions.get(pointer) {
+                return Some(marker);
+            }
+            pointer = &pointer[..pointer.rfind('/')?];
+        }
+    }
+}
+
+impl MarkedEventReceiver for Positions {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let pointer = match &event {
+            Event::Scalar(..) | Event::Alias(..) | Event::MappingStart(..) | Event::SequenceStart(..) => {
+                match self.frames.last_mut() {
+                    None => String::new(),
+                    Some(Frame::Mapping { pointer, key: key @ None }) => {
+                        // Only scalar keys can be pointed to
+                        let name = match &event {
+                            Event::Scalar(name, ..) => name.clone(),
+                            _ => String::new(),
+                        };
+                        let key_pointer = format!("{}/{}", pointer, escape_pointer_segment(&name));
+                        self.positions.entry(key_pointer).or_insert(mark);
+                        *key = Some(name);
+                        return;
+                    }
+                    Some(Frame::Mapping { pointer, key: Some(key) }) => {
+                        format!("{}/{}", pointer, escape_pointer_segment(key))
+                    }
+                    Some(Frame::Sequence { pointer, index }) => format!("{}/{}", pointer, index),
+                }
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.frames.pop();
+                self.end_node();
+                return;
+            }
+            _ => return,
+        };
+
+        self.positions.entry(pointer.clone()).or_insert(mark);
+        match event {
+            Event::MappingStart(..) => self.frames.push(Frame::Mapping { pointer, key: None }),
+            Event::SequenceStart(..) => self.frames.push(Frame::Sequence { pointer, index: 0 }),
+            _ => self.end_node(),
+        }
+    }
+}
+
+/// Describe where a JSON pointer leads, e.g. `gcc.type` for `/gcc/type`.
+fn describe_pointer(pointer: &str) -> String {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Validate the contents of a single spec file against the schema.
+fn validate_content(file: &Path, content: &str, validator: &jsonschema::Validator) -> Vec<Problem> {
+    let problem = |line: Option<usize>, column: Option<usize>, message: String| Problem {
+        origin: Some(SourceOrigin { file: file.to_path_buf(), line, column }),
+        message,
+    };
+
+    let document: serde_yaml::Value = match serde_yaml::from_str(content) {
+        Ok(document) => document,
+        Err(err) => {
+            let location = err.location();
+            return vec![problem(
+                location.as_ref().map(|l| l.line()),
+                location.as_ref().map(|l| l.column()),
+                format!("invalid YAML: {}", err),
+            )];
+        }
+    };
+    if document.is_null() {
+        return Vec::new();
+    }
+    let instance = match serde_json::to_value(&document) {
+        Ok(instance) => instance,
+        Err(err) => return vec![problem(None, None, format!("cannot be checked: {}", err))],
+    };
+
+    let mut positions = Positions::default();
+    // The document was already parsed successfully, so positions are only missing if the two parsers disagree
+    let _ = Parser::new_from_str(content).load(&mut positions, false);
+
+    validator
+        .iter_errors(&instance)
+        .map(|error| {
+            let mut pointer = error.instance_path().to_string();
+            if let jsonschema::error::ValidationErrorKind::AdditionalProperties { unexpected } = error.kind() {
+                if let Some(field) = unexpected.first() {
+                    pointer = format!("{}/{}", pointer, escape_pointer_segment(field));
+                }
+            }
+            let marker = positions.find(&pointer);
+            let message = match error.instance_path().as_str() {
+                "" => error.to_string(),
+                path => format!("{}: {}", describe_pointer(path), error),
+            };
+            problem(marker.map(|m| m.line()), marker.map(|m| m.col() + 1), message)
+        })
+        .collect()
+}
+
+/// Validate a spec file and all the files it includes against the schema.
+pub fn validate_spec_files(spec_file: &Path) -> Result<Vec<Problem>, Error> {
+    validate_spec_files_inner(spec_file).map_err(|err| Error::SpecTree(err.into()))
+}
+
+fn validate_spec_files_inner(spec_file: &Path) -> Result<Vec<Problem>> {
+    let schema = spec_file_schema();
+    let validator =
+        jsonschema::validator_for(schema.as_value()).map_err(|err| anyhow::anyhow!("Invalid schema: {}", err))?;
+
+    let mut problems = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![spec_file.to_path_buf()];
+
+    while let Some(file) = pending.pop() {
+        let canonical =
+            fs::canonicalize(&file).with_context(|| format!("Failed to read spec file: {}", file.display()))?;
+        if !visited.insert(canonical) {
+            continue;
+        }
+
+        let content =
+            fs::read_to_string(&file).with_context(|| format!("Failed to read spec file: {}", file.display()))?;
+        problems.extend(validate_content(&file, &content, &validator));
+
+        let includes = serde_yaml::from_str::<serde_yaml::Value>(&content)
+            .ok()
+            .and_then(|document| serde_yaml::from_value::<Vec<String>>(document.get("include")?.clone()).ok())
+            .unwrap_or_default();
+        let base_dir = file.parent().unwrap_or_else(|| Path::new("."));
+        pending.extend(includes.iter().map(|include| base_dir.join(include)).collect::<Vec<PathBuf>>());
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spec_file_schema, validate_spec_files};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn problems(yaml: &str) -> Vec<String> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tree.yaml");
+        fs::write(&path, yaml).unwrap();
+        validate_spec_files(&path)
+            .unwrap()
+            .iter()
+            .map(|problem| problem.to_string().replace(&format!("{}", path.display()), "tree.yaml"))
+            .collect()
+    }
+
+    #[test]
+    fn test_schema_has_sources_and_reserved_keys() {
+        let schema = spec_file_schema();
+        for key in ["include", "defaults", "vars", "targets", "hash_version", "repos"] {
+            assert!(schema.pointer(&format!("/properties/{}", key)).is_some(), "{}", key);
+        }
+        assert!(schema.pointer("/additionalProperties").is_some());
+        assert!(schema.pointer("/$defs/Source/required").is_none());
+    }
+
+    #[test]
+    fn test_valid_files() {
+        let yaml = "defaults: {type: {source: git, path: x}}\nvars: {a: b}\n\
+                    hello: {dependencies: [\"~x\"], with: [docs], stages: [{name: s1}, {name: s2}]}\n\
+                    pkg:\n  type:\n    source: srpm\n    path: /x.src.rpm\n";
+        assert_eq!(problems(yaml), Vec::<String>::new());
+        assert_eq!(problems(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_problem_positions() {
+        let yaml = "gcc:\n  source:\n    type: git\n  dependencies: [binutils]\n\
+                    binutils: {type: {source: git, path: x}, network: \"yes\"}\n";
+        assert_eq!(
+            problems(yaml),
+            vec![
+                "tree.yaml:5:42: binutils.network: \"yes\" is not of type \"boolean\"",
+                "tree.yaml:2:3: gcc: Additional properties are not allowed ('source' was unexpected)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_included_files() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("main.yaml"), "include: [sub/other.yaml]\n").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/other.yaml"), "a: {typo: 1}\n").unwrap();
+        let problems = validate_spec_files(&dir.path().join("main.yaml")).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].to_string().contains("other.yaml:1:5: a: Additional properties"));
+    }
+}
@@ -29,13 +29,13 @@ impl ShellEscaped for String {
 
 impl ShellEscaped for Path {
     fn shell_escaped(&self) -> Cow<'_, str> {
-        shell_escape(self.to_string_lossy().into())
+        shell_escape(self.to_string_lossy())
     }
 }
 
 impl ShellEscaped for PathBuf {
     fn shell_escaped(&self) -> Cow<'_, str> {
-        shell_escape(self.to_string_lossy().into())
+        shell_escape(self.to_string_lossy())
     }
 }
 
@@ -45,77 +45,6 @@ impl ShellEscaped for std::path::Display<'_> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{shell_escape, ShellEscaped};
-    use std::path::{Path, PathBuf};
-
-    #[test]
-    fn test_shell_escape_simple_path() {
-        let path = "/simple/path";
-        let escaped = shell_escape(path.into());
-        assert_eq!(escaped, "/simple/path");
-    }
-
-    #[test]
-    fn test_shell_escape_path_with_spaces() {
-        let path = "/path with spaces/file.txt";
-        let escaped = shell_escape(path.into());
-        assert_eq!(escaped, "'/path with spaces/file.txt'");
-    }
-
-    #[test]
-    fn test_shell_escape_path_with_special_chars() {
-        let path = "/path/with$special&chars";
-        let escaped = shell_escape(path.into());
-        assert_eq!(escaped, "'/path/with$special&chars'");
-    }
-
-    #[test]
-    fn test_shell_escape_path_with_quotes() {
-        let path = "/path/with'quotes";
-        let escaped = shell_escape(path.into());
-        assert_eq!(escaped, "'/path/with'\\''quotes'");
-    }
-
-    // Tests for the ShellEscaped trait
-    #[test]
-    fn test_trait_str() {
-        let s = "/simple/path";
-        assert_eq!(s.shell_escaped(), "/simple/path");
-
-        let s = "/path with spaces";
-        assert_eq!(s.shell_escaped(), "'/path with spaces'");
-    }
-
-    #[test]
-    fn test_trait_string() {
-        let s = String::from("/simple/path");
-        assert_eq!(s.shell_escaped(), "/simple/path");
-
-        let s = String::from("/path with spaces");
-        assert_eq!(s.shell_escaped(), "'/path with spaces'");
-    }
-
-    #[test]
-    fn test_trait_path() {
-        let p = Path::new("/simple/path");
-        assert_eq!(p.shell_escaped(), "/simple/path");
-
-        let p = Path::new("/path with spaces");
-        assert_eq!(p.shell_escaped(), "'/path with spaces'");
-    }
-
-    #[test]
-    fn test_trait_pathbuf() {
-        let p = PathBuf::from("/simple/path");
-        assert_eq!(p.shell_escaped(), "/simple/path");
-
-        let p = PathBuf::from("/path with spaces");
-        assert_eq!(p.shell_escaped(), "'/path with spaces'");
-    }
-}
-
 pub struct Shell<'a> {
     working_dir: &'a Path,
     docker_image: Option<String>,
@@ -188,7 +117,7 @@ impl<'a> Shell<'a> {
             }
             None => {
                 let mut cmd = Command::new("bash");
-                cmd.args(&["-c", command]).current_dir(self.working_dir);
+                cmd.args(["-c", command]).current_dir(self.working_dir);
                 cmd
             }
         };
@@ -235,7 +164,7 @@ impl<'a> Shell<'a> {
             }
             None => {
                 let mut cmd = TokioCommand::new("bash");
-                cmd.args(&["-c", command]).current_dir(self.working_dir);
+                cmd.args(["-c", command]).current_dir(self.working_dir);
                 cmd
             }
         };
@@ -427,3 +356,74 @@ impl<'a> Shell<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{shell_escape, ShellEscaped};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_shell_escape_simple_path() {
+        let path = "/simple/path";
+        let escaped = shell_escape(path.into());
+        assert_eq!(escaped, "/simple/path");
+    }
+
+    #[test]
+    fn test_shell_escape_path_with_spaces() {
+        let path = "/path with spaces/file.txt";
+        let escaped = shell_escape(path.into());
+        assert_eq!(escaped, "'/path with spaces/file.txt'");
+    }
+
+    #[test]
+    fn test_shell_escape_path_with_special_chars() {
+        let path = "/path/with$special&chars";
+        let escaped = shell_escape(path.into());
+        assert_eq!(escaped, "'/path/with$special&chars'");
+    }
+
+    #[test]
+    fn test_shell_escape_path_with_quotes() {
+        let path = "/path/with'quotes";
+        let escaped = shell_escape(path.into());
+        assert_eq!(escaped, "'/path/with'\\''quotes'");
+    }
+
+    // Tests for the ShellEscaped trait
+    #[test]
+    fn test_trait_str() {
+        let s = "/simple/path";
+        assert_eq!(s.shell_escaped(), "/simple/path");
+
+        let s = "/path with spaces";
+        assert_eq!(s.shell_escaped(), "'/path with spaces'");
+    }
+
+    #[test]
+    fn test_trait_string() {
+        let s = String::from("/simple/path");
+        assert_eq!(s.shell_escaped(), "/simple/path");
+
+        let s = String::from("/path with spaces");
+        assert_eq!(s.shell_escaped(), "'/path with spaces'");
+    }
+
+    #[test]
+    fn test_trait_path() {
+        let p = Path::new("/simple/path");
+        assert_eq!(p.shell_escaped(), "/simple/path");
+
+        let p = Path::new("/path with spaces");
+        assert_eq!(p.shell_escaped(), "'/path with spaces'");
+    }
+
+    #[test]
+    fn test_trait_pathbuf() {
+        let p = PathBuf::from("/simple/path");
+        assert_eq!(p.shell_escaped(), "/simple/path");
+
+        let p = PathBuf::from("/path with spaces");
+        assert_eq!(p.shell_escaped(), "'/path with spaces'");
+    }
+}
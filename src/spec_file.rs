@@ -0,0 +1,511 @@
+use anyhow::{Context, Result};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+use crate::repos::{self, RepoSpec};
+use crate::stages;
+use crate::template::TemplateVars;
+use crate::{Source, SourceKey, SpecTree};
+
+/// Top-level key listing other spec files to merge into the tree
+const INCLUDE_KEY: &str = "include";
+/// Top-level key holding fields merged into every source of the file
+const DEFAULTS_KEY: &str = "defaults";
+/// Top-level key holding variables for `${...}` references
+const VARS_KEY: &str = "vars";
+/// Top-level key listing the target OSes to build for
+const TARGETS_KEY: &str = "targets";
+/// Top-level key listing package repositories for all sources
+const REPOS_KEY: &str = "repos";
+/// Top-level key holding a version that is part of every build hash
+const HASH_VERSION_KEY: &str = "hash_version";
+
+/// Where a source, or some other part of a spec file, was defined, used for error reporting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceOrigin {
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl std::fmt::Display for SourceOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}:{}", self.file.display(), line, column),
+            (Some(line), None) => write!(f, "{}:{}", self.file.display(), line),
+            _ => write!(f, "{}", self.file.display()),
+        }
+    }
+}
+
+/// Find the 1-based line on which a top-level key of a YAML document is defined.
+pub fn find_top_level_key_line(content: &str, key: &str) -> Option<usize> {
+    let candidates = [format!("{}:", key), format!("\"{}\":", key), format!("'{}':", key)];
+    content
+        .lines()
+        .position(|line| candidates.iter().any(|candidate| line.starts_with(candidate.as_str())))
+        .map(|index| index + 1)
+}
+
+struct Loader {
+    tree: SpecTree,
+    vars: BTreeMap<String, (String, PathBuf)>,
+    /// File that defined `targets:`, as only one file may define them
+    targets_file: Option<PathBuf>,
+    /// File that defined `hash_version:`, for the same reason
+    hash_version_file: Option<PathBuf>,
+    /// Repos of the whole tree, from all files
+    repos: Vec<RepoSpec>,
+    /// Files currently being loaded, for detecting include cycles
+    stack: Vec<PathBuf>,
+    /// Files already loaded, so that a file included from several others is only loaded once
+    loaded: HashSet<PathBuf>,
+}
+
+impl Loader {
+    fn load_file(&mut self, path: &Path, inherited_defaults: &Mapping) -> Result<()> {
+        let canonical =
+            fs::canonicalize(path).with_context(|| format!("Failed to read spec file: {}", path.display()))?;
+        if self.stack.contains(&canonical) {
+            anyhow::bail!("Spec file {} includes itself", path.display());
+        }
+        // Loaded with the defaults of the first file that includes it
+        if !self.loaded.insert(canonical.clone()) {
+            debug!("Spec file {} is already loaded", path.display());
+            return Ok(());
+        }
+
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read spec file: {}", path.display()))?;
+        let document: Option<Mapping> =
+            serde_yaml::from_str(&content).with_context(|| format!("Failed to parse spec file: {}", path.display()))?;
+        let mut document = document.unwrap_or_default();
+
+        // Defaults of an including file apply to the included files as well, with their own defaults on top
+        let mut defaults = inherited_defaults.clone();
+        if let Some(file_defaults) = document.remove(DEFAULTS_KEY) {
+            let Value::Mapping(file_defaults) = file_defaults else {
+                anyhow::bail!("'{}' in {} must be a mapping", DEFAULTS_KEY, path.display());
+            };
+            merge_defaults(&mut defaults, &file_defaults);
+        }
+
+        if let Some(file_vars) = document.remove(VARS_KEY) {
+            let file_vars: BTreeMap<String, String> = serde_yaml::from_value(file_vars)
+                .with_context(|| format!("'{}' in {} must be a mapping of strings", VARS_KEY, path.display()))?;
+            for (name, value) in file_vars {
+                if let Some((existing, existing_file)) = self.vars.get(&name) {
+                    if *existing != value {
+                        anyhow::bail!(
+                            "Variable '{}' is defined differently in {} and in {}",
+                            name,
+                            existing_file.display(),
+                            path.display()
+                        );
+                    }
+                }
+                self.vars.insert(name, (value, path.to_path_buf()));
+            }
+        }
+
+        if let Some(targets) = document.remove(TARGETS_KEY) {
+            let targets: Vec<String> = serde_yaml::from_value(targets)
+                .with_context(|| format!("'{}' in {} must be a list of strings", TARGETS_KEY, path.display()))?;
+            if let Some(targets_file) = &self.targets_file {
+                anyhow::bail!(
+                    "'{}' is defined both in {} and in {}",
+                    TARGETS_KEY,
+                    targets_file.display(),
+                    path.display()
+                );
+            }
+            self.tree.targets = targets;
+            self.targets_file = Some(path.to_path_buf());
+        }
+
+        if let Some(hash_version) = document.remove(HASH_VERSION_KEY) {
+            let hash_version: u32 = serde_yaml::from_value(hash_version)
+                .with_context(|| format!("'{}' in {} must be a number", HASH_VERSION_KEY, path.display()))?;
+            if let Some(hash_version_file) = &self.hash_version_file {
+                anyhow::bail!(
+                    "'{}' is defined both in {} and in {}",
+                    HASH_VERSION_KEY,
+                    hash_version_file.display(),
+                    path.display()
+                );
+            }
+            self.tree.hash_version = Some(hash_version);
+            self.hash_version_file = Some(path.to_path_buf());
+        }
+
+        if let Some(file_repos) = document.remove(REPOS_KEY) {
+            let file_repos: Vec<RepoSpec> = serde_yaml::from_value(file_repos)
+                .with_context(|| format!("Failed to parse '{}' in {}", REPOS_KEY, path.display()))?;
+            repos::add_repos(&mut self.repos, &file_repos).with_context(|| format!("In {}", path.display()))?;
+        }
+
+        let includes: Vec<String> = match document.remove(INCLUDE_KEY) {
+            Some(value) => serde_yaml::from_value(value)
+                .with_context(|| format!("'{}' in {} must be a list of paths", INCLUDE_KEY, path.display()))?,
+            None => Vec::new(),
+        };
+
+        for (key, value) in document {
+            let Value::String(key) = key else {
+                anyhow::bail!("Source keys must be strings in {}", path.display());
+            };
+            let origin = SourceOrigin {
+                file: path.to_path_buf(),
+                line: find_top_level_key_line(&content, &key),
+                column: None,
+            };
+
+            let mut value = value;
+            if let Value::Mapping(source) = &mut value {
+                merge_defaults(source, &defaults);
+            }
+            let source: Source = serde_yaml::from_value(value)
+                .with_context(|| format!("Failed to parse source '{}' at {}", key, origin))?;
+
+            let key = SourceKey::from(key);
+            if let Some(existing) = self.tree.origins.get(&key) {
+                anyhow::bail!("Source '{}' is defined twice: at {} and at {}", key, existing, origin);
+            }
+            self.tree.sources.insert(key.clone(), source);
+            self.tree.origins.insert(key, origin);
+        }
+
+        self.stack.push(canonical);
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for include in includes {
+            let include_path = base_dir.join(&include);
+            debug!("Including spec file {} from {}", include_path.display(), path.display());
+            self.load_file(&include_path, &defaults)
+                .with_context(|| format!("Failed to include {} from {}", include, path.display()))?;
+        }
+        self.stack.pop();
+
+        Ok(())
+    }
+}
+
+/// Add every default field that the source does not set itself.
+fn merge_defaults(source: &mut Mapping, defaults: &Mapping) {
+    for (field, value) in defaults {
+        if !source.contains_key(field) {
+            source.insert(field.clone(), value.clone());
+        }
+    }
+}
+
+/// Load a spec file along with all the files it includes, and expand the `${...}` references of all sources.
+pub fn load_spec_tree(spec_file: &Path, overrides: &[(String, String)]) -> Result<SpecTree> {
+    let mut loader = Loader {
+        tree: SpecTree::default(),
+        vars: BTreeMap::new(),
+        targets_file: None,
+        hash_version_file: None,
+        repos: Vec::new(),
+        stack: Vec::new(),
+        loaded: HashSet::new(),
+    };
+    loader.load_file(spec_file, &Mapping::new())?;
+
+    let spec_vars = loader.vars.into_iter().map(|(name, (value, _))| (name, value)).collect();
+    let vars = TemplateVars::new(spec_vars, overrides)?;
+    for (key, source) in loader.tree.sources.iter_mut() {
+        repos::merge_repos(&loader.repos, &mut source.repos);
+        source
+            .expand_templates(key, &vars)
+            .with_context(|| format!("Failed to expand source '{}' at {}", key, loader.tree.origins[key]))?;
+        for repo in &source.repos {
+            repo.validate()
+                .with_context(|| format!("Invalid repo in source '{}' at {}", key, loader.tree.origins[key]))?;
+        }
+    }
+
+    stages::expand_stages(&mut loader.tree)?;
+    for (key, source) in &loader.tree.sources {
+        source.build_params().with_context(|| {
+            format!(
+                "Invalid build parameters in source '{}' at {}",
+                key, loader.tree.origins[key]
+            )
+        })?;
+    }
+
+    info!("Successfully read YAML file with {} sources", loader.tree.sources.len());
+
+    Ok(loader.tree)
+}
+
+/// Load a spec tree from YAML in a temporary file, with every source being a local git repository unless it says
+/// otherwise.
+#[cfg(test)]
+pub(crate) fn load_test_tree(yaml: &str) -> SpecTree {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("tree.yaml");
+    std::fs::write(&path, format!("defaults: {{type: {{source: git, path: x}}}}\n{}", yaml)).unwrap();
+    load_spec_tree(&path, &[]).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_top_level_key_line, load_spec_tree};
+    use crate::{BuilderBackend, SourceKey, SourceType};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_top_level_key_line() {
+        let content = "a: {}\n\nb:\n  c: 1\n'd': {}\n";
+        assert_eq!(find_top_level_key_line(content, "a"), Some(1));
+        assert_eq!(find_top_level_key_line(content, "b"), Some(3));
+        assert_eq!(find_top_level_key_line(content, "c"), None);
+        assert_eq!(find_top_level_key_line(content, "d"), Some(5));
+    }
+
+    #[test]
+    fn test_defaults_are_merged() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tree.yaml");
+        fs::write(
+            &path,
+            r#"
+defaults: {type: {source: git, path: "${NAME}"}, network: true}
+a: {}
+b: {dependencies: [a], network: false}
+c: {type: {source: srpm, path: c.src.rpm}}
+"#,
+        )
+        .unwrap();
+
+        let tree = load_spec_tree(&path, &[]).unwrap();
+        assert_eq!(tree.sources.len(), 3);
+        assert!(tree.sources[&SourceKey::from("a".to_string())].network);
+        assert!(!tree.sources[&SourceKey::from("b".to_string())].network);
+        assert!(matches!(
+            tree.sources[&SourceKey::from("c".to_string())].typ,
+            SourceType::Srpm { .. }
+        ));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("teams")).unwrap();
+        fs::write(
+            dir.path().join("tree.yaml"),
+            "include: [teams/libs.yaml]\ndefaults: {type: {source: git, path: \"${NAME}\"}}\napp: {dependencies: [lib]}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("teams/libs.yaml"), "lib: {}\n").unwrap();
+
+        let tree = load_spec_tree(&dir.path().join("tree.yaml"), &[]).unwrap();
+        assert_eq!(tree.sources.len(), 2);
+        let lib = SourceKey::from("lib".to_string());
+        assert_eq!(
+            tree.origins[&lib].to_string(),
+            format!("{}:1", dir.path().join("teams/libs.yaml").display())
+        );
+    }
+
+    #[test]
+    fn test_duplicate_key_reports_both_locations() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("tree.yaml"),
+            "include: [other.yaml]\n\nlib: {type: {source: git, path: lib}}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("other.yaml"),
+            "\nlib: {type: {source: git, path: lib}}\n",
+        )
+        .unwrap();
+
+        let err = load_spec_tree(&dir.path().join("tree.yaml"), &[]).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("tree.yaml:3"), "{}", message);
+        assert!(message.contains("other.yaml:2"), "{}", message);
+    }
+
+    #[test]
+    fn test_vars() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("tree.yaml"),
+            "vars: {root: /src}\ndefaults: {type: {source: git, path: \"${root}/${NAME}\"}}\nlib: {}\n",
+        )
+        .unwrap();
+
+        let lib = SourceKey::from("lib".to_string());
+        let tree = load_spec_tree(&dir.path().join("tree.yaml"), &[]).unwrap();
+        assert!(matches!(&tree.sources[&lib].typ, SourceType::Git { path: Some(path), .. } if path == "/src/lib"));
+
+        let overrides = [("root".to_string(), "/home/me".to_string())];
+        let tree = load_spec_tree(&dir.path().join("tree.yaml"), &overrides).unwrap();
+        assert!(matches!(&tree.sources[&lib].typ, SourceType::Git { path: Some(path), .. } if path == "/home/me/lib"));
+
+        fs::write(
+            dir.path().join("tree.yaml"),
+            "lib: {type: {source: git, path: \"${nope}\"}}\n",
+        )
+        .unwrap();
+        assert!(load_spec_tree(&dir.path().join("tree.yaml"), &[]).is_err());
+    }
+
+    #[test]
+    fn test_targets() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("tree.yaml"),
+            "include: [other.yaml]\ntargets: [epel9, epel10]\nlib: {type: {source: git, path: lib}, skip_targets: [epel9]}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("other.yaml"), "").unwrap();
+
+        let tree = load_spec_tree(&dir.path().join("tree.yaml"), &[]).unwrap();
+        assert_eq!(tree.targets, vec!["epel9", "epel10"]);
+        let lib = &tree.sources[&SourceKey::from("lib".to_string())];
+        assert!(!lib.is_built_for_target(Some("epel9")));
+        assert!(lib.is_built_for_target(Some("epel10")));
+        assert!(lib.is_built_for_target(None));
+
+        fs::write(dir.path().join("other.yaml"), "targets: [epel9]\n").unwrap();
+        assert!(load_spec_tree(&dir.path().join("tree.yaml"), &[]).is_err());
+    }
+
+    #[test]
+    fn test_backend_overrides() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("tree.yaml"),
+            "defaults: {type: {source: git, path: x}}\na: {backend: docker, target_os: epel9}\nb: {}\n",
+        )
+        .unwrap();
+
+        let tree = load_spec_tree(&dir.path().join("tree.yaml"), &[]).unwrap();
+        let a = &tree.sources[&SourceKey::from("a".to_string())];
+        assert_eq!(a.backend, Some(BuilderBackend::Docker));
+        assert_eq!(a.target_os.as_deref(), Some("epel9"));
+        assert_eq!(tree.sources[&SourceKey::from("b".to_string())].backend, None);
+
+        fs::write(
+            dir.path().join("tree.yaml"),
+            "a: {type: {source: git, path: x}, backend: qemu}\n",
+        )
+        .unwrap();
+        assert!(load_spec_tree(&dir.path().join("tree.yaml"), &[]).is_err());
+    }
+
+    #[test]
+    fn test_repos() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("tree.yaml"),
+            r#"
+vars: {mirror: "https://mirror.example.com"}
+repos: [{name: toolset, baseurl: "${mirror}/toolset/$basearch/", gpgcheck: false}]
+defaults: {type: {source: git, path: x}}
+a: {}
+b: {repos: [{name: extras, baseurl: "https://example.com/extras/", priority: 10}]}
+"#,
+        )
+        .unwrap();
+
+        let tree = load_spec_tree(&dir.path().join("tree.yaml"), &[]).unwrap();
+        let a = &tree.sources[&SourceKey::from("a".to_string())];
+        assert_eq!(a.repos.len(), 1);
+        assert_eq!(a.repos[0].baseurl, "https://mirror.example.com/toolset/$basearch/");
+        let b = &tree.sources[&SourceKey::from("b".to_string())];
+        let names: Vec<&str> = b.repos.iter().map(|repo| repo.name.as_str()).collect();
+        assert_eq!(names, vec!["toolset", "extras"]);
+    }
+
+    #[test]
+    fn test_build_params() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tree.yaml");
+        fs::write(
+            &path,
+            r#"
+vars: {suffix: ".acme"}
+defaults: {type: {source: git, path: x}}
+a: {with: [docs], defines: {dist: "${suffix}"}, params: ["--without", "tests"]}
+"#,
+        )
+        .unwrap();
+        let tree = load_spec_tree(&path, &[]).unwrap();
+        let params = tree.sources[&SourceKey::from("a".to_string())].build_params().unwrap();
+        assert_eq!(
+            params.to_command_args(""),
+            "--with docs --without tests --define 'dist .acme'"
+        );
+
+        fs::write(
+            &path,
+            "defaults: {type: {source: git, path: x}}
+a: {params: [\"--nocheck\"]}
+",
+        )
+        .unwrap();
+        let message = format!("{:#}", load_spec_tree(&path, &[]).unwrap_err());
+        assert!(
+            message.contains("Unsupported build parameter '--nocheck'"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.yaml"), "include: [b.yaml]\n").unwrap();
+        fs::write(dir.path().join("b.yaml"), "include: [a.yaml]\n").unwrap();
+
+        let err = load_spec_tree(&dir.path().join("a.yaml"), &[]).unwrap_err();
+        assert!(format!("{:#}", err).contains("includes itself"));
+    }
+
+    #[test]
+    fn test_include_diamond() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("libs")).unwrap();
+        fs::write(
+            dir.path().join("root.yaml"),
+            "include: [a.yaml, libs/b.yaml]
+defaults: {type: {source: git, path: x}}
+",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("a.yaml"),
+            "include: [common.yaml]
+a: {dependencies: [common]}
+",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("libs/b.yaml"),
+            "include: [../common.yaml]
+b: {dependencies: [common]}
+",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("common.yaml"),
+            "common: {}
+",
+        )
+        .unwrap();
+
+        let tree = load_spec_tree(&dir.path().join("root.yaml"), &[]).unwrap();
+        let mut keys: Vec<&str> = tree.sources.keys().map(|key| key.as_ref()).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "common"]);
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{Dependency, SourceKey, SpecTree};
+
+/// A build stage of a source, for bootstrapping sources that are part of a build cycle.
+///
+/// Every stage but the last is built as a separate source named `<key>@<stage>`; the last stage keeps the key of
+/// the source, so that its dependents get the complete build. Each stage has a direct-only dependency on the stage
+/// before it.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Stage {
+    pub name: String,
+    /// Dependencies of the stage, instead of those of the source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<Vec<String>>")]
+    pub dependencies: Option<Vec<SourceKey>>,
+    /// Bconds to enable in this stage, in addition to those of the source
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub with: Vec<String>,
+    /// Bconds to disable in this stage, in addition to those of the source
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub without: Vec<String>,
+    /// RPM macros to define in this stage, overriding those of the source
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub defines: BTreeMap<String, String>,
+}
+
+/// The key of the source built for a stage that is not the last one.
+pub fn stage_key(key: &SourceKey, stage: &str) -> SourceKey {
+    SourceKey::from(format!("{}@{}", key, stage))
+}
+
+fn validate_stages(key: &SourceKey, stages: &[Stage]) -> Result<()> {
+    let mut names = HashSet::new();
+    for stage in stages {
+        if stage.name.is_empty()
+            || !stage
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            anyhow::bail!("Invalid stage name '{}' in source '{}'", stage.name, key);
+        }
+        if !names.insert(stage.name.as_str()) {
+            anyhow::bail!("Stage '{}' is defined more than once in source '{}'", stage.name, key);
+        }
+    }
+    Ok(())
+}
+
+/// Replace every source that has stages with one source per stage.
+pub fn expand_stages(spec_tree: &mut SpecTree) -> Result<()> {
+    let mut staged_keys: Vec<SourceKey> = spec_tree
+        .sources
+        .iter()
+        .filter(|(_, source)| !source.stages.is_empty())
+        .map(|(key, _)| key.clone())
+        .collect();
+    staged_keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+
+    for key in staged_keys {
+        let mut source = spec_tree.sources.remove(&key).unwrap();
+        let origin = spec_tree.origins[&key].clone();
+        let stages = std::mem::take(&mut source.stages);
+        validate_stages(&key, &stages)?;
+
+        let mut previous: Option<SourceKey> = None;
+        for (index, stage) in stages.iter().enumerate() {
+            let key_of_stage = if index + 1 == stages.len() {
+                key.clone()
+            } else {
+                stage_key(&key, &stage.name)
+            };
+            if spec_tree.sources.contains_key(&key_of_stage) {
+                anyhow::bail!(
+                    "Stage '{}' of source '{}' at {} is also defined as a source at {}",
+                    stage.name,
+                    key,
+                    origin,
+                    spec_tree.origins[&key_of_stage]
+                );
+            }
+
+            let mut stage_source = source.clone();
+            if let Some(dependencies) = &stage.dependencies {
+                stage_source.dependencies = dependencies.clone();
+            }
+            if let Some(previous) = &previous {
+                let depends_on_previous = stage_source
+                    .dependencies
+                    .iter()
+                    .any(|dep| Dependency::parse(dep.as_ref()).key() == previous.as_ref());
+                if !depends_on_previous {
+                    stage_source.dependencies.push(SourceKey::from(format!("~{}", previous)));
+                }
+            }
+
+            // A stage overrides the bconds of the source rather than conflicting with them
+            for feature in &stage.with {
+                stage_source.without.retain(|f| f != feature);
+                if !stage_source.with.contains(feature) {
+                    stage_source.with.push(feature.clone());
+                }
+            }
+            for feature in &stage.without {
+                stage_source.with.retain(|f| f != feature);
+                if !stage_source.without.contains(feature) {
+                    stage_source.without.push(feature.clone());
+                }
+            }
+            stage_source.defines.extend(stage.defines.clone());
+
+            spec_tree.sources.insert(key_of_stage.clone(), stage_source);
+            spec_tree.origins.insert(key_of_stage.clone(), origin.clone());
+            previous = Some(key_of_stage);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::spec_file::load_spec_tree;
+    use crate::SourceKey;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn key(s: &str) -> SourceKey {
+        SourceKey::from(s.to_string())
+    }
+
+    #[test]
+    fn test_expand_stages() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tree.yaml");
+        fs::write(
+            &path,
+            r#"
+defaults: {type: {source: git, path: x}}
+binutils: {dependencies: ["gcc@stage1"]}
+gcc:
+  dependencies: [binutils, glibc]
+  without: [bootstrap]
+  stages:
+    - {name: stage1, dependencies: [glibc], with: [bootstrap]}
+    - {name: stage2}
+glibc: {}
+"#,
+        )
+        .unwrap();
+
+        let tree = load_spec_tree(&path, &[]).unwrap();
+        let deps =
+            |k: &str| -> Vec<String> { tree.sources[&key(k)].dependencies.iter().map(|d| d.to_string()).collect() };
+        assert_eq!(deps("gcc@stage1"), vec!["glibc"]);
+        assert_eq!(deps("gcc"), vec!["binutils", "glibc", "~gcc@stage1"]);
+
+        let stage1 = &tree.sources[&key("gcc@stage1")];
+        assert_eq!(stage1.with, vec!["bootstrap"]);
+        assert!(stage1.without.is_empty());
+        assert_eq!(tree.sources[&key("gcc")].without, vec!["bootstrap"]);
+        assert!(tree.sources.values().all(|source| source.stages.is_empty()));
+        assert_eq!(tree.origins[&key("gcc@stage1")].line, tree.origins[&key("gcc")].line);
+    }
+
+    #[test]
+    fn test_stage_errors() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tree.yaml");
+        let defaults = "defaults: {type: {source: git, path: x}}\n";
+
+        fs::write(
+            &path,
+            format!("{}a: {{stages: [{{name: s1}}, {{name: s1}}]}}\n", defaults),
+        )
+        .unwrap();
+        assert!(load_spec_tree(&path, &[]).is_err());
+
+        fs::write(
+            &path,
+            format!("{}a: {{stages: [{{name: \"s 1\"}}, {{name: s2}}]}}\n", defaults),
+        )
+        .unwrap();
+        assert!(load_spec_tree(&path, &[]).is_err());
+
+        fs::write(
+            &path,
+            format!(
+                "{}a: {{stages: [{{name: s1}}, {{name: s2}}]}}\n\"a@s1\": {{}}\n",
+                defaults
+            ),
+        )
+        .unwrap();
+        assert!(load_spec_tree(&path, &[]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+
+use crate::Error;
+
+/// Variable that always expands to the key of the source being expanded
+const NAME_VAR: &str = "NAME";
+/// Prefix of variables that are looked up in the environment
+const ENV_PREFIX: &str = "env:";
+
+/// Expands `${...}` references in source paths, URLs and parameters.
+///
+/// Supported references are `${NAME}` (the source key), `${env:VAR}` (an environment variable) and
+/// `${var}` for variables from the `vars:` section of the spec file or from `--set` on the command
+/// line. `$${` produces a literal `${`. Referencing anything else is an error.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+    vars: BTreeMap<String, String>,
+}
+
+impl TemplateVars {
+    pub fn new(spec_vars: BTreeMap<String, String>, overrides: &[(String, String)]) -> Result<Self> {
+        let mut vars = spec_vars;
+        for (name, value) in overrides {
+            vars.insert(name.clone(), value.clone());
+        }
+
+        for name in vars.keys() {
+            if name == NAME_VAR || name.starts_with(ENV_PREFIX) {
+                anyhow::bail!("Variable name '{}' is reserved", name);
+            }
+        }
+
+        Ok(Self { vars })
+    }
+
+    pub fn expand(&self, template: &str, name: &str) -> Result<String> {
+        self.expand_inner(template, name, &mut Vec::new())
+    }
+
+    fn expand_inner(&self, template: &str, name: &str, stack: &mut Vec<String>) -> Result<String> {
+        let mut result = String::new();
+        let mut rest = template;
+
+        while let Some(pos) = rest.find('$') {
+            result.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+
+            if let Some(escaped) = rest.strip_prefix("$${") {
+                result.push_str("${");
+                rest = escaped;
+            } else if let Some(reference) = rest.strip_prefix("${") {
+                let end = reference
+                    .find('}')
+                    .ok_or_else(|| anyhow::anyhow!("Unterminated '${{' in '{}'", template))?;
+                let var = &reference[..end];
+                result.push_str(&self.lookup(var, name, stack)?);
+                rest = &reference[end + 1..];
+            } else {
+                result.push('$');
+                rest = &rest[1..];
+            }
+        }
+
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    fn lookup(&self, var: &str, name: &str, stack: &mut Vec<String>) -> Result<String> {
+        if var == NAME_VAR {
+            return Ok(name.to_string());
+        }
+
+        if let Some(env_var) = var.strip_prefix(ENV_PREFIX) {
+            return std::env::var(env_var).with_context(|| format!("Environment variable '{}' is not set", env_var));
+        }
+
+        let Some(value) = self.vars.get(var) else {
+            anyhow::bail!("Unknown variable '${{{}}}'", var);
+        };
+
+        // Variables may refer to other variables
+        if stack.iter().any(|v| v == var) {
+            anyhow::bail!("Variable '{}' refers to itself", var);
+        }
+        stack.push(var.to_string());
+        let expanded = self.expand_inner(value, name, stack);
+        stack.pop();
+
+        expanded
+    }
+}
+
+/// Parse a `KEY=VALUE` command line argument.
+pub fn parse_var_assignment(s: &str) -> Result<(String, String), Error> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| Error::InvalidValue(format!("Invalid variable assignment '{}', expected KEY=VALUE", s)))?;
+    if key.is_empty() {
+        return Err(Error::InvalidValue(format!(
+            "Invalid variable assignment '{}', the variable name is empty",
+            s
+        )));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_var_assignment, TemplateVars};
+    use std::collections::BTreeMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> TemplateVars {
+        let spec_vars: BTreeMap<String, String> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        TemplateVars::new(spec_vars, &[]).unwrap()
+    }
+
+    #[test]
+    fn test_expand_name() {
+        let vars = vars(&[]);
+        assert_eq!(vars.expand("/repos/${NAME}", "bash").unwrap(), "/repos/bash");
+        assert_eq!(vars.expand("no references", "bash").unwrap(), "no references");
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let vars = vars(&[("root", "/src"), ("repos", "${root}/rpms")]);
+        assert_eq!(vars.expand("${repos}/${NAME}", "bash").unwrap(), "/src/rpms/bash");
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let vars = vars(&[]);
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(vars.expand("${env:PATH}", "x").unwrap(), path);
+        assert!(vars.expand("${env:SPECTREE_SURELY_UNSET_VARIABLE}", "x").is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let spec_vars = BTreeMap::from([("root".to_string(), "/src".to_string())]);
+        let vars = TemplateVars::new(spec_vars, &[("root".to_string(), "/home/me".to_string())]).unwrap();
+        assert_eq!(vars.expand("${root}/${NAME}", "bash").unwrap(), "/home/me/bash");
+    }
+
+    #[test]
+    fn test_errors() {
+        let vars = vars(&[("a", "${b}"), ("b", "${a}")]);
+        assert!(vars.expand("${unknown}", "x").is_err());
+        assert!(vars.expand("${a}", "x").is_err());
+        assert!(vars.expand("${NAME", "x").is_err());
+        assert!(TemplateVars::new(BTreeMap::from([("NAME".to_string(), "x".to_string())]), &[]).is_err());
+    }
+
+    #[test]
+    fn test_literals() {
+        let vars = vars(&[]);
+        assert_eq!(vars.expand("$basearch/$${NAME}", "x").unwrap(), "$basearch/${NAME}");
+    }
+
+    #[test]
+    fn test_parse_var_assignment() {
+        assert_eq!(
+            parse_var_assignment("a=b=c").unwrap(),
+            ("a".to_string(), "b=c".to_string())
+        );
+        assert!(parse_var_assignment("a").is_err());
+        assert!(parse_var_assignment("=a").is_err());
+    }
+}
@@ -82,7 +82,7 @@ fn main() -> Result<()> {
         log_dir: None,
         log_dir_level: None,
     };
-    logging::start(&logging_args)?;
+    logging::start(&logging_args, false)?;
 
     info!("Setting up test environment...");
 
@@ -1,5 +1,6 @@
 use crate::shell::{Shell, ShellEscaped};
 use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
 use std::path::Path;
 use tracing::{debug, info};
 
@@ -32,6 +33,14 @@ pub(crate) fn get_git_revision(repo_path: &Path) -> Result<String> {
     Ok(output)
 }
 
+pub(crate) fn get_file_sha256(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {} for hashing", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to hash {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 // Helper function to recursively copy directories with hardlinks when possible
 pub(crate) fn copy_dir_all(src: &Path, dst: &Path) -> std::io::Result<()> {
     // Remove destination if it exists to ensure clean copy
@@ -132,3 +141,27 @@ pub(crate) fn export_git_revision(
     debug!("Successfully exported git revision to {}", export_path.display());
     Ok(())
 }
+
+/// Run a git command in a repo of a test, as a test user.
+#[cfg(test)]
+pub(crate) fn test_git(repo: &Path, args: &str) -> String {
+    Shell::new(repo)
+        .run_with_output_sync(&format!(
+            "git -c user.name=test -c user.email=test@example.com {}",
+            args
+        ))
+        .unwrap()
+}
+
+/// Commit a new revision of a file to a repo of a test, creating the repo if needed, and return the commit.
+#[cfg(test)]
+pub(crate) fn test_commit(repo: &Path, content: &str) -> String {
+    if !repo.exists() {
+        std::fs::create_dir_all(repo).unwrap();
+        test_git(repo, "init -q");
+    }
+    std::fs::write(repo.join("file.txt"), content).unwrap();
+    test_git(repo, "add -A");
+    test_git(repo, &format!("commit -qm {}", content.shell_escaped()));
+    test_git(repo, "rev-parse HEAD")
+}
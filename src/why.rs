@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{cycles, Dependency, Error, SourceKey, SpecTree};
+
+/// A chain of dependencies leading from one source to another.
+#[derive(Debug, PartialEq)]
+pub struct DependencyPath {
+    /// The sources along the path, each with whether the edge leading to it is direct-only
+    pub steps: Vec<(SourceKey, bool)>,
+}
+
+impl DependencyPath {
+    /// The first direct-only edge past the first hop, which keeps the end of the path out of the build repo of its
+    /// start. Direct-only edges are only followed from the source being built, as `resolve_dependencies` does.
+    pub fn cut_at(&self) -> Option<(&SourceKey, &SourceKey)> {
+        self.steps
+            .windows(2)
+            .skip(1)
+            .find(|pair| pair[1].1)
+            .map(|pair| (&pair[0].0, &pair[1].0))
+    }
+}
+
+impl std::fmt::Display for DependencyPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let steps: Vec<String> = self
+            .steps
+            .iter()
+            .map(|(key, direct_only)| format!("{}{}", if *direct_only { "~" } else { "" }, key))
+            .collect();
+        write!(f, "{}", steps.join(" -> "))
+    }
+}
+
+/// Find every dependency path from `from` to `to`, in the order of the dependency lists.
+pub fn find_dependency_paths(
+    from: &SourceKey, to: &SourceKey, spec_tree: &SpecTree,
+) -> Result<Vec<DependencyPath>, Error> {
+    for key in [from, to] {
+        if !spec_tree.sources.contains_key(key) {
+            return Err(Error::SourceNotFound(key.clone()));
+        }
+    }
+    if from == to {
+        return Err(Error::SameSource(from.clone()));
+    }
+    cycles::ensure_acyclic(std::slice::from_ref(from), spec_tree)?;
+
+    let mut paths = Vec::new();
+    let mut reaches = HashMap::new();
+    let mut steps = vec![(from.clone(), false)];
+    collect_paths(to, spec_tree, &mut steps, &mut reaches, &mut paths);
+    Ok(paths)
+}
+
+/// Whether `to` can be reached from `key`, memoized so that only the branches that lead to it are walked.
+fn reaches_target(
+    key: &SourceKey, to: &SourceKey, spec_tree: &SpecTree, reaches: &mut HashMap<SourceKey, bool>,
+) -> bool {
+    if key == to {
+        return true;
+    }
+    if let Some(reached) = reaches.get(key) {
+        return *reached;
+    }
+
+    let reached = spec_tree.sources[key].dependencies.iter().any(|dep| {
+        let dep_key = SourceKey::from(Dependency::parse(dep.as_ref()).key().to_string());
+        spec_tree.sources.contains_key(&dep_key) && reaches_target(&dep_key, to, spec_tree, reaches)
+    });
+    reaches.insert(key.clone(), reached);
+    reached
+}
+
+fn collect_paths(
+    to: &SourceKey, spec_tree: &SpecTree, steps: &mut Vec<(SourceKey, bool)>, reaches: &mut HashMap<SourceKey, bool>,
+    paths: &mut Vec<DependencyPath>,
+) {
+    let key = steps.last().unwrap().0.clone();
+    if &key == to {
+        paths.push(DependencyPath { steps: steps.clone() });
+        return;
+    }
+
+    for dep_str in &spec_tree.sources[&key].dependencies {
+        let dependency = Dependency::parse(dep_str.as_ref());
+        let dep_key = SourceKey::from(dependency.key().to_string());
+        if !spec_tree.sources.contains_key(&dep_key) || !reaches_target(&dep_key, to, spec_tree, reaches) {
+            continue;
+        }
+
+        steps.push((dep_key, dependency.is_direct_only()));
+        collect_paths(to, spec_tree, steps, reaches, paths);
+        steps.pop();
+    }
+}
+
+/// Describe the dependency paths between two sources, and whether `to` ends up in the build repo of `from`.
+pub fn render_dependency_paths(from: &SourceKey, to: &SourceKey, paths: &[DependencyPath]) -> String {
+    let mut out = String::new();
+    if paths.is_empty() {
+        let _ = writeln!(out, "'{}' does not depend on '{}'", from, to);
+        return out;
+    }
+
+    for path in paths {
+        let _ = write!(out, "{}", path);
+        match path.cut_at() {
+            Some((dependent, dependency)) => {
+                let _ = writeln!(out, "  [cut at {} -> ~{}]", dependent, dependency);
+            }
+            None => {
+                let _ = writeln!(out, "  [in build repo]");
+            }
+        }
+    }
+
+    let visible = paths.iter().filter(|path| path.cut_at().is_none()).count();
+    if visible > 0 {
+        let _ = writeln!(
+            out,
+            "\n'{}' is in the build repo of '{}' through {} of {} paths",
+            to,
+            from,
+            visible,
+            paths.len()
+        );
+    } else {
+        let _ = writeln!(
+            out,
+            "\n'{}' is built before '{}', but is not in its build repo: every path is cut by a direct-only edge",
+            to, from
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_dependency_paths, render_dependency_paths};
+    use crate::spec_file::load_test_tree;
+    use crate::{resolve_dependencies, SourceKey};
+
+    fn key(name: &str) -> SourceKey {
+        SourceKey::from(name.to_string())
+    }
+
+    #[test]
+    fn test_find_paths() {
+        let tree = load_test_tree(
+            "combined: {dependencies: [extended, other]}\nextended: {dependencies: [hello]}\n\
+             other: {dependencies: [\"~hello\"]}\nhello: {}\n",
+        );
+
+        let paths = find_dependency_paths(&key("combined"), &key("hello"), &tree).unwrap();
+        let rendered: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        assert_eq!(
+            rendered,
+            vec!["combined -> extended -> hello", "combined -> other -> ~hello"]
+        );
+        assert_eq!(paths[0].cut_at(), None);
+        assert_eq!(paths[1].cut_at(), Some((&key("other"), &key("hello"))));
+
+        // A direct-only edge from the source being built still makes the dependency visible
+        let paths = find_dependency_paths(&key("other"), &key("hello"), &tree).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].cut_at(), None);
+
+        assert!(find_dependency_paths(&key("hello"), &key("combined"), &tree)
+            .unwrap()
+            .is_empty());
+        assert!(find_dependency_paths(&key("combined"), &key("missing"), &tree).is_err());
+    }
+
+    #[test]
+    fn test_matches_build_repo() {
+        let tree = load_test_tree(
+            "app: {dependencies: [\"~lib\", tool]}\nlib: {dependencies: [zlib, \"~bootstrap\"]}\n\
+             tool: {dependencies: [\"~zlib\"]}\nzlib: {dependencies: [bootstrap]}\nbootstrap: {}\n",
+        );
+
+        for from in ["app", "lib", "tool", "zlib"] {
+            let build_repo = resolve_dependencies(&key(from), &tree).unwrap();
+            for to in ["lib", "tool", "zlib", "bootstrap"].into_iter().filter(|to| *to != from) {
+                let paths = find_dependency_paths(&key(from), &key(to), &tree).unwrap();
+                let visible = paths.iter().any(|path| path.cut_at().is_none());
+                assert_eq!(visible, build_repo.contains(&key(to)), "{} -> {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let tree = load_test_tree("a: {dependencies: [b]}\nb: {dependencies: [\"~c\"]}\nc: {}\n");
+        let paths = find_dependency_paths(&key("a"), &key("c"), &tree).unwrap();
+        assert_eq!(
+            render_dependency_paths(&key("a"), &key("c"), &paths),
+            "a -> b -> ~c  [cut at b -> ~c]\n\n\
+             'c' is built before 'a', but is not in its build repo: every path is cut by a direct-only edge\n"
+        );
+        assert_eq!(
+            render_dependency_paths(&key("c"), &key("a"), &[]),
+            "'c' does not depend on 'a'\n"
+        );
+    }
+}